@@ -0,0 +1,210 @@
+use peer_protocol::PeerID;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most candidate peer addresses to remember.
+const MAX_CANDIDATES: usize = 500;
+/// Wait before retrying an address the first time.
+const BACKOFF_BASE_SECS: u64 = 30;
+/// Longest wait before retrying an address.
+const BACKOFF_MAX_SECS: u64 = 30 * 60;
+/// Consecutive failed attempts after which an address is given up on.
+const MAX_FAILURES: u32 = 6;
+
+#[derive(Debug)]
+struct Entry {
+    peer_id: Option<PeerID>,
+    /// Connection attempts in a row that did not reach a handshake.
+    failures: u32,
+    /// When the address may be tried again.
+    retry_at: Instant,
+    /// Whether a connection to the address is in progress or open.
+    in_use: bool,
+    /// Whether the current connection completed its handshake.
+    connected: bool,
+}
+
+/// Addresses of peers we might connect to.
+/// Addresses are tried in the order they were added.
+/// Closed connections go to the back of the line, and failed ones wait
+/// with exponential backoff before being tried again.
+pub struct CandidatePool {
+    entries: HashMap<SocketAddr, Entry>,
+    /// Addresses not in use, oldest first.
+    idle: VecDeque<SocketAddr>,
+    /// Addresses never to be added again.
+    excluded: HashSet<SocketAddr>,
+}
+
+impl CandidatePool {
+    pub fn new() -> Self {
+        CandidatePool {
+            entries: HashMap::new(),
+            idle: VecDeque::new(),
+            excluded: HashSet::new(),
+        }
+    }
+
+    /// Returns whether the address was added.
+    /// Addresses already known keep their place and backoff.
+    pub fn add(&mut self, addr: SocketAddr, peer_id: Option<PeerID>, now: Instant) -> bool {
+        if addr.port() == 0 || self.entries.len() >= MAX_CANDIDATES {
+            return false;
+        }
+        if self.entries.contains_key(&addr) || self.excluded.contains(&addr) {
+            return false;
+        }
+        self.entries
+            .insert(addr,
+                    Entry {
+                        peer_id: peer_id,
+                        failures: 0,
+                        retry_at: now,
+                        in_use: false,
+                        connected: false,
+                    });
+        self.idle.push_back(addr);
+        true
+    }
+
+    /// Take the next address which is due to be tried.
+    /// It stays in use until `closed` is called.
+    pub fn pop(&mut self, now: Instant) -> Option<(SocketAddr, Option<PeerID>)> {
+        let i = {
+            let entries = &self.entries;
+            match self.idle.iter().position(|addr| entries[addr].retry_at <= now) {
+                Some(i) => i,
+                None => return None,
+            }
+        };
+        let addr = self.idle.remove(i).unwrap();
+        let entry = self.entries.get_mut(&addr).unwrap();
+        entry.in_use = true;
+        entry.connected = false;
+        Some((addr, entry.peer_id.clone()))
+    }
+
+    /// Record that a connection to the address completed its handshake.
+    pub fn connected(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.connected = true;
+            entry.failures = 0;
+        }
+    }
+
+    /// Record that a connection to the address closed.
+    /// Addresses which failed too often are forgotten.
+    pub fn closed(&mut self, addr: SocketAddr, now: Instant) {
+        let retry = match self.entries.get_mut(&addr) {
+            Some(entry) if entry.in_use => {
+                entry.in_use = false;
+                if !entry.connected {
+                    entry.failures += 1;
+                }
+                entry.retry_at = now + backoff(entry.failures);
+                entry.failures < MAX_FAILURES
+            }
+            _ => return,
+        };
+        if retry {
+            self.idle.push_back(addr);
+        } else {
+            self.entries.remove(&addr);
+        }
+    }
+
+    /// Forget an address for good.
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.entries.remove(&addr);
+        self.idle.retain(|x| *x != addr);
+    }
+
+    /// Forget an address and refuse it if it is added again,
+    /// such as a peer with nothing to trade.
+    pub fn exclude(&mut self, addr: SocketAddr) {
+        self.remove(addr);
+        self.excluded.insert(addr);
+    }
+
+    /// Number of addresses waiting to be tried.
+    pub fn len(&self) -> usize {
+        self.idle.len()
+    }
+}
+
+/// Wait before retrying an address after a number of consecutive failures.
+fn backoff(failures: u32) -> Duration {
+    let exp = cmp::min(failures.saturating_sub(1), 16);
+    Duration::from_secs(cmp::min(BACKOFF_BASE_SECS << exp, BACKOFF_MAX_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use candidates::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_secs(30));
+        assert_eq!(backoff(1), Duration::from_secs(30));
+        assert_eq!(backoff(2), Duration::from_secs(60));
+        assert_eq!(backoff(3), Duration::from_secs(120));
+        assert_eq!(backoff(100), Duration::from_secs(BACKOFF_MAX_SECS));
+    }
+
+    #[test]
+    fn test_retry() {
+        let now = Instant::now();
+        let mut pool = CandidatePool::new();
+        assert!(pool.add(addr(1), None, now));
+        assert!(pool.add(addr(2), None, now));
+        assert!(!pool.add(addr(1), None, now));
+        assert!(!pool.add(addr(0), None, now));
+
+        assert_eq!(pool.pop(now).unwrap().0, addr(1));
+        assert_eq!(pool.pop(now).unwrap().0, addr(2));
+        assert!(pool.pop(now).is_none());
+
+        // A failed address waits.
+        pool.closed(addr(1), now);
+        // A connected address goes to the back of the line.
+        pool.connected(addr(2));
+        pool.closed(addr(2), now);
+        assert!(pool.pop(now).is_none());
+        let later = now + Duration::from_secs(BACKOFF_BASE_SECS);
+        assert_eq!(pool.pop(later).unwrap().0, addr(1));
+        assert_eq!(pool.pop(later).unwrap().0, addr(2));
+    }
+
+    #[test]
+    fn test_give_up() {
+        let mut now = Instant::now();
+        let mut pool = CandidatePool::new();
+        pool.add(addr(1), None, now);
+        for _ in 0..MAX_FAILURES {
+            now += Duration::from_secs(BACKOFF_MAX_SECS);
+            assert_eq!(pool.pop(now).unwrap().0, addr(1));
+            pool.closed(addr(1), now);
+        }
+        assert_eq!(pool.len(), 0);
+        assert!(pool.pop(now + Duration::from_secs(BACKOFF_MAX_SECS)).is_none());
+    }
+
+    #[test]
+    fn test_exclude() {
+        let now = Instant::now();
+        let mut pool = CandidatePool::new();
+        pool.add(addr(1), None, now);
+        assert_eq!(pool.pop(now).unwrap().0, addr(1));
+        pool.connected(addr(1));
+        pool.exclude(addr(1));
+        pool.closed(addr(1), now);
+        assert!(!pool.add(addr(1), None, now));
+        assert!(pool.pop(now + Duration::from_secs(BACKOFF_MAX_SECS)).is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use byteorder::{BigEndian, ByteOrder};
+use errors::*;
+use ring::rand::SystemRandom;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// How often to decide which peers to unchoke.
+pub const RECHOKE_INTERVAL_SECS: u64 = 10;
+/// How often to move the optimistic unchokes to other peers.
+pub const OPTIMISTIC_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChokerConfig {
+    /// Number of peers unchoked for their rate.
+    pub unchoke_slots: usize,
+    /// Number of peers unchoked at random.
+    pub optimistic_slots: usize,
+}
+
+/// What the choker knows about a peer.
+pub struct Candidate<K> {
+    pub peer: K,
+    /// Whether the peer wants data from us.
+    pub interested: bool,
+    /// Rate the peer is judged by.
+    /// Its upload rate to us while downloading, our upload rate to it while seeding.
+    pub rate: f64,
+}
+
+/// Tit-for-tat choker.
+/// Unchokes the interested peers with the best rates,
+/// plus a few at random so that new peers get a chance to prove themselves.
+pub struct Choker<K> {
+    config: ChokerConfig,
+    /// Peers currently unchoked at random.
+    optimistic: Vec<K>,
+    /// When the optimistic unchokes were last moved.
+    last_rotate: Option<Instant>,
+}
+
+impl<K: Copy + Eq + Hash> Choker<K> {
+    pub fn new(config: ChokerConfig) -> Self {
+        Choker {
+            config: config,
+            optimistic: Vec::new(),
+            last_rotate: None,
+        }
+    }
+
+    /// Decide which peers to unchoke. All others should be choked.
+    pub fn rechoke(&mut self, candidates: &[Candidate<K>], now: Instant) -> Result<HashSet<K>> {
+        let mut interested: Vec<&Candidate<K>> = candidates.iter().filter(|c| c.interested).collect();
+        interested.sort_by(|a, b| b.rate.partial_cmp(&a.rate).unwrap_or(Ordering::Equal));
+        let mut unchoke: HashSet<K> = interested
+            .iter()
+            .take(self.config.unchoke_slots)
+            .map(|c| c.peer)
+            .collect();
+
+        let rotate = match self.last_rotate {
+            Some(last) => now.duration_since(last) >= Duration::from_secs(OPTIMISTIC_INTERVAL_SECS),
+            None => true,
+        };
+        if rotate {
+            self.optimistic.clear();
+            self.last_rotate = Some(now);
+        }
+        // Keep optimistic unchokes which are still eligible.
+        self.optimistic
+            .retain(|peer| interested.iter().any(|c| c.peer == *peer) && !unchoke.contains(peer));
+        let mut eligible: Vec<K> = interested
+            .iter()
+            .map(|c| c.peer)
+            .filter(|peer| !unchoke.contains(peer) && !self.optimistic.contains(peer))
+            .collect();
+        while self.optimistic.len() < self.config.optimistic_slots && !eligible.is_empty() {
+            let i = random_index(eligible.len())?;
+            self.optimistic.push(eligible.swap_remove(i));
+        }
+
+        unchoke.extend(self.optimistic.iter().cloned());
+        Ok(unchoke)
+    }
+}
+
+/// Random number in [0, n).
+fn random_index(n: usize) -> Result<usize> {
+    let mut buf = [0; 4];
+    SystemRandom::new().fill(&mut buf)?;
+    Ok(BigEndian::read_u32(&buf) as usize % n)
+}
+
+#[cfg(test)]
+mod tests {
+    use choker::*;
+
+    fn candidate(peer: usize, interested: bool, rate: f64) -> Candidate<usize> {
+        Candidate {
+            peer: peer,
+            interested: interested,
+            rate: rate,
+        }
+    }
+
+    #[test]
+    fn test_rechoke() {
+        let mut choker = Choker::new(ChokerConfig {
+                                         unchoke_slots: 2,
+                                         optimistic_slots: 1,
+                                     });
+        let candidates = vec![candidate(0, true, 10.0),
+                              candidate(1, true, 30.0),
+                              candidate(2, false, 50.0),
+                              candidate(3, true, 20.0),
+                              candidate(4, true, 0.0)];
+        let now = Instant::now();
+        let unchoke = choker.rechoke(&candidates, now).unwrap();
+        assert_eq!(unchoke.len(), 3);
+        assert!(unchoke.contains(&1));
+        assert!(unchoke.contains(&3));
+        assert!(!unchoke.contains(&2));
+        let optimistic = *unchoke.iter().find(|p| **p != 1 && **p != 3).unwrap();
+        assert!(optimistic == 0 || optimistic == 4);
+
+        // The optimistic unchoke stays until it is time to rotate.
+        let later = now + Duration::from_secs(RECHOKE_INTERVAL_SECS);
+        assert_eq!(choker.rechoke(&candidates, later).unwrap(), unchoke);
+    }
+
+    #[test]
+    fn test_rechoke_few_peers() {
+        let mut choker = Choker::new(ChokerConfig {
+                                         unchoke_slots: 4,
+                                         optimistic_slots: 1,
+                                     });
+        let candidates = vec![candidate(0, true, 0.0), candidate(1, false, 0.0)];
+        let unchoke = choker.rechoke(&candidates, Instant::now()).unwrap();
+        assert_eq!(unchoke, [0].iter().cloned().collect());
+    }
+}
@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use errors::*;
 use metainfo::{MetaInfo, PieceHash, SizeInfo};
 use ring::digest;
@@ -41,6 +42,19 @@ impl DataStore {
         Ok(())
     }
 
+    /// Read a block of data.
+    /// Does not check that the data has been verified.
+    pub fn read_block(&mut self, piece: u64, offset: u64, length: u64) -> Result<Bytes> {
+        self.size_info.check_range(piece, offset, length)?;
+        let x = self.size_info.absolute_offset(piece, offset);
+        self.file.seek(SeekFrom::Start(x))?;
+        let buf = self.file.read_n(length)?;
+        if buf.len() as u64 != length {
+            bail!("short read from datastore {} < {}", buf.len(), length);
+        }
+        Ok(Bytes::from(buf))
+    }
+
     pub fn verify_piece(&mut self, piece: u64, expected: PieceHash) -> Result<Option<Verified>> {
         self.size_info.check_piece(piece)?;
         let x = self.size_info.absolute_offset(piece, 0);
@@ -1,127 +1,766 @@
+use candidates::CandidatePool;
+use choker;
+use choker::{Candidate, Choker, ChokerConfig};
 use datastore::DataStore;
 use errors::*;
+use extension;
+use extension::ExtensionHandshake;
 use fillable::*;
 use futures;
 use futures::{Sink, Stream};
 use futures::future;
 use futures::future::Future;
-use manifest::{BlockRequest, ManifestWithFile};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use manifest::{BLOCK_SIZE, BlockRequest, Manifest, ManifestWithFile};
+use metadata;
+use metadata::MetadataMessage;
 use metainfo::{InfoHash, MetaInfo};
+use mse;
+use mse::{EncryptedStream, EncryptionPolicy};
+use pex;
+use pex::PexMessage;
+use picker::{Availability, PeerSpeed, PickerStrategy, PieceOrder};
+use provenance::Provenance;
+use rate::RateMeter;
+use ratelimit::{DisplayRate, RateLimits, TokenBucket};
+use session::Shared;
 use peer_protocol;
-use peer_protocol::{BitTorrentPeerCodec, Message, PeerID};
+use peer_protocol::{BitTorrentPeerCodec, DEFAULT_MAX_MESSAGE_LENGTH, Message, PeerID, Reserved};
 use slog::Logger;
 use std::cmp;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::default::Default;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::AtomicUsize;
-use std::time::Duration;
-use tokio_core::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio_core::reactor;
 use tokio_core::reactor::Handle;
 use tokio_io::AsyncRead;
 use tokio_io::codec::Framed;
-use tracker;
-use tracker::TrackerClient;
-use util::{BxFuture, FutureEnhanced, VecDequeStream, mkdirp_for_file, tcp_connect2};
-
-type PeerFramed = Framed<TcpStream, BitTorrentPeerCodec>;
+use tracker::{AnnounceSchedule, TrackerClient, TrackerEvent, TrackerResponse, TransferStats};
+use transport;
+use transport::Transport;
+use util::{BxFuture, FutureEnhanced, VecDequeStream, mkdirp_for_file, sleep, with_timeout};
+use utp::UtpSocket;
+
+type PeerFramed = Framed<EncryptedStream<Transport>, BitTorrentPeerCodec>;
+
+/// Largest block a peer may request from us.
+const MAX_REQUEST_LENGTH: u64 = 1 << 17;
+/// Most requests a peer may have queued with us at once.
+const MAX_UPLOAD_QUEUE: usize = 250;
+/// Most bytes of blocks waiting in a peer's send channel.
+const MAX_QUEUED_UPLOAD_BYTES: u64 = 1 << 18;
+/// Most peer connections to have open at once.
+const MAX_PEERS: usize = 15;
+/// Most outgoing connections to have in progress before their handshakes finish.
+const MAX_HALF_OPEN: usize = 8;
+/// How often to connect to more peers.
+const CONNECT_INTERVAL_MILLIS: u64 = 2000;
+/// How often the progress report includes statistics for each peer.
+const PEER_REPORT_INTERVAL_SECS: u64 = 10;
+/// How often to check whether to announce to the tracker.
+const TRACKER_CHECK_INTERVAL_MILLIS: u64 = 1000;
+/// Time allowed for the encryption handshake.
+const ENCRYPTION_TIMEOUT_MILLIS: u64 = 10000;
+/// Time allowed to connect and complete the peer handshake.
+pub const HANDSHAKE_TIMEOUT_MILLIS: u64 = 20000;
+/// Close connections which send nothing for this long.
+/// Peers send keep-alives more often than this.
+const IDLE_TIMEOUT_SECS: u64 = 180;
+/// How often to send keep-alives.
+const KEEPALIVE_INTERVAL_SECS: u64 = 60;
+/// Give up on a request after this long without any block from the peer.
+/// The peer is then considered to be snubbing us.
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+/// How often to check peer timers.
+const PEER_TIMER_INTERVAL_MILLIS: u64 = 1000;
+/// How often to serve requests held back by the upload limiters.
+const UPLOAD_INTERVAL_MILLIS: u64 = 100;
+/// Fewest requests to keep outstanding on a peer.
+const MIN_PIPELINE_DEPTH: u64 = 4;
+/// Most requests to keep outstanding on a peer.
+const MAX_PIPELINE_DEPTH: u64 = 250;
+/// Most requests outstanding on a peer which does not advertise its queue length.
+const DEFAULT_REQQ: u64 = 50;
+/// Keep enough requests outstanding to cover this long past a round trip.
+const PIPELINE_SLACK_SECS: f64 = 1.0;
+/// Round trip time to assume before any have been measured.
+const INITIAL_RTT_MILLIS: u64 = 500;
+/// Peers downloading at less than this fraction of the fastest peer's rate are slow.
+const SLOW_PEER_FRACTION: f64 = 0.25;
+/// Most requests outstanding on a peer which is snubbing us.
+const MAX_OUTSTANDING_PER_SNUBBED_PEER: u64 = 1;
 
 // Local number used to identify peer connections.
 type PeerNum = usize;
 
-struct DownloaderState {
+/// Settings for running a torrent.
+#[derive(Debug, Clone, Copy)]
+pub struct TorrentConfig {
+    pub picker: PickerStrategy,
+    pub choker: ChokerConfig,
+    /// Addresses which send more corrupt blocks than this are banned.
+    pub max_bad_blocks: u32,
+    /// Stop seeding once this many times the torrent's size has been uploaded.
+    pub seed_ratio: Option<f64>,
+    /// Stop seeding after this long.
+    pub seed_time: Option<Duration>,
+    /// The data store is expected to be complete already.
+    /// It is verified on start instead of downloading.
+    pub seed: bool,
+}
+
+/// Resolves when a torrent stops.
+type StopSignal = future::Shared<oneshot::Receiver<()>>;
+
+pub struct DownloaderState {
     info: MetaInfo,
+    /// Settings and limiters shared with the other torrents of the session.
+    shared: Arc<Shared>,
     datastore: DataStore,
     manifest: ManifestWithFile,
+    tracker: AM<TrackerClient>,
     peer_states: HashMap<PeerNum, PeerState>,
-    next_peer_num: AtomicUsize,
     outstanding: OutstandingRequestsManager,
+    /// Number of blocks not added to the manifest yet, as `next_requests` would request them.
+    missing_blocks: u64,
+    /// Number of connected peers with each piece.
+    availability: Availability,
+    picker: PieceOrder,
+    choker: Choker<PeerNum>,
+    /// Peers we could connect to.
+    candidates: CandidatePool,
+    /// Addresses of outgoing connections, from connecting until closed.
+    dialing: HashSet<SocketAddr>,
+    /// Addresses which sent too many corrupt blocks.
+    banned: HashSet<IpAddr>,
+    /// Senders of the blocks of unverified pieces.
+    provenance: Provenance<PeerNum>,
+    /// Number of corrupt blocks each address has sent.
+    bad_blocks: HashMap<IpAddr, u32>,
+    /// Addresses which send more corrupt blocks than this are banned.
+    max_bad_blocks: u32,
+    /// When per-peer statistics were last reported.
+    last_peer_report: Instant,
+    /// Bytes of blocks received this session.
+    downloaded: u64,
+    /// Bytes of blocks sent this session.
+    uploaded: u64,
+    /// Only the per-peer limits apply here. The session applies the others.
+    limits: RateLimits,
+    seed_ratio: Option<f64>,
+    seed_time: Option<Duration>,
+    /// When the download completed, or when the torrent started if it was already complete.
+    seeding_since: Option<Instant>,
+    /// Sends the stop signal. None once stopped.
+    stop_tx: Option<oneshot::Sender<()>>,
+    stop: StopSignal,
 }
 
-type AM<T> = Arc<Mutex<T>>;
+impl DownloaderState {
+    /// Change the per-peer rate limits of every peer.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        let now = Instant::now();
+        self.limits = limits;
+        for ps in self.peer_states.values_mut() {
+            ps.download_limiter.set_rate(limits.peer_download, now);
+            ps.upload_limiter.set_rate(limits.peer_upload, now);
+        }
+    }
 
-pub fn start<P: AsRef<Path>>(log: Logger, info: MetaInfo, peer_id: PeerID, store_path: P, manifest_path: P) -> Result<()> {
-    let log2 = log.clone();
+    /// Whether the torrent has seeded enough to stop.
+    /// Seeding stops at the share ratio or after the seed time, whichever comes first.
+    /// Without either it goes on until the torrent is removed.
+    pub fn is_done_seeding(&self, now: Instant) -> bool {
+        let since = match self.seeding_since {
+            Some(since) => since,
+            None => return false,
+        };
+        let ratio_reached = self.seed_ratio
+            .map(|ratio| self.share_ratio() >= ratio)
+            .unwrap_or(false);
+        let time_reached = self.seed_time
+            .map(|time| now.duration_since(since) >= time)
+            .unwrap_or(false);
+        ratio_reached || time_reached
+    }
 
-    let mut core = reactor::Core::new()?;
-    let handle = core.handle();
+    /// Bytes uploaded this session relative to the size of the torrent.
+    fn share_ratio(&self) -> f64 {
+        self.uploaded as f64 / self.info.size_info.total_size() as f64
+    }
 
-    mkdirp_for_file(&store_path)?;
-    mkdirp_for_file(&manifest_path)?;
-    let datastore = DataStore::create_or_open(&info, store_path)?;
-    let manifest = ManifestWithFile::load_or_new(log2, info.clone(), manifest_path)?;
-    let mut tc = TrackerClient::new(info.clone(), peer_id.clone())?;
+    /// Account for a block received from a peer.
+    /// Returns how long to wait before reading from the peer again.
+    fn throttle_download(&mut self, peer_num: PeerNum, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let wait = self.shared.download_limiter.lock().unwrap().take(bytes, now);
+        match self.peer_states.get_mut(&peer_num) {
+            Some(ps) => cmp::max(wait, ps.download_limiter.take(bytes, now)),
+            None => wait,
+        }
+    }
 
-    debug!(log, "asking tracker");
-    let tracker_res = tc.easy_start()?;
-    debug!(log, "tracker res: {:#?}", tracker_res);
-    if let Some(reason) = tracker_res.failure_reason {
-        bail!("tracker failed: {}", reason);
+    fn transfer_stats(&self) -> TransferStats {
+        TransferStats {
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.manifest.manifest.bytes_left(),
+        }
     }
+}
+
+type AM<T> = Arc<Mutex<T>>;
+
+/// A torrent's data store and manifest, ready to start.
+pub struct OpenTorrent {
+    info: MetaInfo,
+    datastore: DataStore,
+    manifest: ManifestWithFile,
+}
 
-    if tracker_res.peers.is_empty() {
-        bail!("tracker returned no peers");
+/// Open a torrent's files, checking the existing data when seeding.
+/// This blocks on disk, so it is kept off the event loop.
+pub fn open<P: AsRef<Path>>(log: &Logger, info: MetaInfo, store_path: P, manifest_path: P, config: &TorrentConfig) -> Result<OpenTorrent> {
+    mkdirp_for_file(&store_path)?;
+    mkdirp_for_file(&manifest_path)?;
+    let mut datastore = DataStore::create_or_open(&info, store_path)?;
+    let mut manifest = ManifestWithFile::load_or_new(log.clone(), info.clone(), manifest_path)?;
+    if config.seed && !manifest.manifest.is_all_verified() {
+        info!(log, "checking existing data for seeding");
+        manifest.manifest.fill_all();
+        let (_, flunked) = verify_all(log, &info, &mut manifest, &mut datastore)?;
+        if !flunked.is_empty() {
+            bail!("data store is incomplete: {} of {} pieces failed verification",
+                  flunked.len(),
+                  info.num_pieces());
+        }
     }
+    Ok(OpenTorrent {
+           info: info,
+           datastore: datastore,
+           manifest: manifest,
+       })
+}
+
+/// Start running an opened torrent on the session's event loop.
+pub fn start(log: Logger, handle: &reactor::Handle, shared: Arc<Shared>, torrent: OpenTorrent, config: TorrentConfig) -> Result<AM<DownloaderState>> {
+    let OpenTorrent { info, datastore, manifest } = torrent;
+
+    let tc = TrackerClient::new(&info.announce, info.info_hash.clone(), shared.peer_id.clone(), shared.port)?;
+
+    // A torrent complete before this session started is not reported as completed.
+    let completed = manifest.manifest.is_all_verified();
 
     let num_pieces = info.num_pieces() as u64;
     let info_hash = info.info_hash.clone();
+    let peer_id = shared.peer_id.clone();
+    let limits = *shared.limits.lock().unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let stop = stop_rx.shared();
+
+    let missing_blocks = count_missing_blocks(&manifest.manifest, 0..num_pieces)?;
+    let mut outstanding = OutstandingRequestsManager::new();
+    for piece in 0..num_pieces {
+        if manifest.manifest.is_partial(piece)? {
+            outstanding.piece_started(piece);
+        }
+    }
 
     let dstate = DownloaderState {
         info: info,
+        shared: shared,
         datastore: datastore,
         manifest: manifest,
+        tracker: Arc::new(Mutex::new(tc)),
         peer_states: HashMap::new(),
-        next_peer_num: AtomicUsize::new(0),
-        outstanding: OutstandingRequestsManager::new(),
+        outstanding: outstanding,
+        missing_blocks: missing_blocks,
+        availability: Availability::new(num_pieces),
+        picker: PieceOrder::new(config.picker.new_picker(num_pieces)?, num_pieces),
+        choker: Choker::new(config.choker),
+        candidates: CandidatePool::new(),
+        dialing: HashSet::new(),
+        banned: HashSet::new(),
+        provenance: Provenance::new(),
+        bad_blocks: HashMap::new(),
+        max_bad_blocks: config.max_bad_blocks,
+        last_peer_report: Instant::now(),
+        downloaded: 0,
+        uploaded: 0,
+        limits: limits,
+        seed_ratio: config.seed_ratio,
+        seed_time: config.seed_time,
+        seeding_since: match completed {
+            true => Some(Instant::now()),
+            false => None,
+        },
+        stop_tx: Some(stop_tx),
+        stop: stop.clone(),
     };
 
     let dstate_c = Arc::new(Mutex::new(dstate));
 
-    let mut top_futures: Vec<BxFuture<(), Error>> = Vec::new();
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_progress_report(log.clone(), handle.clone(), dstate_c.clone()), stop.clone())
+                     .map_err(move |err| error!(log2, "progress report failed: {}", err)));
 
-    top_futures.push(run_progress_report(log.clone(), handle.clone(), dstate_c.clone()));
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_connector(log.clone(),
+                                             handle.clone(),
+                                             dstate_c.clone(),
+                                             info_hash.clone(),
+                                             num_pieces,
+                                             peer_id.clone()),
+                               stop.clone())
+                         .map_err(move |err| error!(log2, "connector failed: {}", err)));
 
-    let n_start_peers = cmp::min(15, tracker_res.peers.len());
-    info!(log,
-           "using {}/{} available peers",
-           n_start_peers,
-           tracker_res.peers.len());
-
-    // Connect to an initial set of peers
-    for peer in tracker_res.peers[..n_start_peers].iter() {
-        let peer_num = {
-            let dstate = dstate_c.lock().unwrap();
-            dstate
-                .next_peer_num
-                .fetch_add(1, ::std::sync::atomic::Ordering::Relaxed)
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_peer_timers(log.clone(), handle.clone(), dstate_c.clone()), stop.clone())
+                     .map_err(move |err| error!(log2, "peer timers failed: {}", err)));
+
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_uploader(log.clone(), handle.clone(), dstate_c.clone()), stop.clone())
+                     .map_err(move |err| error!(log2, "uploader failed: {}", err)));
+
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_tracker(log.clone(), handle.clone(), dstate_c.clone(), completed), stop.clone())
+                     .map_err(move |err| error!(log2, "tracker announcer failed: {}", err)));
+
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_choker(log.clone(), handle.clone(), dstate_c.clone()), stop.clone())
+                     .map_err(move |err| error!(log2, "choker failed: {}", err)));
+
+    let log2 = log.clone();
+    handle.spawn(until_stopped(run_pex(handle.clone(), dstate_c.clone()), stop)
+                     .map_err(move |err| error!(log2, "peer exchange failed: {}", err)));
+
+    Ok(dstate_c)
+}
+
+/// Stop running a torrent.
+/// Closes its peer connections and announces the stop to the tracker.
+pub fn stop(log: Logger, dstate_c: &AM<DownloaderState>) -> BxFuture<(), Error> {
+    let (tc, stats) = {
+        let mut dstate = dstate_c.lock().unwrap();
+        if let Some(stop_tx) = dstate.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+        // Dropping the send channels lets the connections close.
+        dstate.peer_states.clear();
+        (dstate.tracker.clone(), dstate.transfer_stats())
+    };
+    announce(&tc, TrackerEvent::Stopped, stats)
+        .map(|_| ())
+        .or_else(move |err| {
+                     warn!(log, "could not announce stop: {}", err);
+                     Ok(())
+                 })
+        .bxed()
+}
+
+/// Run a task until its torrent stops.
+fn until_stopped<F>(f: F, stop: StopSignal) -> BxFuture<(), F::Error>
+    where F: Future<Item = ()> + 'static
+{
+    f.select(stop.then(|_| Ok(())))
+        .map(|_| ())
+        .map_err(|(err, _)| err)
+        .bxed()
+}
+
+/// Run a loop that keeps connecting to candidate peers.
+fn run_connector(log: Logger,
+                 handle: reactor::Handle,
+                 dstate_c: AM<DownloaderState>,
+                 info_hash: InfoHash,
+                 num_pieces: u64,
+                 local_peer_id: PeerID)
+                 -> BxFuture<(), Error> {
+    use futures::future::Loop::Continue;
+
+    future::loop_fn((), move |()| {
+        connect_candidates(&log,
+                           &handle,
+                           &dstate_c,
+                           &info_hash,
+                           num_pieces,
+                           &local_peer_id);
+        let duration = Duration::from_millis(CONNECT_INTERVAL_MILLIS);
+        match reactor::Timeout::new(duration, &handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout.map_err(|e| e.into()).map(|()| Continue(())).bxed(),
+        }
+    })
+            .bxed()
+}
+
+/// Start connecting to candidates until there are enough peers.
+/// Dropped peers are replaced from the pool as long as it has addresses due to be tried.
+fn connect_candidates(log: &Logger,
+                      handle: &reactor::Handle,
+                      dstate_c: &AM<DownloaderState>,
+                      info_hash: &InfoHash,
+                      num_pieces: u64,
+                      local_peer_id: &PeerID) {
+    let mut dstate = dstate_c.lock().unwrap();
+    loop {
+        let n_inbound = dstate.peer_states.values().filter(|ps| ps.inbound).count();
+        if dstate.dialing.len() + n_inbound >= MAX_PEERS {
+            break;
+        }
+        let n_outbound = dstate.peer_states.len() - n_inbound;
+        if dstate.dialing.len().saturating_sub(n_outbound) >= MAX_HALF_OPEN {
+            break;
+        }
+        let (addr, expected_peer_id) = match dstate.candidates.pop(Instant::now()) {
+            Some(x) => x,
+            None => break,
         };
-        let dstate_c = dstate_c.clone();
-        let handle = handle.clone();
+        if dstate.banned.contains(&addr.ip()) {
+            dstate.candidates.remove(addr);
+            continue;
+        }
+        dstate.dialing.insert(addr);
+        let peer_num = dstate.shared.next_peer_num();
         let log = log.new(o!("peer_num" => peer_num));
-        let f = run_peer(log.clone(),
+        let log2 = log.clone();
+        let dstate_c2 = dstate_c.clone();
+        let f = run_peer(log,
                          handle.clone(),
-                         dstate_c,
-                         peer.clone(),
+                         dstate_c.clone(),
+                         dstate.shared.clone(),
+                         addr,
+                         expected_peer_id,
                          info_hash.clone(),
                          num_pieces,
-                         peer_id.clone(),
-                         peer_num);
-        top_futures.push(f);
+                         local_peer_id.clone(),
+                         peer_num)
+                .then(move |res| {
+                          let mut dstate = dstate_c2.lock().unwrap();
+                          dstate.dialing.remove(&addr);
+                          dstate.candidates.closed(addr, Instant::now());
+                          res
+                      });
+        handle.spawn(until_stopped(f, dstate.stop.clone()).map_err(move |err| error!(log2, "peer failed: {}", err)));
     }
+}
 
-    let future_root = future::join_all(top_futures);
+/// Run a loop that periodically sends peer exchange messages.
+fn run_pex(handle: reactor::Handle, dstate_c: AM<DownloaderState>) -> BxFuture<(), Error> {
+    use futures::future::Loop::Continue;
 
-    core.run(future_root)?;
-    Ok(())
+    future::loop_fn((), move |()| {
+        let duration = Duration::from_secs(pex::PEX_INTERVAL_SECS);
+        let dstate_c = dstate_c.clone();
+        match reactor::Timeout::new(duration, &handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout
+                .map_err(|e| e.into())
+                .map(move |()| {
+                    let mut dstate = dstate_c.lock().unwrap();
+                    send_pex(&mut dstate);
+                    Continue(())
+                }).bxed(),
+        }
+    })
+            .bxed()
+}
+
+/// Tell peers which support peer exchange how our set of connected peers has changed.
+fn send_pex(dstate: &mut DownloaderState) {
+    let connected: HashSet<SocketAddr> = dstate
+        .peer_states
+        .values()
+        .filter_map(|ps| ps.listen_addr())
+        .collect();
+    for ps in dstate.peer_states.values_mut() {
+        let remote_id = match ps.extensions.as_ref().and_then(|x| x.id(pex::EXTENSION_NAME)) {
+            Some(id) => id,
+            None => continue,
+        };
+        let own = ps.listen_addr();
+        let msg = PexMessage {
+            added: connected
+                .iter()
+                .filter(|addr| Some(**addr) != own && !ps.pex_sent.contains(addr))
+                .take(pex::MAX_PEX_PEERS)
+                .cloned()
+                .collect(),
+            dropped: ps.pex_sent
+                .iter()
+                .filter(|addr| !connected.contains(addr))
+                .take(pex::MAX_PEX_PEERS)
+                .cloned()
+                .collect(),
+        };
+        if msg.is_empty() {
+            continue;
+        }
+        for addr in msg.added.iter() {
+            ps.pex_sent.insert(*addr);
+        }
+        for addr in msg.dropped.iter() {
+            ps.pex_sent.remove(addr);
+        }
+        ps.send(Message::Extended {
+                    id: remote_id,
+                    payload: msg.encode(),
+                });
+    }
+}
+
+/// Run a loop that handles keep-alives and request timeouts.
+fn run_peer_timers(log: Logger, handle: reactor::Handle, dstate_c: AM<DownloaderState>) -> BxFuture<(), Error> {
+    use futures::future::Loop::Continue;
+
+    future::loop_fn((), move |()| {
+        let duration = Duration::from_millis(PEER_TIMER_INTERVAL_MILLIS);
+        let log = log.clone();
+        let dstate_c = dstate_c.clone();
+        match reactor::Timeout::new(duration, &handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout
+                .map_err(|e| e.into())
+                .map(move |()| {
+                    let mut dstate = dstate_c.lock().unwrap();
+                    check_peer_timers(&log, &mut dstate);
+                    Continue(())
+                }).bxed(),
+        }
+    })
+            .bxed()
+}
+
+/// Run a loop that serves requests held back by the upload limiters.
+fn run_uploader(log: Logger, handle: reactor::Handle, dstate_c: AM<DownloaderState>) -> BxFuture<(), Error> {
+    use futures::future::Loop::Continue;
+
+    future::loop_fn((), move |()| {
+        let duration = Duration::from_millis(UPLOAD_INTERVAL_MILLIS);
+        let log = log.clone();
+        let dstate_c = dstate_c.clone();
+        match reactor::Timeout::new(duration, &handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout
+                .map_err(|e| e.into())
+                .map(move |()| {
+                    let mut dstate = dstate_c.lock().unwrap();
+                    let dstate = &mut *dstate;
+                    for ps in dstate.peer_states.values_mut() {
+                        if let Err(err) = serve_uploads(&log, &mut dstate.datastore, &dstate.shared, ps) {
+                            warn!(log, "could not serve uploads: {}", err);
+                        }
+                    }
+                    Continue(())
+                }).bxed(),
+        }
+    })
+            .bxed()
 }
 
+/// Send keep-alives that are due.
+/// Expire requests from peers which have stopped delivering blocks
+/// so that the blocks can be requested from other peers.
+fn check_peer_timers(log: &Logger, dstate: &mut DownloaderState) {
+    let now = Instant::now();
+    let keepalive_interval = Duration::from_secs(KEEPALIVE_INTERVAL_SECS);
+    let request_timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    let fastest = dstate
+        .peer_states
+        .values_mut()
+        .map(|ps| ps.download.rate(now))
+        .fold(0.0, f64::max);
+    for (&peer_num, ps) in dstate.peer_states.iter_mut() {
+        if now.duration_since(ps.last_keepalive) >= keepalive_interval {
+            ps.send(Message::KeepAlive);
+            ps.last_keepalive = now;
+        }
+
+        ps.speed = match ps.snubbed || ps.download.rate(now) < fastest * SLOW_PEER_FRACTION {
+            true => PeerSpeed::Slow,
+            false => PeerSpeed::Fast,
+        };
+
+        // A peer which is delivering slowly but steadily is not stalled.
+        if now.duration_since(ps.last_block) < request_timeout {
+            continue;
+        }
+        let expired = dstate.outstanding.expired(peer_num, now - request_timeout);
+        if expired.is_empty() {
+            continue;
+        }
+        if !ps.snubbed {
+            info!(log, "peer is snubbing us"; "peer_num" => peer_num);
+            ps.snubbed = true;
+        }
+        for req in expired {
+            debug!(log, "request timed out: {:?}", req; "peer_num" => peer_num);
+            dstate.outstanding.clear(peer_num, req);
+            ps.send(cancel_message(req));
+        }
+    }
+}
+
+/// Run a loop that announces to the tracker.
+/// Announces the start, then regularly, early when short of peers, and once the download completes.
+/// `completed` says whether the completion has already been announced or needs no announcing.
+fn run_tracker(log: Logger, handle: reactor::Handle, dstate_c: AM<DownloaderState>, completed: bool) -> BxFuture<(), Error> {
+    use futures::future::Loop::Continue;
+
+    // No schedule until the first announce has been tried.
+    let init: (Option<AnnounceSchedule>, bool, bool) = (None, false, completed);
+    future::loop_fn(init, move |(schedule, started, completed)| {
+        let duration = Duration::from_millis(TRACKER_CHECK_INTERVAL_MILLIS);
+        let log = log.clone();
+        let dstate_c = dstate_c.clone();
+        match reactor::Timeout::new(duration, &handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout
+                .map_err(|e| e.into())
+                .and_then(move |()| {
+                    let now = Instant::now();
+                    let (event, stats, tc) = {
+                        let dstate = dstate_c.lock().unwrap();
+                        let want_peers = dstate.peer_states.len() + dstate.candidates.len() < MAX_PEERS;
+                        let allowed = schedule.as_ref().map(|s| s.allowed(now)).unwrap_or(true);
+                        let event = if !started {
+                            if allowed { Some(TrackerEvent::Started) } else { None }
+                        } else if !completed && dstate.manifest.manifest.is_all_verified() && schedule.as_ref().map(|s| s.may_complete(now)).unwrap_or(true) {
+                            Some(TrackerEvent::Completed)
+                        } else if schedule.as_ref().map(|s| s.due(now, want_peers)).unwrap_or(true) {
+                            Some(TrackerEvent::Periodical)
+                        } else {
+                            None
+                        };
+                        (event, dstate.transfer_stats(), dstate.tracker.clone())
+                    };
+                    let event = match event {
+                        Some(event) => event,
+                        None => return future::ok(Continue((schedule, started, completed))).bxed(),
+                    };
+                    let mut schedule = schedule.unwrap_or_else(|| AnnounceSchedule::new(now));
+                    debug!(log, "announcing to tracker: {:?} {:?}", event, stats);
+                    announce(&tc, event, stats)
+                        .then(move |res| {
+                            let now = Instant::now();
+                            match res {
+                                Ok(res) => {
+                                    if let Some(ref warning) = res.warning_message {
+                                        warn!(log, "tracker warning: {}", warning);
+                                    }
+                                    info!(log, "tracker returned {} peers", res.peers.len());
+                                    schedule.succeeded(&res, now);
+                                    let mut dstate = dstate_c.lock().unwrap();
+                                    for peer in res.peers {
+                                        dstate.candidates.add(peer.address, peer.peer_id, now);
+                                    }
+                                    Ok(Continue((Some(schedule), true, completed || event == TrackerEvent::Completed)))
+                                }
+                                Err(err) => {
+                                    warn!(log, "announce failed: {}", err);
+                                    schedule.failed(now);
+                                    Ok(Continue((Some(schedule), started, completed)))
+                                }
+                            }
+                        })
+                        .bxed()
+                }).bxed(),
+        }
+    })
+            .bxed()
+}
+
+/// Announce to the tracker without blocking the event loop.
+fn announce(tc: &Arc<Mutex<TrackerClient>>, event: TrackerEvent, stats: TransferStats) -> BxFuture<TrackerResponse, Error> {
+    let (tx, rx) = oneshot::channel();
+    let tc = tc.clone();
+    thread::spawn(move || {
+                      let res = tc.lock().unwrap().announce(event, stats);
+                      let _ = tx.send(res);
+                  });
+    rx.then(|res| match res {
+                Ok(res) => res,
+                Err(_) => Err("tracker announce thread exited".into()),
+            })
+        .bxed()
+}
+
+/// Run a loop that periodically chooses which peers to upload to.
+fn run_choker(log: Logger, handle: reactor::Handle, dstate_c: AM<DownloaderState>) -> BxFuture<(), Error> {
+    use futures::future::Loop::Continue;
+
+    future::loop_fn((), move |()| {
+        let duration = Duration::from_secs(choker::RECHOKE_INTERVAL_SECS);
+        let log = log.clone();
+        let dstate_c = dstate_c.clone();
+        match reactor::Timeout::new(duration, &handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout
+                .map_err(|e| e.into())
+                .and_then(move |()| {
+                    let mut dstate = dstate_c.lock().unwrap();
+                    rechoke(&log, &mut dstate)?;
+                    Ok(Continue(()))
+                }).bxed(),
+        }
+    })
+            .bxed()
+}
+
+/// Choke and unchoke peers as the choker decides.
+/// Peers are judged by how fast they upload to us,
+/// or once we are seeding by how fast we upload to them.
+fn rechoke(log: &Logger, dstate: &mut DownloaderState) -> Result<()> {
+    let now = Instant::now();
+    let seeding = dstate.manifest.manifest.is_all_verified();
+    let candidates: Vec<Candidate<PeerNum>> = dstate
+        .peer_states
+        .iter_mut()
+        .map(|(&peer_num, ps)| {
+                 Candidate {
+                     peer: peer_num,
+                     interested: ps.peer_interested,
+                     rate: match seeding {
+                         true => ps.upload.rate(now),
+                         false => ps.download.rate(now),
+                     },
+                 }
+             })
+        .collect();
+    let unchoke = dstate.choker.rechoke(&candidates, now)?;
+    for (peer_num, ps) in dstate.peer_states.iter_mut() {
+        let choke = !unchoke.contains(peer_num);
+        if choke == ps.am_choking {
+            continue;
+        }
+        debug!(log, "{}", if choke { "choking peer" } else { "unchoking peer" }; "peer_num" => *peer_num);
+        ps.am_choking = choke;
+        if choke {
+            ps.send(Message::Choke);
+            // Choking discards queued requests.
+            // With the fast extension each one must be rejected.
+            let queued: Vec<BlockRequest> = ps.upload_queue.drain(..).collect();
+            if ps.fast() {
+                for req in queued {
+                    ps.send(reject_message(req));
+                }
+            }
+        } else {
+            ps.send(Message::Unchoke);
+        }
+    }
+    Ok(())
+}
 
 /// Run a loop that prints a progress report occasionally.
 fn run_progress_report(log: Logger, handle: reactor::Handle, dstate_c: AM<DownloaderState>) -> BxFuture<(), Error> {
-    use futures::future::Loop::{Break, Continue};
+    use futures::future::Loop::Continue;
 
     future::loop_fn((log, handle, dstate_c), |(log, handle, dstate_c)| {
         let duration = Duration::from_millis(500);
@@ -130,41 +769,65 @@ fn run_progress_report(log: Logger, handle: reactor::Handle, dstate_c: AM<Downlo
             Ok(timeout) => timeout
                 .map_err(|e| e.into())
                 .and_then(|()| {
-                    let pres = {
+                    {
                         let mut dstate = dstate_c.lock().unwrap();
-                        progress_report(log.clone(), &mut dstate)
-                    };
-                    match pres {
-                        Err(err) => Err(Into::<Error>::into(err)),
-                        Ok(true) => Ok(Continue((log, handle, dstate_c))),
-                        Ok(false) => Ok(Break(())),
+                        progress_report(log.clone(), &mut dstate)?;
                     }
+                    Ok(Continue((log, handle, dstate_c)))
                 }).bxed(),
         }
     })
             .bxed()
 }
 
-/// Returns whether to continue looping.
-fn progress_report(log: Logger, dstate: &mut DownloaderState) -> Result<bool> {
+/// Notices when the download completes and seeding starts.
+fn progress_report(log: Logger, dstate: &mut DownloaderState) -> Result<()> {
     let chokers = dstate
         .peer_states
         .iter()
         .filter(|&(ref _k, ref v)| v.peer_choking)
         .count();
+    let snubbers = dstate
+        .peer_states
+        .values()
+        .filter(|ps| ps.snubbed)
+        .count();
     let n_peers = dstate.peer_states.len();
+    let now = Instant::now();
+    if dstate.seeding_since.is_none() && dstate.manifest.manifest.is_all_verified() {
+        info!(log, "all pieces verified, seeding");
+        dstate.seeding_since = Some(now);
+    }
+    let (down, up) = dstate
+        .peer_states
+        .values_mut()
+        .fold((0.0, 0.0), |(down, up), ps| (down + ps.download.rate(now), up + ps.upload.rate(now)));
 
     // let bar = dstate.manifest.manifest.progress_bar();
     // info!(log, "progress report: {}", bar);
     let p = dstate.manifest.manifest.amount_verified();
     info!(log,
-          "progress: {:03}%  chokers:{}/{}",
+          "progress: {:03}%  chokers:{}/{}  snubbers:{}  down:{}/{}  up:{}/{}  ratio:{:.2}",
           p * (100 as f64),
           chokers,
-          n_peers);
+          n_peers,
+          snubbers,
+          DisplayRate(Some(down as u64)),
+          DisplayRate(dstate.shared.download_limiter.lock().unwrap().rate()),
+          DisplayRate(Some(up as u64)),
+          DisplayRate(dstate.shared.upload_limiter.lock().unwrap().rate()),
+          dstate.share_ratio());
+
+    if now.duration_since(dstate.last_peer_report) >= Duration::from_secs(PEER_REPORT_INTERVAL_SECS) {
+        dstate.last_peer_report = now;
+        let mut peers: Vec<(&PeerNum, &mut PeerState)> = dstate.peer_states.iter_mut().collect();
+        peers.sort_by_key(|&(peer_num, _)| *peer_num);
+        for (peer_num, ps) in peers {
+            info!(log, "peer: {}", ps.report(now); "peer_num" => *peer_num);
+        }
+    }
 
-    let go = !dstate.manifest.manifest.is_all_verified();
-    Ok(go)
+    Ok(())
 }
 
 /// Connect and run a peer.
@@ -173,49 +836,32 @@ fn progress_report(log: Logger, dstate: &mut DownloaderState) -> Result<bool> {
 fn run_peer(log: Logger,
             handle: reactor::Handle,
             dstate_c: AM<DownloaderState>,
-            peer: tracker::Peer,
+            shared: Arc<Shared>,
+            addr: SocketAddr,
+            expected_peer_id: Option<PeerID>,
             info_hash: InfoHash,
             num_pieces: u64,
             local_peer_id: PeerID,
             peer_num: PeerNum)
             -> BxFuture<(), Error> {
-
     let log2 = log.clone();
-    connect_peer(&log,
-                 peer.address,
-                 info_hash.clone(),
-                 local_peer_id.clone(),
-                 peer_num,
+    let connect = connect_peer(&log,
+                               addr,
+                               expected_peer_id,
+                               info_hash.clone(),
+                               local_peer_id.clone(),
+                               peer_num,
+                               num_pieces,
+                               shared.encryption,
+                               shared.utp.clone(),
+                               &handle);
+    with_timeout(connect,
+                 Duration::from_millis(HANDSHAKE_TIMEOUT_MILLIS),
                  &handle)
-            .and_then(move |(stream, remote_peer_id)| {
-                // Create peer state
-                {
-                    let mut dstate = dstate_c.lock().unwrap();
-                    let x = dstate
-                        .peer_states
-                        .insert(peer_num, PeerState::new(num_pieces, remote_peer_id));
-                    if x.is_some() {
-                        warn!(log, "peer state already existed"; "peer_num" => peer_num)
-                    }
-                }
-
-                let dstate_c2 = dstate_c.clone();
-                drive_peer(&log, dstate_c, &handle, stream, peer_num)
-                    .then(move |res| {
-                        // Delete peer state
-                        let mut dstate = dstate_c2.lock().unwrap();
-                        let x = dstate.peer_states.remove(&peer_num);
-                        if x.is_none() {
-                            warn!(log,  "peer state was missing"; "peer_num" => peer_num)
-                        }
-
-                        let n = dstate.outstanding.clear_peer(peer_num);
-                        debug!(log, "cleared outstanding requests: {}", n; "peer_num" => peer_num);
-
-                        res
-                    })
-                    .bxed()
-            })
+            .and_then(move |(stream, remote)| {
+                          dstate_c.lock().unwrap().candidates.connected(addr);
+                          run_connected_peer(log, handle, dstate_c, stream, remote, num_pieces, peer_num)
+                      })
             .or_else(move |err| {
                          error!(log2, "peer error: {}", err);
                          Ok(())
@@ -223,39 +869,245 @@ fn run_peer(log: Logger,
             .bxed()
 }
 
+/// Run a peer which connected to us and asked for this torrent.
+/// `stream` has been read up to the remote peer id.
+/// Logs most errors. Any returned error is a programming error.
+pub fn run_inbound_peer(log: Logger,
+                        handle: reactor::Handle,
+                        dstate_c: AM<DownloaderState>,
+                        stream: EncryptedStream<Transport>,
+                        addr: SocketAddr,
+                        reserved: Reserved,
+                        peer_num: PeerNum)
+                        -> BxFuture<(), Error> {
+    let (info_hash, local_peer_id, num_pieces, stop) = {
+        let dstate = dstate_c.lock().unwrap();
+        if dstate.banned.contains(&addr.ip()) {
+            debug!(log, "rejected connection from banned peer {}", addr);
+            return future::ok(()).bxed();
+        }
+        (dstate.info.info_hash.clone(), dstate.shared.peer_id.clone(), dstate.info.num_pieces() as u64, dstate.stop.clone())
+    };
+    let log2 = log.clone();
+    let accept = accept_peer(&log, stream, addr, reserved, info_hash, local_peer_id, num_pieces);
+    let f = with_timeout(accept,
+                         Duration::from_millis(HANDSHAKE_TIMEOUT_MILLIS),
+                         &handle)
+        .and_then(move |(stream, remote)| run_connected_peer(log, handle, dstate_c, stream, remote, num_pieces, peer_num))
+        .or_else(move |err| {
+                     error!(log2, "peer error: {}", err);
+                     Ok(())
+                 });
+    until_stopped(f, stop)
+}
+
+/// Run the peer loop on a peer which has completed the handshake.
+/// Manages the lifetime of the peer's state.
+fn run_connected_peer(log: Logger,
+                      handle: reactor::Handle,
+                      dstate_c: AM<DownloaderState>,
+                      stream: PeerFramed,
+                      remote: RemotePeer,
+                      num_pieces: u64,
+                      peer_num: PeerNum)
+                      -> BxFuture<(), Error> {
+    // Channel of messages to send to the peer.
+    let (buf_tx, buf_rx) = futures::sync::mpsc::unbounded::<Message>();
+
+    let addr = remote.addr;
+
+    // Create peer state
+    {
+        let mut dstate = dstate_c.lock().unwrap();
+
+        // The same peer may connect to us while we connect to it.
+        if dstate.peer_states.values().any(|ps| ps.peer_id == remote.peer_id) {
+            return future::err(format!("already connected to peer {:?}", remote.peer_id).into()).bxed();
+        }
+        info!(log, "peer client: {}", remote.peer_id);
+
+        let rstate = PeerState::new(num_pieces, remote, buf_tx.clone(), &dstate.limits);
+
+        // Tell the peer what we have.
+        let bits = dstate.manifest.manifest.verified_bits();
+        if rstate.fast() {
+            // With the fast extension something must always be sent first.
+            if bits.iter().all(|b| *b) {
+                rstate.send(Message::HaveAll);
+            } else if bits.iter().all(|b| !*b) {
+                rstate.send(Message::HaveNone);
+            } else {
+                rstate.send(Message::Bitfield { bits: bits });
+            }
+        } else if bits.iter().any(|b| *b) {
+            rstate.send(Message::Bitfield { bits: bits });
+        }
+
+        if rstate.reserved.extension_protocol() {
+            let hs = ExtensionHandshake::local(dstate.shared.port,
+                                               MAX_UPLOAD_QUEUE as u64,
+                                               Some(dstate.info.info_bytes.len() as u64));
+            rstate.send(Message::Extended {
+                            id: extension::HANDSHAKE_ID,
+                            payload: hs.encode(),
+                        });
+        }
+
+        let x = dstate.peer_states.insert(peer_num, rstate);
+        if x.is_some() {
+            warn!(log, "peer state already existed"; "peer_num" => peer_num)
+        }
+    }
+
+    let dstate_c2 = dstate_c.clone();
+    drive_peer(&log, dstate_c, &handle, stream, buf_tx, buf_rx, peer_num)
+        .then(move |res| {
+            // Delete peer state
+            let mut dstate = dstate_c2.lock().unwrap();
+
+            // Protocol violations only close the connection.
+            // Addresses are banned for deliberate abuse, see `add_bad_block`.
+            if let Err(Error(ErrorKind::Protocol(ref msg), _)) = res {
+                warn!(log, "closing peer {}: {}", addr, msg);
+            }
+
+            match dstate.peer_states.remove(&peer_num) {
+                Some(ps) => dstate.availability.remove_peer(&ps.has),
+                None => warn!(log,  "peer state was missing"; "peer_num" => peer_num),
+            }
+
+            let n = dstate.outstanding.clear_peer(peer_num);
+            debug!(log, "cleared outstanding requests: {}", n; "peer_num" => peer_num);
+
+            res
+        })
+        .bxed()
+}
+
+/// What we learn about a remote peer from the handshake.
+#[derive(Debug, Clone)]
+struct RemotePeer {
+    peer_id: PeerID,
+    reserved: Reserved,
+    addr: SocketAddr,
+    /// Whether the peer connected to us.
+    inbound: bool,
+    /// Whether the connection is over uTP.
+    utp: bool,
+}
+
 /// Connect to a remote peer
-fn connect_peer(log: &Logger, addr: SocketAddr, info_hash: InfoHash, peer_id: PeerID, peer_num: PeerNum, handle: &reactor::Handle) -> BxFuture<(PeerFramed, PeerID), Error> {
+/// If `expected_peer_id` is given the remote must have that id.
+fn connect_peer(log: &Logger, addr: SocketAddr, expected_peer_id: Option<PeerID>, info_hash: InfoHash, peer_id: PeerID, peer_num: PeerNum, num_pieces: u64, encryption: EncryptionPolicy, utp: Option<UtpSocket>, handle: &reactor::Handle) -> BxFuture<(PeerFramed, RemotePeer), Error> {
     let info_hash2 = info_hash.clone();
 
     let log1 = log.clone();
     let log2 = log.clone();
     let log3 = log.clone();
+    let local_peer_id = peer_id.clone();
 
     info!(log, "connecting to {} ...", addr);
-    tcp_connect2(&addr, Duration::from_millis(3000), &handle)
-        .chain_err(|| "peer connection failed")
+    connect_transport(log, addr, info_hash.clone(), encryption, utp, handle)
         .and_then(move |stream| {
-                      info!(log1, "connected");
+                      info!(log1, "connected"; "encrypted" => stream.is_encrypted(), "utp" => stream.get_ref().is_utp());
                       peer_protocol::handshake_send_async(stream, info_hash.clone(), peer_id.clone())
                   })
         .and_then(|stream| peer_protocol::handshake_read_1_async(stream))
-        .and_then(move |(stream, remote_info_hash)| {
+        .and_then(move |(stream, remote_info_hash, reserved)| {
             debug!(log2, "remote info hash: {:?}", remote_info_hash);
+            debug!(log2, "remote reserved: {:?}", reserved);
             if remote_info_hash != info_hash2 {
                 bail!("peer [{}] info hash mismatch peer:{:?} me:{:?}",
                       peer_num,
                       remote_info_hash,
                       info_hash2);
             }
-            Ok(stream)
+            Ok((stream, reserved))
         })
-        .and_then(|stream| peer_protocol::handshake_read_2_async(stream).map(move |(stream, remote_peer_id)| (stream, remote_peer_id)))
-        .and_then(move |(stream, remote_peer_id)| {
-                      debug!(log3, "remote peer id: {:?}", remote_peer_id);
-
+        .and_then(|(stream, reserved)| peer_protocol::handshake_read_2_async(stream).map(move |(stream, remote_peer_id)| (stream, remote_peer_id, reserved)))
+        .and_then(move |(stream, remote_peer_id, reserved)| {
+                      debug!(log3, "remote peer id: {:?}", remote_peer_id; "client" => format!("{}", remote_peer_id));
+                      check_remote_peer_id(&remote_peer_id, &local_peer_id)?;
+                      if let Some(expected) = expected_peer_id {
+                          if remote_peer_id != expected {
+                              bail!("peer id {:?} does not match tracker's {:?}",
+                                    remote_peer_id,
+                                    expected);
+                          }
+                      }
+
+                      let utp = stream.get_ref().is_utp();
                       // let stream: Framed<TcpStream,BitTorrentPeerCodec> = stream.framed(BitTorrentPeerCodec);
-                      let stream: PeerFramed = stream.framed(BitTorrentPeerCodec);
-                      Ok((stream, remote_peer_id))
+                      let stream: PeerFramed = stream.framed(BitTorrentPeerCodec::new(Some(num_pieces), DEFAULT_MAX_MESSAGE_LENGTH));
+                      let remote = RemotePeer {
+                          peer_id: remote_peer_id,
+                          reserved: reserved,
+                          addr: addr,
+                          inbound: false,
+                          utp: utp,
+                      };
+                      Ok((stream, remote))
+                  })
+        .bxed()
+}
+
+/// Refuse to talk to ourselves.
+fn check_remote_peer_id(remote: &PeerID, local: &PeerID) -> Result<()> {
+    if remote == local {
+        bail!("connected to self");
+    }
+    Ok(())
+}
+
+/// Open a connection to a peer, encrypted according to the policy.
+/// When encryption is optional and its handshake fails, reconnects in plaintext.
+fn connect_transport(log: &Logger, addr: SocketAddr, info_hash: InfoHash, encryption: EncryptionPolicy, utp: Option<UtpSocket>, handle: &reactor::Handle) -> BxFuture<EncryptedStream<Transport>, Error> {
+    let connect = transport::connect(log, addr, utp.as_ref(), handle);
+    if encryption == EncryptionPolicy::Disabled {
+        return connect.map(EncryptedStream::plaintext).bxed();
+    }
+
+    let log = log.clone();
+    let handle = handle.clone();
+    connect
+        .and_then(move |stream| {
+            let encrypted = with_timeout(mse::connect(stream, info_hash, encryption),
+                                         Duration::from_millis(ENCRYPTION_TIMEOUT_MILLIS),
+                                         &handle);
+            if encryption == EncryptionPolicy::Required {
+                return encrypted;
+            }
+            encrypted
+                .or_else(move |err| {
+                             info!(log, "encryption handshake failed, retrying in plaintext: {}", err);
+                             transport::connect(&log, addr, utp.as_ref(), &handle).map(EncryptedStream::plaintext)
+                         })
+                .bxed()
+        })
+        .bxed()
+}
+
+/// Complete the handshake with a remote peer that connected to us.
+/// The session has already read the start of its handshake to find the torrent.
+fn accept_peer(log: &Logger, stream: EncryptedStream<Transport>, addr: SocketAddr, reserved: Reserved, info_hash: InfoHash, peer_id: PeerID, num_pieces: u64) -> BxFuture<(PeerFramed, RemotePeer), Error> {
+    let log = log.clone();
+    let local_peer_id = peer_id.clone();
+
+    peer_protocol::handshake_send_async(stream, info_hash, peer_id)
+        .and_then(|stream| peer_protocol::handshake_read_2_async(stream))
+        .and_then(move |(stream, remote_peer_id)| {
+                      debug!(log, "remote peer id: {:?}", remote_peer_id; "client" => format!("{}", remote_peer_id));
+                      check_remote_peer_id(&remote_peer_id, &local_peer_id)?;
+                      let utp = stream.get_ref().is_utp();
+                      let stream: PeerFramed = stream.framed(BitTorrentPeerCodec::new(Some(num_pieces), DEFAULT_MAX_MESSAGE_LENGTH));
+                      let remote = RemotePeer {
+                          peer_id: remote_peer_id,
+                          reserved: reserved,
+                          addr: addr,
+                          inbound: true,
+                          utp: utp,
+                      };
+                      Ok((stream, remote))
                   })
         .bxed()
 }
@@ -266,11 +1118,17 @@ enum HandlePeerMessageRes {
     Close,
 }
 
-fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stream: PeerFramed, peer_num: PeerNum) -> BxFuture<(), Error> {
+fn drive_peer(log: &Logger,
+              dstate_c: AM<DownloaderState>,
+              handle: &Handle,
+              stream: PeerFramed,
+              buf_tx: UnboundedSender<Message>,
+              buf_rx: UnboundedReceiver<Message>,
+              peer_num: PeerNum)
+              -> BxFuture<(), Error> {
     let (peer_tx, peer_rx) = stream.split();
 
     // Separate send from receive so that the listener doesn't block all the time
-    let (buf_tx, buf_rx) = futures::sync::mpsc::channel::<Message>(5);
 
     // debugging type assertions
     // let _: &Stream<Item = Message, Error = ()> = &buf_rx;
@@ -278,9 +1136,21 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
     // let _: &Stream<Item = Message, Error = Error> = &buf_rx.map_err(|()| "lol".into());
     // let _: &Future<Item = (_, _), Error = Error> = &peer_tx.send_all(buf_rx.map_err(|()| "lol".into()));
 
-    // Process the send channel
+    // Process the send channel.
+    // Blocks are counted as uploaded once they are written,
+    // and each one makes room for the peer's next request.
     let log2 = log.clone();
-    handle.spawn(peer_tx.send_all(buf_rx.map_err(|()| Into::<Error>::into("peer send failed")))
+    let dstate_c2 = dstate_c.clone();
+    let buf_rx = buf_rx
+        .map_err(|()| Into::<Error>::into("peer send failed"))
+        .map(move |msg| {
+            if let Message::Piece { ref block, .. } = msg {
+                block_sent(&log2, &mut dstate_c2.lock().unwrap(), peer_num, block.len() as u64);
+            }
+            msg
+        });
+    let log2 = log.clone();
+    handle.spawn(peer_tx.send_all(buf_rx)
         .map(|(_sink, _stream)| ())
         .map_err(move |e| {
             warn!(log2, "warning: send to peer failed: {}", e);
@@ -299,6 +1169,8 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
         peer_num: PeerNum,
         peer_rx: S,
         peer_tx: U,
+        /// Wait before reading the next message, for the download limiters.
+        wait: Duration,
     }
 
     let init = LoopState {
@@ -307,26 +1179,37 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
         peer_num: peer_num,
         peer_rx: peer_rx,
         peer_tx: buf_tx.sink_map_err(|send_err| format!("error sending message: {}", send_err).into()),
+        wait: Duration::from_secs(0),
     };
 
     use futures::future::Loop;
-    future::loop_fn(init, |LoopState {
+    let handle = handle.clone();
+    future::loop_fn(init, move |LoopState {
                          log,
                          peer_num,
                          dstate_c,
                          peer_rx,
                          peer_tx,
+                         wait,
                      }|
      -> BxFuture<Loop<(), LoopState<_, _>>, Error> {
-        peer_rx
-            .into_future()
-            .map_err(|(err, _stream)| err)
+        let recv = sleep(wait, &handle).and_then(move |()| {
+                                                      peer_rx
+                                                          .into_future()
+                                                          .map_err(|(err, _stream)| err)
+                                                  });
+        // Peers which go quiet for too long are gone.
+        with_timeout(recv, wait + Duration::from_secs(IDLE_TIMEOUT_SECS), &handle)
             .and_then(move |(item, peer_rx)| -> BxFuture<Loop<(), LoopState<_, _>>, Error> {
                 match item {
                     Some(msg) => {
-                        let cmd: Result<HandlePeerMessageRes> = {
+                        let (cmd, wait): (Result<HandlePeerMessageRes>, Duration) = {
                             let mut dstate = dstate_c.lock().unwrap();
-                            handle_peer_message(&log, &mut dstate, peer_num, &msg)
+                            let wait = match msg {
+                                Message::Piece { ref block, .. } => dstate.throttle_download(peer_num, block.len() as u64),
+                                _ => Duration::from_secs(0),
+                            };
+                            (handle_peer_message(&log, &mut dstate, peer_num, &msg), wait)
                         };
                         use self::HandlePeerMessageRes::*;
                         match cmd {
@@ -337,6 +1220,7 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
                                                               peer_num,
                                                               peer_rx,
                                                               peer_tx,
+                                                              wait,
                                                           }))
                                         .bxed()
                             }
@@ -350,6 +1234,7 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
                                                            peer_num,
                                                            peer_rx,
                                                            peer_tx,
+                                                           wait,
                                                        })
                                     })
                                     .bxed()
@@ -358,6 +1243,10 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
                                 debug!(log, "closing peer connection");
                                 future::ok(Loop::Break(())).bxed()
                             }
+                            Err(Error(ErrorKind::Protocol(msg), state)) => {
+                                // Pass protocol violations up so they are reported.
+                                future::err(Error(ErrorKind::Protocol(msg), state)).bxed()
+                            }
                             Err(err) => {
                                 error!(log, "closing peer due to error: {:?}", err);
                                 future::ok(Loop::Break(())).bxed()
@@ -378,6 +1267,71 @@ fn drive_peer(log: &Logger, dstate_c: AM<DownloaderState>, handle: &Handle, stre
 /// One synchronous step.
 /// Returns messages to send.
 fn handle_peer_message(log: &Logger, dstate: &mut DownloaderState, peer_num: PeerNum, msg: &Message) -> Result<HandlePeerMessageRes> {
+    let mut verified = Vec::new();
+    let mut flunked = Vec::new();
+    let mut cancels = Vec::new();
+    let res = handle_peer_message_inner(log, dstate, peer_num, msg, &mut verified, &mut flunked, &mut cancels);
+    for &piece in verified.iter().chain(flunked.iter()) {
+        dstate.outstanding.piece_finished(piece);
+        dstate.picker.invalidate();
+    }
+    // Flunked pieces are downloaded again.
+    for &piece in flunked.iter() {
+        dstate.missing_blocks += count_missing_blocks(&dstate.manifest.manifest, piece..piece + 1).unwrap_or(0);
+    }
+    for piece in verified {
+        broadcast_have(dstate, piece);
+        let v = dstate.provenance.verified(piece);
+        for contributor in v.contributors {
+            if let Some(ps) = dstate.peer_states.get_mut(&contributor) {
+                ps.pieces_contributed += 1;
+            }
+        }
+        for ip in v.culprits {
+            add_bad_block(log, dstate, ip);
+        }
+    }
+    for piece in flunked {
+        for ip in dstate.provenance.flunked(piece) {
+            add_bad_block(log, dstate, ip);
+        }
+    }
+    for (other, req) in cancels {
+        if let Some(ps) = dstate.peer_states.get(&other) {
+            debug!(log, "cancelling duplicate request: {:?}", req; "peer_num" => other);
+            ps.send(cancel_message(req));
+        }
+    }
+    res
+}
+
+/// Count a corrupt block against an address, banning it once it has sent too many.
+/// Connected peers from a banned address are closed when they next send a message.
+fn add_bad_block(log: &Logger, dstate: &mut DownloaderState, ip: IpAddr) {
+    let n = {
+        let n = dstate.bad_blocks.entry(ip).or_insert(0);
+        *n += 1;
+        *n
+    };
+    warn!(log, "peer {} sent a corrupt block ({} so far)", ip, n);
+    if n > dstate.max_bad_blocks && dstate.banned.insert(ip) {
+        warn!(log, "banning peer {} for sending corrupt data", ip);
+    }
+}
+
+/// Tell peers that don't have it that we now have a piece.
+fn broadcast_have(dstate: &DownloaderState, piece: u64) {
+    for (_, ps) in dstate.peer_states.iter() {
+        if !ps.has.has(piece) {
+            ps.send(Message::Have { piece: piece as u32 });
+        }
+    }
+}
+
+/// Handle a message.
+/// Appends newly verified pieces to `verified` and pieces which failed verification to `flunked`.
+/// Appends requests to other peers which are no longer needed to `cancels`.
+fn handle_peer_message_inner(log: &Logger, dstate: &mut DownloaderState, peer_num: PeerNum, msg: &Message, verified: &mut Vec<u64>, flunked: &mut Vec<u64>, cancels: &mut Vec<(PeerNum, BlockRequest)>) -> Result<HandlePeerMessageRes> {
     use self::HandlePeerMessageRes::*;
 
     debug!(log, "n-out {}", dstate.outstanding.get_num(peer_num));
@@ -387,6 +1341,11 @@ fn handle_peer_message(log: &Logger, dstate: &mut DownloaderState, peer_num: Pee
         .get_mut(&peer_num)
         .ok_or_else(|| Into::<Error>::into(format!("missing peer state: {}", peer_num).to_owned()))?;
 
+    if dstate.banned.contains(&rstate.addr.ip()) {
+        debug!(log, "closing banned peer");
+        return Ok(Close);
+    }
+
     let mut outs = VecDeque::new();
     debug!(log, "recv message";
            "msg" => msg.summarize(),
@@ -396,11 +1355,18 @@ fn handle_peer_message(log: &Logger, dstate: &mut DownloaderState, peer_num: Pee
         &Message::KeepAlive => {}
         &Message::Choke => {
             rstate.peer_choking = true;
-            dstate.outstanding.clear_peer(peer_num);
+            // With the fast extension choking does not discard requests.
+            // The peer rejects each one instead.
+            if !rstate.fast() {
+                dstate.outstanding.clear_peer(peer_num);
+            }
         }
         &Message::Unchoke => {
             rstate.peer_choking = false;
-            dstate.outstanding.clear_peer(peer_num);
+            // Allowed fast requests made while choked are still answered.
+            if !rstate.fast() {
+                dstate.outstanding.clear_peer(peer_num);
+            }
         }
         &Message::Interested => rstate.peer_interested = true,
         &Message::NotInterested => rstate.peer_interested = false,
@@ -410,6 +1376,7 @@ fn handle_peer_message(log: &Logger, dstate: &mut DownloaderState, peer_num: Pee
                       bits.len(),
                       dstate.info.num_pieces());
             }
+            dstate.availability.remove_peer(&rstate.has);
             let mut i_start = 0;
             let mut in_interval = false;
             for b in 0..dstate.info.num_pieces() {
@@ -426,100 +1393,294 @@ fn handle_peer_message(log: &Logger, dstate: &mut DownloaderState, peer_num: Pee
                     .has
                     .add(i_start as u64, dstate.info.num_pieces() as u64)?;
             }
+            dstate.availability.add_peer(&rstate.has);
         }
         &Message::Have { piece } => {
-            rstate.has.add(piece as u64, piece as u64 + 1)?;
+            if !rstate.has.has(piece as u64) {
+                rstate.has.add(piece as u64, piece as u64 + 1)?;
+                dstate.availability.add_piece(piece as u64);
+            }
+        }
+        &Message::HaveAll => {
+            if !rstate.fast() {
+                bail!(ErrorKind::Protocol("HaveAll from peer without fast extension".to_owned()));
+            }
+            dstate.availability.remove_peer(&rstate.has);
+            rstate.has.fill();
+            dstate.availability.add_peer(&rstate.has);
+        }
+        &Message::HaveNone => {
+            if !rstate.fast() {
+                bail!(ErrorKind::Protocol("HaveNone from peer without fast extension".to_owned()));
+            }
+            dstate.availability.remove_peer(&rstate.has);
+            rstate.has.clear();
         }
-        &Message::Request { .. } => {
-            bail!("not implemented");
+        &Message::Suggest { piece } => {
+            if !rstate.fast() {
+                bail!(ErrorKind::Protocol("Suggest from peer without fast extension".to_owned()));
+            }
+            debug!(log, "peer suggests piece {}", piece);
+        }
+        &Message::AllowedFast { piece } => {
+            if !rstate.fast() {
+                bail!(ErrorKind::Protocol("AllowedFast from peer without fast extension".to_owned()));
+            }
+            dstate.info.size_info.check_piece(piece as u64)?;
+            rstate.allowed_fast.insert(piece as u64);
+        }
+        &Message::Reject {
+            piece,
+            offset,
+            length,
+        } => {
+            if !rstate.fast() {
+                bail!(ErrorKind::Protocol("Reject from peer without fast extension".to_owned()));
+            }
+            let req = BlockRequest {
+                piece: piece as u64,
+                offset: offset as u64,
+                length: length as u64,
+            };
+            if dstate.outstanding.clear(peer_num, req) == 0 {
+                debug!(log, "peer rejected a block we did not request: {:?}", req);
+            }
+        }
+        &Message::Request {
+            piece,
+            offset,
+            length,
+        } => {
+            let req = BlockRequest {
+                piece: piece as u64,
+                offset: offset as u64,
+                length: length as u64,
+            };
+            let have = check_upload_request(&dstate.info, &dstate.manifest.manifest, req)?;
+            if rstate.am_choking || !have {
+                debug!(log, "not serving request: {:?}", req;
+                       "choked" => rstate.am_choking,
+                       "have" => have);
+                if rstate.fast() {
+                    outs.push_back(reject_message(req));
+                }
+            } else {
+                if rstate.upload_queue.len() >= MAX_UPLOAD_QUEUE {
+                    bail!("peer exceeded upload queue length {}", MAX_UPLOAD_QUEUE);
+                }
+                if !rstate.upload_queue.contains(&req) {
+                    rstate.upload_queue.push_back(req);
+                }
+            }
         }
         &Message::Piece {
             piece,
             offset,
             ref block,
         } => {
-            dstate
-                .datastore
-                .write_block(piece as u64, offset as u64, &block)?;
-            let newly_filled = dstate
-                .manifest
-                .manifest
-                .add_block(piece as u64, offset as u64, block.len() as u64)?;
-            for p in newly_filled {
-                let expected_hash = dstate.info.piece_hashes[p as usize].clone();
-                info!(log, "filled piece: {}", p);
-                if let Some(verified) = dstate.datastore.verify_piece(p, expected_hash)? {
-                    info!(log, "verified piece: {}", verified.piece);
-                    dstate.manifest.manifest.mark_verified(verified)?;
-                } else {
-                    info!(log, "flunked piece: {}", p);
-                    dstate.manifest.manifest.remove_piece(p)?;
+            dstate.downloaded += block.len() as u64;
+            let req = BlockRequest {
+                piece: piece as u64,
+                offset: offset as u64,
+                length: block.len() as u64,
+            };
+            let requested_at = dstate.outstanding.requested_at(peer_num, req);
+            rstate.block_received(block.len() as u64, requested_at, Instant::now());
+            if rstate.snubbed {
+                debug!(log, "peer stopped snubbing us");
+                rstate.snubbed = false;
+            }
+            // In endgame the same block is requested from several peers.
+            // Copies arriving after the first are dropped.
+            if dstate.manifest.manifest.has_block(req)? {
+                debug!(log, "ignoring duplicate block: {:?}", req);
+            } else {
+                for other in dstate.outstanding.get_peers(req) {
+                    if other != peer_num {
+                        dstate.outstanding.clear(other, req);
+                        cancels.push((other, req));
+                    }
+                }
+                dstate
+                    .provenance
+                    .add_block(piece as u64, offset as u64, &block, peer_num, rstate.addr.ip());
+                dstate
+                    .datastore
+                    .write_block(piece as u64, offset as u64, &block)?;
+                let last_piece = dstate
+                    .info
+                    .size_info
+                    .piece_at_point(piece as u64, offset as u64 + cmp::max(block.len() as u64, 1) - 1);
+                let pieces = piece as u64..last_piece + 1;
+                let before = count_missing_blocks(&dstate.manifest.manifest, pieces.clone())?;
+                let newly_filled = dstate
+                    .manifest
+                    .manifest
+                    .add_block(piece as u64, offset as u64, block.len() as u64)?;
+                let after = count_missing_blocks(&dstate.manifest.manifest, pieces)?;
+                dstate.missing_blocks = dstate.missing_blocks.saturating_sub(before - after);
+                for p in newly_filled {
+                    let expected_hash = dstate.info.piece_hashes[p as usize].clone();
+                    info!(log, "filled piece: {}", p);
+                    if let Some(v) = dstate.datastore.verify_piece(p, expected_hash)? {
+                        info!(log, "verified piece: {}", v.piece);
+                        verified.push(v.piece);
+                        dstate.manifest.manifest.mark_verified(v)?;
+                    } else {
+                        info!(log, "flunked piece: {}", p);
+                        flunked.push(p);
+                        dstate.manifest.manifest.remove_piece(p)?;
+                    }
                 }
+                dstate.manifest.store(log)?;
+            }
+            dstate.outstanding.clear(peer_num, req);
+        }
+        &Message::Cancel {
+            piece,
+            offset,
+            length,
+        } => {
+            let req = BlockRequest {
+                piece: piece as u64,
+                offset: offset as u64,
+                length: length as u64,
+            };
+            let n_queued = rstate.upload_queue.len();
+            rstate.upload_queue.retain(|x| *x != req);
+            // With the fast extension every request gets a piece or a reject.
+            if rstate.upload_queue.len() < n_queued && rstate.fast() {
+                outs.push_back(reject_message(req));
             }
-
-            dstate
-                .outstanding
-                .clear(peer_num,
-                       BlockRequest {
-                           piece: piece as u64,
-                           offset: offset as u64,
-                           length: block.len() as u64,
-                       });
-
-            dstate.manifest.store(log)?;
         }
-        &Message::Cancel { .. } => bail!("not implemented"),
         &Message::Port { .. } => {}
+        &Message::Extended { id, ref payload } => {
+            if !rstate.reserved.extension_protocol() {
+                bail!(ErrorKind::Protocol("extended message from peer without extension protocol".to_owned()));
+            }
+            if id == extension::HANDSHAKE_ID {
+                let hs = ExtensionHandshake::decode(payload)?;
+                debug!(log, "extension handshake: {:?}", hs);
+                let merged = match rstate.extensions.take() {
+                    Some(mut x) => {
+                        x.update(hs);
+                        x
+                    }
+                    None => hs,
+                };
+                rstate.extensions = Some(merged);
+            } else {
+                match extension::local_extension_name(id) {
+                    Some(metadata::EXTENSION_NAME) => {
+                        if let MetadataMessage::Request { piece } = MetadataMessage::decode(payload)? {
+                            let remote_id = rstate.extensions
+                                .as_ref()
+                                .and_then(|x| x.id(metadata::EXTENSION_NAME))
+                                .ok_or("metadata request from peer without ut_metadata")?;
+                            let info_bytes = &dstate.info.info_bytes;
+                            let reply = match metadata::piece_data(info_bytes, piece) {
+                                Some(data) => {
+                                    MetadataMessage::Data {
+                                        piece: piece,
+                                        total_size: info_bytes.len() as u64,
+                                        data: data.to_vec(),
+                                    }
+                                }
+                                None => MetadataMessage::Reject { piece: piece },
+                            };
+                            outs.push_back(Message::Extended {
+                                               id: remote_id,
+                                               payload: reply.encode(),
+                                           });
+                        }
+                    }
+                    Some(pex::EXTENSION_NAME) => {
+                        let pex = PexMessage::decode(payload)?;
+                        debug!(log, "peer exchange: {} added {} dropped", pex.added.len(), pex.dropped.len());
+                        for addr in pex.added {
+                            dstate.candidates.add(addr, None, Instant::now());
+                        }
+                    }
+                    Some(name) => debug!(log, "unhandled extension message: {}", name),
+                    // We never advertised the id, or the peer raced an update to our ids.
+                    None => debug!(log, "ignoring unknown extended message id {}", id),
+                }
+            }
+        }
     }
 
-    if rstate.temp.nreceived >= 1 && rstate.am_choking {
-        let out = Message::Unchoke {};
-        debug!(log, "sending message: {:?}", out);
-        rstate.am_choking = false;
-        outs.push_back(out);
-    }
-    if rstate.temp.nreceived >= 1 && !rstate.am_interested {
+    serve_uploads(log, &mut dstate.datastore, &dstate.shared, rstate)?;
+
+    // Once seeding there is nothing left to want.
+    let seeding = dstate.manifest.manifest.is_all_verified();
+    if rstate.temp.nreceived >= 1 && !seeding && !rstate.am_interested {
         let out = Message::Interested {};
         debug!(log, "sending message: {:?}", out);
         rstate.am_interested = true;
         outs.push_back(out);
+    } else if seeding && rstate.am_interested {
+        let out = Message::NotInterested {};
+        debug!(log, "sending message: {:?}", out);
+        rstate.am_interested = false;
+        outs.push_back(out);
     }
-    if !rstate.peer_choking && rstate.am_interested {
-        for safety in 0.. {
-            if safety == 99 {
-                error!(log, "collecting too many requests to send!");
-            }
-            match next_request(log, &mut dstate.manifest, &mut dstate.outstanding, peer_num)? {
-                None => {
-                    if dstate.manifest.manifest.is_all_full() {
-                        verify_all(log,
-                                   &dstate.info,
-                                   &mut dstate.manifest,
-                                   &mut dstate.datastore)?;
-
-                        if dstate.manifest.manifest.is_all_verified() {
-                            println!("all pieces verified!");
-                            return Ok(Close);
-                        }
-                    } else {
-                        if safety == 0 {
-                            debug!(log, "not requesting");
-                        }
-                    }
-                    break;
-                }
-                Some(desire) => {
-                    let out = Message::Request {
-                        piece: desire.piece as u32,
-                        offset: desire.offset as u32,
-                        length: desire.length as u32,
-                    };
-                    dstate.outstanding.add(peer_num, desire);
-                    debug!(log, "sending message: {:?}", out);
-                    outs.push_back(out);
-                }
+    // Two seeds have nothing to trade.
+    // Both addresses are excluded so the connector does not dial the seed again.
+    if seeding && rstate.has.is_full() {
+        debug!(log, "closing connection to another seed");
+        dstate.candidates.exclude(rstate.addr);
+        if let Some(addr) = rstate.listen_addr() {
+            dstate.candidates.exclude(addr);
+        }
+        return Ok(Close);
+    }
+    // While choked only allowed fast pieces may be requested.
+    let allowed_fast: Option<HashSet<u64>> = match rstate.peer_choking {
+        true => Some(rstate
+                         .allowed_fast
+                         .iter()
+                         .filter(|p| rstate.has.has(**p))
+                         .cloned()
+                         .collect()),
+        false => None,
+    };
+    let may_request = allowed_fast.as_ref().map(|x| !x.is_empty()).unwrap_or(true);
+    if may_request && rstate.am_interested {
+        let max_outstanding = rstate.pipeline_depth(Instant::now());
+        let desires = next_requests(&dstate.manifest.manifest,
+                                    &dstate.outstanding,
+                                    &mut dstate.picker,
+                                    dstate.missing_blocks,
+                                    &dstate.availability,
+                                    &rstate.has,
+                                    peer_num,
+                                    rstate.speed,
+                                    max_outstanding,
+                                    allowed_fast.as_ref(),
+                                    &dstate.provenance.suspected_pieces(rstate.addr.ip()))?;
+        if desires.is_empty() {
+            if dstate.manifest.manifest.is_all_full() {
+                let (newly, newly_flunked) = verify_all(log,
+                                                        &dstate.info,
+                                                        &mut dstate.manifest,
+                                                        &mut dstate.datastore)?;
+                verified.extend(newly);
+                flunked.extend(newly_flunked);
+
+            } else {
+                debug!(log, "not requesting");
             }
         }
+        for desire in desires {
+            let out = Message::Request {
+                piece: desire.piece as u32,
+                offset: desire.offset as u32,
+                length: desire.length as u32,
+            };
+            dstate.outstanding.add(peer_num, desire, rstate.speed);
+            debug!(log, "sending message: {:?}", out);
+            outs.push_back(out);
+        }
     }
 
     if outs.len() > 0 {
@@ -529,62 +1690,203 @@ fn handle_peer_message(log: &Logger, dstate: &mut DownloaderState, peer_num: Pee
     }
 }
 
-/// Decide the next block to request from a peer.
-fn next_request(log: &Logger, manifest: &mut ManifestWithFile, outstanding: &mut OutstandingRequestsManager, peer_num: PeerNum) -> Result<Option<BlockRequest>> {
-    const MAX_OUTSTANDING_PER_PEER: u64 = 5;
-    const MAX_OUTSTANDING_PER_BLOCK: u64 = 1;
-    if outstanding.get_num(peer_num) >= MAX_OUTSTANDING_PER_PEER {
-        // Already plenty of requests outstanding on this peer.
-        return Ok(None);
+/// Send blocks the peer has asked for while the upload limiters allow
+/// and its send channel is not full.
+/// The rest stay queued so that cancels can still reach them.
+fn serve_uploads(log: &Logger, datastore: &mut DataStore, shared: &Shared, ps: &mut PeerState) -> Result<()> {
+    while ps.queued_upload_bytes < MAX_QUEUED_UPLOAD_BYTES && !ps.upload_queue.is_empty() {
+        let now = Instant::now();
+        let mut limiter = shared.upload_limiter.lock().unwrap();
+        if !limiter.ready(now) || !ps.upload_limiter.ready(now) {
+            break;
+        }
+        let req = ps.upload_queue.pop_front().unwrap();
+        let block = datastore.read_block(req.piece, req.offset, req.length)?;
+        let len = block.len() as u64;
+        limiter.take(len, now);
+        ps.upload_limiter.take(len, now);
+        ps.queued_upload_bytes += len;
+        let out = Message::Piece {
+            piece: req.piece as u32,
+            offset: req.offset as u32,
+            block: block,
+        };
+        debug!(log, "sending message: {}", out.summarize());
+        ps.send(out);
     }
-    let mut after = None;
-    for safety in 0.. {
-        if safety == 99 {
-            error!(log, "loop has gone too far looking for next request!");
+    Ok(())
+}
+
+/// Account for a block written to a peer and serve the peer's next requests.
+fn block_sent(log: &Logger, dstate: &mut DownloaderState, peer_num: PeerNum, bytes: u64) {
+    dstate.uploaded += bytes;
+    if let Some(ps) = dstate.peer_states.get_mut(&peer_num) {
+        ps.upload.add(bytes, Instant::now());
+        ps.queued_upload_bytes = ps.queued_upload_bytes.saturating_sub(bytes);
+        if let Err(err) = serve_uploads(log, &mut dstate.datastore, &dstate.shared, ps) {
+            warn!(log, "could not serve uploads: {}", err);
         }
+    }
+}
+
+/// Check whether a block requested by a peer can be served.
+/// Returns an error for requests that violate the protocol.
+/// Returns false for valid requests of pieces that are not verified.
+fn check_upload_request(info: &MetaInfo, manifest: &Manifest, req: BlockRequest) -> Result<bool> {
+    if req.length == 0 || req.length > MAX_REQUEST_LENGTH {
+        bail!("requested block length {} not in (0, {}]",
+              req.length,
+              MAX_REQUEST_LENGTH);
+    }
+    info.size_info.check_piece(req.piece)?;
+    if req.offset + req.length > info.size_info.piece_size(req.piece) {
+        bail!("requested block overruns piece {:?}", req);
+    }
+    manifest.is_verified(req.piece)
+}
+
+/// Message cancelling a request.
+fn cancel_message(req: BlockRequest) -> Message {
+    Message::Cancel {
+        piece: req.piece as u32,
+        offset: req.offset as u32,
+        length: req.length as u32,
+    }
+}
 
-        if let Some(desire) = manifest.manifest.next_desired_block(log, after) {
-            // TODO: allow multiple outstanding per block, maybe, and if so remember to cancel upon receive.
-            let ps = outstanding.get_peers(desire);
-            if ps.len() > MAX_OUTSTANDING_PER_BLOCK as usize {
-                after = Some(desire);
+/// Message rejecting a request.
+fn reject_message(req: BlockRequest) -> Message {
+    Message::Reject {
+        piece: req.piece as u32,
+        offset: req.offset as u32,
+        length: req.length as u32,
+    }
+}
+
+/// Decide the next blocks to request from a peer.
+/// Pieces are tried in the order the picker ranks them.
+/// If `only_pieces` is given, only blocks in those pieces are considered.
+/// Normally each block is requested from one peer at a time.
+/// Once every missing block has been requested the download is in endgame,
+/// and blocks outstanding on other peers are requested from this one too.
+/// Pieces in `suspected` are left to other peers until endgame.
+fn next_requests(manifest: &Manifest,
+                 outstanding: &OutstandingRequestsManager,
+                 picker: &mut PieceOrder,
+                 missing_blocks: u64,
+                 availability: &Availability,
+                 has: &Fillable,
+                 peer_num: PeerNum,
+                 speed: PeerSpeed,
+                 max_outstanding: u64,
+                 only_pieces: Option<&HashSet<u64>>,
+                 suspected: &HashSet<u64>)
+                 -> Result<Vec<BlockRequest>> {
+    let n_outstanding = outstanding.get_num(peer_num);
+    if n_outstanding >= max_outstanding {
+        // Already plenty of requests outstanding on this peer.
+        return Ok(Vec::new());
+    }
+    let want = (max_outstanding - n_outstanding) as usize;
+    let allowed = |piece: &u64| only_pieces.map(|only| only.contains(piece)).unwrap_or(true);
+    let mut desires = Vec::new();
+    let pieces = picker
+        .rank(has, availability, manifest, outstanding.started(), speed)
+        .filter(&allowed);
+    for piece in pieces.filter(|piece| !suspected.contains(piece)) {
+        let mut offset = 0;
+        while let Some(desire) = manifest.missing_block(piece, offset)? {
+            offset = desire.offset + desire.length;
+            if outstanding.is_requested(desire) {
                 continue;
             }
-            if ps.contains(&peer_num) {
-                after = Some(desire);
+            desires.push(desire);
+            if desires.len() >= want {
+                return Ok(desires);
+            }
+        }
+    }
+    if !desires.is_empty() || !is_endgame(missing_blocks, outstanding) {
+        return Ok(desires);
+    }
+    let pieces = picker
+        .rank(has, availability, manifest, outstanding.started(), speed)
+        .filter(&allowed);
+    for piece in pieces {
+        let mut offset = 0;
+        while let Some(desire) = manifest.missing_block(piece, offset)? {
+            offset = desire.offset + desire.length;
+            if outstanding.is_requested_from(peer_num, desire) {
                 continue;
             }
-            return Ok(Some(desire));
+            desires.push(desire);
+            if desires.len() >= want {
+                return Ok(desires);
+            }
         }
-        return Ok(None);
     }
-    Ok(None)
+    Ok(desires)
+}
+
+/// Whether every missing block has been requested from some peer.
+/// Only missing blocks are ever requested, so it is enough to count them.
+fn is_endgame(missing_blocks: u64, outstanding: &OutstandingRequestsManager) -> bool {
+    outstanding.num_blocks() >= missing_blocks
+}
+
+/// Number of blocks missing from some pieces, as `next_requests` would request them.
+fn count_missing_blocks(manifest: &Manifest, pieces: Range<u64>) -> Result<u64> {
+    let mut n = 0;
+    for piece in pieces {
+        let mut offset = 0;
+        while let Some(block) = manifest.missing_block(piece, offset)? {
+            offset = block.offset + block.length;
+            n += 1;
+        }
+    }
+    Ok(n)
 }
 
 /// Call this when the download might be done.
 /// Run verification on the data, save the manifest.
 /// If this function returns Ok that does _not_ mean all verified.
-fn verify_all(log: &Logger, info: &MetaInfo, manifest: &mut ManifestWithFile, datastore: &mut DataStore) -> Result<()> {
+/// Returns the pieces which were newly verified and those which failed verification.
+fn verify_all(log: &Logger, info: &MetaInfo, manifest: &mut ManifestWithFile, datastore: &mut DataStore) -> Result<(Vec<u64>, Vec<u64>)> {
+    let mut newly = Vec::new();
+    let mut flunked = Vec::new();
     // No more blocks needed! Unless something fails verification.
     for piece in manifest.manifest.needs_verify() {
         let expected_hash = info.piece_hashes[piece as usize].clone();
         info!(log, "verifying piece: {}", piece);
         if let Some(verified) = datastore.verify_piece(piece, expected_hash)? {
             info!(log, "verified piece: {}", verified.piece);
+            newly.push(verified.piece);
             manifest.manifest.mark_verified(verified)?;
         } else {
             info!(log, "flunked piece: {}", piece);
+            flunked.push(piece);
             manifest.manifest.remove_piece(piece)?;
         }
     }
     manifest.store(log)?;
-    Ok(())
+    Ok((newly, flunked))
 }
 
 #[derive(Debug)]
 pub struct PeerState {
     /// ID of the remote peer
     peer_id: PeerID,
+    /// Address of the remote peer
+    addr: SocketAddr,
+    /// Whether the peer connected to us
+    inbound: bool,
+    /// Whether the connection is over uTP
+    utp: bool,
+    /// Extensions the peer advertised in its handshake
+    reserved: Reserved,
+    /// Extension protocol handshake received from the peer.
+    /// None until the peer sends one.
+    extensions: Option<ExtensionHandshake>,
 
     /// Peer is interested in this client
     peer_interested: bool,
@@ -595,6 +1897,42 @@ pub struct PeerState {
     am_choking: bool,
 
     has: Fillable,
+    /// Pieces the peer allows us to request while choked (fast extension)
+    allowed_fast: HashSet<u64>,
+
+    /// Blocks the peer has requested which have not been sent yet.
+    upload_queue: VecDeque<BlockRequest>,
+
+    /// Peers we have told this peer about with peer exchange.
+    pex_sent: HashSet<SocketAddr>,
+
+    /// When we last received a block from the peer, or connected.
+    last_block: Instant,
+    /// Whether the peer has stopped delivering the blocks we request.
+    snubbed: bool,
+    /// Whether the peer is fast enough to help finish pieces other peers started.
+    speed: PeerSpeed,
+    /// When we last sent a keep-alive.
+    last_keepalive: Instant,
+    /// Rate of blocks received from the peer.
+    download: RateMeter,
+    /// Rate of blocks sent to the peer.
+    upload: RateMeter,
+    /// Limits blocks received from the peer.
+    download_limiter: TokenBucket,
+    /// Limits blocks sent to the peer.
+    upload_limiter: TokenBucket,
+    /// Smoothed time from request to block.
+    rtt: Option<Duration>,
+    /// Bytes of blocks waiting in the send channel.
+    queued_upload_bytes: u64,
+    /// When the connection was established.
+    connected_at: Instant,
+    /// Number of verified pieces the peer sent blocks of.
+    pieces_contributed: u64,
+
+    /// Send messages to the peer.
+    tx: UnboundedSender<Message>,
 
     temp: TempState,
 }
@@ -605,24 +1943,144 @@ pub struct TempState {
 }
 
 impl PeerState {
-    fn new(num_pieces: u64, peer_id: PeerID) -> Self {
+    fn new(num_pieces: u64, remote: RemotePeer, tx: UnboundedSender<Message>, limits: &RateLimits) -> Self {
         PeerState {
-            peer_id: peer_id,
+            peer_id: remote.peer_id,
+            addr: remote.addr,
+            inbound: remote.inbound,
+            utp: remote.utp,
+            reserved: remote.reserved,
+            extensions: None,
             peer_interested: false,
             peer_choking: true,
             am_interested: false,
             am_choking: true,
             has: Fillable::new(num_pieces),
+            allowed_fast: HashSet::new(),
+            upload_queue: VecDeque::new(),
+            pex_sent: HashSet::new(),
+            last_block: Instant::now(),
+            snubbed: false,
+            speed: PeerSpeed::Fast,
+            last_keepalive: Instant::now(),
+            download: RateMeter::new(Instant::now()),
+            upload: RateMeter::new(Instant::now()),
+            download_limiter: TokenBucket::new(limits.peer_download, Instant::now()),
+            upload_limiter: TokenBucket::new(limits.peer_upload, Instant::now()),
+            rtt: None,
+            queued_upload_bytes: 0,
+            connected_at: Instant::now(),
+            pieces_contributed: 0,
+            tx: tx,
             temp: TempState::default(),
         }
     }
+
+    /// Whether the fast extension is in use.
+    /// Both sides must advertise it, and we always do.
+    fn fast(&self) -> bool {
+        self.reserved.fast_extension()
+    }
+
+    /// Address the peer accepts connections on, if known.
+    /// The source port of an incoming connection is not its listening port.
+    fn listen_addr(&self) -> Option<SocketAddr> {
+        if !self.inbound {
+            return Some(self.addr);
+        }
+        self.extensions
+            .as_ref()
+            .and_then(|x| x.port)
+            .map(|port| SocketAddr::new(self.addr.ip(), port))
+    }
+
+    /// Account for a block received from the peer.
+    /// The round trip is timed from the request, or from the previous block if that came later,
+    /// so that time spent queued behind other blocks does not count.
+    fn block_received(&mut self, bytes: u64, requested_at: Option<Instant>, now: Instant) {
+        if let Some(requested_at) = requested_at {
+            let start = cmp::max(requested_at, self.last_block);
+            self.add_rtt_sample(now.duration_since(start));
+        }
+        self.last_block = now;
+        self.download.add(bytes, now);
+    }
+
+    fn add_rtt_sample(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+                            Some(rtt) => (rtt * 7 + sample) / 8,
+                            None => sample,
+                        });
+    }
+
+    /// How many requests to keep outstanding on the peer.
+    /// Covers the bandwidth delay product so fast peers stay busy
+    /// while slow peers don't hold blocks others could deliver.
+    /// Bounded by the request queue length the peer advertises.
+    fn pipeline_depth(&mut self, now: Instant) -> u64 {
+        if self.snubbed {
+            return MAX_OUTSTANDING_PER_SNUBBED_PEER;
+        }
+        let rtt = self.rtt.unwrap_or(Duration::from_millis(INITIAL_RTT_MILLIS));
+        let rtt_secs = rtt.as_secs() as f64 + rtt.subsec_nanos() as f64 / 1e9;
+        let depth = (self.download.rate(now) * (rtt_secs + PIPELINE_SLACK_SECS) / BLOCK_SIZE as f64).ceil() as u64;
+        let reqq = self.extensions
+            .as_ref()
+            .and_then(|x| x.reqq)
+            .unwrap_or(DEFAULT_REQQ);
+        let max = cmp::max(1, cmp::min(reqq, MAX_PIPELINE_DEPTH));
+        cmp::min(max, cmp::max(MIN_PIPELINE_DEPTH, depth))
+    }
+
+    /// One line summary of the peer's statistics.
+    fn report(&mut self, now: Instant) -> String {
+        let mut flags = String::new();
+        if self.peer_choking {
+            flags.push_str(" choking");
+        }
+        if !self.am_choking {
+            flags.push_str(" unchoked");
+        }
+        if self.peer_interested {
+            flags.push_str(" interested");
+        }
+        if self.snubbed {
+            flags.push_str(" snubbed");
+        }
+        if self.utp {
+            flags.push_str(" utp");
+        }
+        format!("{} {} age:{}s down:{}kB at {} up:{}kB at {} rtt:{} pieces:{}{}",
+                self.addr,
+                self.peer_id,
+                now.duration_since(self.connected_at).as_secs(),
+                self.download.total() / 1024,
+                DisplayRate(Some(self.download.rate(now) as u64)),
+                self.upload.total() / 1024,
+                DisplayRate(Some(self.upload.rate(now) as u64)),
+                self.rtt
+                    .map(|rtt| format!("{}ms", rtt.as_secs() * 1000 + rtt.subsec_nanos() as u64 / 1_000_000))
+                    .unwrap_or_else(|| "?".to_owned()),
+                self.pieces_contributed,
+                flags)
+    }
+
+    /// Queue a message to send to the peer.
+    /// Messages sent after the connection has closed are dropped.
+    fn send(&self, msg: Message) {
+        let _ = UnboundedSender::send(&self.tx, msg);
+    }
 }
 
 struct OutstandingRequestsManager {
-    /// Blocks for each peer.
-    peer_blocks: HashMap<PeerNum, HashSet<BlockRequest>>,
+    /// Blocks for each peer, when they were requested and how fast the peer was.
+    peer_blocks: HashMap<PeerNum, HashMap<BlockRequest, (Instant, PeerSpeed)>>,
     /// Peers for each block
     block_peers: HashMap<BlockRequest, HashSet<PeerNum>>,
+    /// Requests outstanding to fast peers for each piece.
+    fast_requests: HashMap<u64, u32>,
+    /// Pieces being downloaded, with the fastest of the peers they are requested from.
+    started: HashMap<u64, PeerSpeed>,
 }
 
 impl OutstandingRequestsManager {
@@ -630,53 +2088,110 @@ impl OutstandingRequestsManager {
         Self {
             peer_blocks: HashMap::new(),
             block_peers: HashMap::new(),
+            fast_requests: HashMap::new(),
+            started: HashMap::new(),
         }
     }
 
-    fn add(&mut self, peer: PeerNum, block: BlockRequest) {
-        self.peer_blocks
+    fn add(&mut self, peer: PeerNum, block: BlockRequest, speed: PeerSpeed) {
+        let old = self.peer_blocks
             .entry(peer)
-            .or_insert(HashSet::new())
-            .insert(block);
+            .or_insert(HashMap::new())
+            .insert(block, (Instant::now(), speed));
+        if let Some((_, old_speed)) = old {
+            self.forget(block, old_speed);
+        }
 
         self.block_peers
             .entry(block)
             .or_insert(HashSet::new())
             .insert(peer);
+
+        self.piece_started(block.piece);
+        if speed == PeerSpeed::Fast {
+            *self.fast_requests.entry(block.piece).or_insert(0) += 1;
+            self.started.insert(block.piece, PeerSpeed::Fast);
+        }
     }
 
     /// Returns the number of cleared items: 0 or 1.
     fn clear(&mut self, peer: PeerNum, block: BlockRequest) -> usize {
-        if let Some(peers) = self.block_peers.get_mut(&block) {
-            peers.remove(&peer);
-
-            if let Some(blocks) = self.peer_blocks.get_mut(&peer) {
-                blocks.remove(&block);
+        let removed = self.peer_blocks
+            .get_mut(&peer)
+            .and_then(|blocks| blocks.remove(&block));
+        match removed {
+            Some((_, speed)) => {
+                self.remove_block_peer(peer, block);
+                self.forget(block, speed);
+                1
             }
-
-            return 1;
-        } else {
-            return 0;
+            None => 0,
         }
     }
 
     /// Clear all outstanding requests for a peer.
     /// Returns the number of cleared items.
     fn clear_peer(&mut self, peer: PeerNum) -> usize {
-        if let Some(blocks) = self.peer_blocks.remove(&peer) {
-            let mut x = 0;
-            for block in blocks.iter() {
-                if let Some(peers) = self.block_peers.get_mut(&block) {
-                    peers.remove(&peer);
-                    x += 1;
-                }
+        let blocks = self.peer_blocks.remove(&peer).unwrap_or_else(|| HashMap::new());
+        for (&block, &(_, speed)) in blocks.iter() {
+            self.remove_block_peer(peer, block);
+            self.forget(block, speed);
+        }
+        blocks.len()
+    }
+
+    fn remove_block_peer(&mut self, peer: PeerNum, block: BlockRequest) {
+        let empty = match self.block_peers.get_mut(&block) {
+            Some(peers) => {
+                peers.remove(&peer);
+                peers.is_empty()
             }
-            return x;
-        } else {
-            return 0;
+            None => false,
+        };
+        if empty {
+            self.block_peers.remove(&block);
         }
     }
 
+    /// Account for a request which is no longer outstanding.
+    /// The piece stays started until it is finished.
+    fn forget(&mut self, block: BlockRequest, speed: PeerSpeed) {
+        if speed != PeerSpeed::Fast {
+            return;
+        }
+        let done = match self.fast_requests.get_mut(&block.piece) {
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            }
+            None => false,
+        };
+        if done {
+            self.fast_requests.remove(&block.piece);
+            if let Some(started) = self.started.get_mut(&block.piece) {
+                *started = PeerSpeed::Slow;
+            }
+        }
+    }
+
+    /// Number of distinct blocks requested from any peer.
+    fn num_blocks(&self) -> u64 {
+        self.block_peers.len() as u64
+    }
+
+    /// Whether the block is requested from any peer.
+    fn is_requested(&self, block: BlockRequest) -> bool {
+        self.block_peers.contains_key(&block)
+    }
+
+    /// Whether the block is requested from the peer.
+    fn is_requested_from(&self, peer: PeerNum, block: BlockRequest) -> bool {
+        self.peer_blocks
+            .get(&peer)
+            .map(|blocks| blocks.contains_key(&block))
+            .unwrap_or(false)
+    }
+
     /// Get the set of peers with outstanding requests for the block
     fn get_peers(&self, block: BlockRequest) -> HashSet<PeerNum> {
         return self.block_peers
@@ -685,6 +2200,44 @@ impl OutstandingRequestsManager {
                    .unwrap_or_else(|| HashSet::new());
     }
 
+    /// Mark a piece as being downloaded, for pieces partly downloaded before.
+    fn piece_started(&mut self, piece: u64) {
+        self.started.entry(piece).or_insert(PeerSpeed::Slow);
+    }
+
+    /// Call when a piece is verified or fails verification.
+    fn piece_finished(&mut self, piece: u64) {
+        self.fast_requests.remove(&piece);
+        self.started.remove(&piece);
+    }
+
+    /// Pieces being downloaded, with the fastest of the peers they are requested from.
+    fn started(&self) -> &HashMap<u64, PeerSpeed> {
+        &self.started
+    }
+
+    /// When a block was requested from a peer.
+    fn requested_at(&self, peer: PeerNum, block: BlockRequest) -> Option<Instant> {
+        self.peer_blocks
+            .get(&peer)
+            .and_then(|blocks| blocks.get(&block))
+            .map(|&(at, _)| at)
+    }
+
+    /// Get the requests to a peer which were made before `before`.
+    fn expired(&self, peer: PeerNum, before: Instant) -> Vec<BlockRequest> {
+        self.peer_blocks
+            .get(&peer)
+            .map(|blocks| {
+                     blocks
+                         .iter()
+                         .filter(|&(_, &(at, _))| at < before)
+                         .map(|(block, _)| *block)
+                         .collect()
+                 })
+            .unwrap_or_else(|| Vec::new())
+    }
+
     /// Get the number of requests outstanding for the peer
     fn get_num(&self, peer: PeerNum) -> u64 {
         return self.peer_blocks
@@ -693,3 +2246,235 @@ impl OutstandingRequestsManager {
                    .unwrap_or(0);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use downloader::*;
+    use choker::ChokerConfig;
+    use futures::sync::mpsc;
+    use ring::digest;
+    use ring::rand::SystemRandom;
+    use session::SessionHandle;
+    use slog;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_pipeline_depth_slow_peer() {
+        let remote = RemotePeer {
+            peer_id: PeerID { id: [0; 20] },
+            reserved: Reserved::default(),
+            addr: "127.0.0.1:6881".parse().unwrap(),
+            inbound: false,
+            utp: false,
+        };
+        let (tx, _rx) = mpsc::unbounded();
+        let mut ps = PeerState::new(10, remote, tx, &RateLimits::default());
+
+        // The peer sends one block a second, serving requests in order.
+        let latency = Duration::from_millis(100);
+        let mut now = Instant::now();
+        let mut requests = VecDeque::new();
+        for _ in 0..300 {
+            while (requests.len() as u64) < ps.pipeline_depth(now) {
+                requests.push_back(now);
+            }
+            let requested_at = requests.pop_front().unwrap();
+            now = cmp::max(now + Duration::from_secs(1), requested_at + latency);
+            ps.block_received(BLOCK_SIZE, Some(requested_at), now);
+        }
+        // Queued requests must not inflate the round trip and the depth with it.
+        assert!(ps.rtt.unwrap() < Duration::from_millis(1500));
+        assert_eq!(ps.pipeline_depth(now), MIN_PIPELINE_DEPTH);
+    }
+
+    fn test_config(seed: bool) -> TorrentConfig {
+        TorrentConfig {
+            picker: PickerStrategy::RarestFirst,
+            choker: ChokerConfig {
+                unchoke_slots: 4,
+                optimistic_slots: 1,
+            },
+            max_bad_blocks: 2,
+            seed_ratio: None,
+            seed_time: None,
+            seed: seed,
+        }
+    }
+
+    fn test_shared(handle: &reactor::Handle) -> Arc<Shared> {
+        let utp = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap(), handle).unwrap();
+        let peer_id = PeerID::new(&SystemRandom::new()).unwrap();
+        let port = utp.local_addr().port();
+        Arc::new(Shared::new(peer_id, port, EncryptionPolicy::Required, Some(utp), RateLimits::default()))
+    }
+
+    /// A torrent of `data` in 32KiB pieces.
+    fn test_info(data: &[u8]) -> MetaInfo {
+        let piece_length = 1 << 15;
+        let num_pieces = (data.len() + piece_length - 1) / piece_length;
+        let mut info = format!("d6:lengthi{}e4:name1:a12:piece lengthi{}e6:pieces{}:", data.len(), piece_length, num_pieces * 20).into_bytes();
+        for piece in data.chunks(piece_length) {
+            info.extend(digest::digest(&digest::SHA1, piece).as_ref());
+        }
+        info.push(b'e');
+        // Announces fail, peers are introduced directly.
+        MetaInfo::from_info_bytes(&info, "http://127.0.0.1:1/announce".to_owned()).unwrap()
+    }
+
+    #[test]
+    fn test_endgame() {
+        // Two full blocks and a short one.
+        let mut manifest = Manifest::new(test_info(&vec![0; 40000]));
+        let mut missing_blocks = count_missing_blocks(&manifest, 0..2).unwrap();
+        assert_eq!(missing_blocks, 3);
+        let block = |piece, offset, length| {
+            BlockRequest {
+                piece: piece,
+                offset: offset,
+                length: length,
+            }
+        };
+        let blocks = [block(0, 0, BLOCK_SIZE), block(0, BLOCK_SIZE, BLOCK_SIZE), block(1, 0, 40000 - 2 * BLOCK_SIZE)];
+        let mut outstanding = OutstandingRequestsManager::new();
+        outstanding.add(0, blocks[0], PeerSpeed::Fast);
+        outstanding.add(1, blocks[0], PeerSpeed::Fast);
+        outstanding.add(0, blocks[1], PeerSpeed::Fast);
+        assert!(!is_endgame(missing_blocks, &outstanding));
+        outstanding.add(0, blocks[2], PeerSpeed::Fast);
+        assert!(is_endgame(missing_blocks, &outstanding));
+
+        // A received block is no longer missing or requested.
+        manifest.add_block(0, 0, BLOCK_SIZE).unwrap();
+        missing_blocks -= 1;
+        assert_eq!(count_missing_blocks(&manifest, 0..2).unwrap(), missing_blocks);
+        outstanding.clear(0, blocks[0]);
+        outstanding.clear(1, blocks[0]);
+        assert!(is_endgame(missing_blocks, &outstanding));
+
+        // A dropped request leaves its block to be requested again.
+        outstanding.clear_peer(0);
+        assert!(!is_endgame(missing_blocks, &outstanding));
+    }
+
+    /// Start downloading a torrent of 80000 bytes without running the event loop.
+    fn test_downloader(log: &Logger, handle: &reactor::Handle, dir: &Path) -> AM<DownloaderState> {
+        let info = test_info(&vec![0; 80000]);
+        let torrent = open(log, info, dir.join("data"), dir.join("manifest"), &test_config(false)).unwrap();
+        start(log.clone(), handle, test_shared(handle), torrent, test_config(false)).unwrap()
+    }
+
+    /// Add a connected peer. Messages sent to it go to the returned receiver.
+    fn add_test_peer(dstate: &mut DownloaderState, peer_num: PeerNum, reserved: Reserved) -> mpsc::UnboundedReceiver<Message> {
+        let remote = RemotePeer {
+            peer_id: PeerID { id: [peer_num as u8; 20] },
+            reserved: reserved,
+            addr: "127.0.0.1:6881".parse().unwrap(),
+            inbound: false,
+            utp: false,
+        };
+        let (tx, rx) = mpsc::unbounded();
+        let ps = PeerState::new(dstate.info.num_pieces() as u64, remote, tx, &RateLimits::default());
+        dstate.peer_states.insert(peer_num, ps);
+        rx
+    }
+
+    #[test]
+    fn test_unknown_extended_id() {
+        let log = Logger::root(slog::Discard, o!());
+        let core = Core::new().unwrap();
+        let dir = env::temp_dir().join(format!("bittles-test-extended-{}", process::id()));
+        let dstate_c = test_downloader(&log, &core.handle(), &dir);
+        let mut dstate = dstate_c.lock().unwrap();
+        let _rx = add_test_peer(&mut dstate, 0, Reserved::local());
+        let msg = Message::Extended {
+            id: 200,
+            payload: Vec::new(),
+        };
+        // The peer is kept.
+        match handle_peer_message(&log, &mut dstate, 0, &msg).unwrap() {
+            HandlePeerMessageRes::Close => panic!("peer closed"),
+            _ => {}
+        }
+        drop(dstate);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unchoke_keeps_fast_requests() {
+        let log = Logger::root(slog::Discard, o!());
+        let core = Core::new().unwrap();
+        let dir = env::temp_dir().join(format!("bittles-test-unchoke-{}", process::id()));
+        let dstate_c = test_downloader(&log, &core.handle(), &dir);
+        let mut dstate = dstate_c.lock().unwrap();
+
+        let req = BlockRequest {
+            piece: 1,
+            offset: 0,
+            length: BLOCK_SIZE,
+        };
+        let mut rxs = Vec::new();
+        for (peer_num, reserved) in vec![(0, Reserved::local()), (1, Reserved::default())] {
+            rxs.push(add_test_peer(&mut dstate, peer_num, reserved));
+            // Requested while choked, like an allowed fast piece.
+            dstate.outstanding.add(peer_num, req, PeerSpeed::Fast);
+            handle_peer_message(&log, &mut dstate, peer_num, &Message::Unchoke).unwrap();
+        }
+        // With the fast extension the peer still answers the request.
+        assert!(dstate.outstanding.requested_at(0, req).is_some());
+        // Without it unchoking means the peer dropped every request.
+        assert!(dstate.outstanding.requested_at(1, req).is_none());
+        drop(dstate);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// One peer downloads a torrent from another over encrypted uTP.
+    #[test]
+    fn test_download_over_utp() {
+        let log = Logger::root(slog::Discard, o!());
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let dir = env::temp_dir().join(format!("bittles-test-utp-{}", process::id()));
+
+        let data: Vec<u8> = (0..80000).map(|i| (i % 251) as u8).collect();
+        let info = test_info(&data);
+        let info_hash = info.info_hash.clone();
+
+        // Seed through a session, so peers come in over its listener.
+        let seeder_dir = dir.join("seeder");
+        fs::create_dir_all(seeder_dir.join(format!("{}", info_hash))).unwrap();
+        fs::write(seeder_dir.join(format!("{}/data", info_hash)), &data).unwrap();
+        let peer_id = PeerID::new(&SystemRandom::new()).unwrap();
+        let seeder = SessionHandle::new(log.clone(), &handle, peer_id, 0, EncryptionPolicy::Required, true, RateLimits::default(), seeder_dir, test_config(true)).unwrap();
+        seeder.add(info.clone(), test_config(true)).unwrap();
+        let seeder_addr = SocketAddr::new("127.0.0.1".parse().unwrap(), seeder.port());
+
+        let leecher_shared = test_shared(&handle);
+        let torrent = open(&log, info.clone(), dir.join("leecher/data"), dir.join("leecher/manifest"), &test_config(false)).unwrap();
+        let leecher = start(log.clone(), &handle, leecher_shared, torrent, test_config(false)).unwrap();
+        leecher.lock().unwrap().candidates.add(seeder_addr, None, Instant::now());
+
+        use futures::future::Loop::{Break, Continue};
+        let handle2 = handle.clone();
+        let done = future::loop_fn(false, move |saw_utp| {
+            let leecher = leecher.clone();
+            reactor::Timeout::new(Duration::from_millis(100), &handle2)
+                .unwrap()
+                .map_err(Error::from)
+                .map(move |()| {
+                    let dstate = leecher.lock().unwrap();
+                    let saw_utp = saw_utp || dstate.peer_states.values().any(|ps| ps.utp);
+                    match dstate.manifest.manifest.is_all_verified() {
+                        true => Break(saw_utp),
+                        false => Continue(saw_utp),
+                    }
+                })
+        });
+        let saw_utp = core.run(with_timeout(done, Duration::from_secs(60), &handle)).unwrap();
+        assert!(saw_utp);
+        assert!(fs::read(dir.join("leecher/data")).unwrap() == data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
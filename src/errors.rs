@@ -20,6 +20,15 @@ mod errors_gen {
             // MpscRecvError(mpsc::RecvError),
 
         }
+
+        errors {
+            /// A peer broke the wire protocol.
+            /// The connection should be closed.
+            Protocol(msg: String) {
+                description("peer protocol violation")
+                display("peer protocol violation: {}", msg)
+            }
+        }
     }
 }
 
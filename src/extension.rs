@@ -0,0 +1,172 @@
+use bip_bencode::{BDecodeOpt, BMutAccess, BRefAccess, BencodeMut, BencodeRef};
+use errors::*;
+use std::collections::HashMap;
+
+/// Extended message id reserved for the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Extensions supported by this client.
+/// Each is paired with the extended message id which peers should use to send it to us.
+pub const LOCAL_EXTENSIONS: &'static [(&'static str, u8)] = &[("ut_metadata", 1), ("ut_pex", 2)];
+
+/// Name and version sent in the extension handshake.
+pub const CLIENT_NAME: &'static str = "Bittles 0.0.1";
+
+/// Look up the name of one of our extensions by the id we assigned it.
+pub fn local_extension_name(id: u8) -> Option<&'static str> {
+    LOCAL_EXTENSIONS.iter()
+        .find(|&&(_, x)| x == id)
+        .map(|&(name, _)| name)
+}
+
+/// Look up the id we assigned one of our extensions.
+pub fn local_extension_id(name: &str) -> Option<u8> {
+    LOCAL_EXTENSIONS.iter()
+        .find(|&&(x, _)| x == name)
+        .map(|&(_, id)| id)
+}
+
+/// Contents of a BEP 10 extension handshake.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtensionHandshake {
+    /// Map from extension name to the extended message id the sender wants to receive it as.
+    /// An id of 0 means the extension is disabled.
+    pub m: HashMap<String, u8>,
+    /// TCP port the sender is listening on.
+    pub port: Option<u16>,
+    /// Client name and version.
+    pub client: Option<String>,
+    /// Number of outstanding requests the sender will queue.
+    pub reqq: Option<u64>,
+    /// Size in bytes of the info dictionary (BEP 9).
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake describing this client.
+    pub fn local(port: u16, reqq: u64, metadata_size: Option<u64>) -> Self {
+        ExtensionHandshake {
+            m: LOCAL_EXTENSIONS.iter()
+                .map(|&(name, id)| (name.to_owned(), id))
+                .collect(),
+            port: Some(port),
+            client: Some(CLIENT_NAME.to_owned()),
+            reqq: Some(reqq),
+            metadata_size: metadata_size,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut m = BencodeMut::new_dict();
+        {
+            let md = m.dict_mut().unwrap();
+            for (name, id) in self.m.iter() {
+                md.insert(name.as_bytes(), BencodeMut::new_int(*id as i64));
+            }
+        }
+        let mut d = BencodeMut::new_dict();
+        {
+            let dd = d.dict_mut().unwrap();
+            dd.insert(b"m", m);
+            if let Some(port) = self.port {
+                dd.insert(b"p", BencodeMut::new_int(port as i64));
+            }
+            if let Some(ref client) = self.client {
+                dd.insert(b"v", BencodeMut::new_bytes(client.as_bytes()));
+            }
+            if let Some(reqq) = self.reqq {
+                dd.insert(b"reqq", BencodeMut::new_int(reqq as i64));
+            }
+            if let Some(metadata_size) = self.metadata_size {
+                dd.insert(b"metadata_size", BencodeMut::new_int(metadata_size as i64));
+            }
+        }
+        d.encode()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let b = BencodeRef::decode(payload, BDecodeOpt::default())?;
+        let d = b.dict().ok_or("extension handshake not a dict")?;
+        let mut m = HashMap::new();
+        if let Some(mb) = d.lookup(b"m") {
+            let md = mb.dict().ok_or("extension handshake 'm' not a dict")?;
+            for (name, id) in md.to_list() {
+                let name = String::from_utf8_lossy(name).into_owned();
+                match id.int() {
+                    Some(id) if id >= 0 && id < 256 => {
+                        m.insert(name, id as u8);
+                    }
+                    _ => bail!("extension handshake bad id for '{}'", name),
+                }
+            }
+        }
+        let port = match d.lookup(b"p").and_then(|x| x.int()) {
+            Some(p) if p > 0 && p < 1 << 16 => Some(p as u16),
+            _ => None,
+        };
+        let client = d.lookup(b"v").and_then(|x| x.bytes()).map(|x| String::from_utf8_lossy(x).into_owned());
+        let reqq = match d.lookup(b"reqq").and_then(|x| x.int()) {
+            Some(x) if x > 0 => Some(x as u64),
+            _ => None,
+        };
+        let metadata_size = match d.lookup(b"metadata_size").and_then(|x| x.int()) {
+            Some(x) if x > 0 => Some(x as u64),
+            _ => None,
+        };
+        Ok(ExtensionHandshake {
+               m: m,
+               port: port,
+               client: client,
+               reqq: reqq,
+               metadata_size: metadata_size,
+           })
+    }
+
+    /// Apply a later handshake from the same peer.
+    /// Extensions not mentioned are left as they were.
+    pub fn update(&mut self, other: ExtensionHandshake) {
+        for (name, id) in other.m {
+            if id == 0 {
+                self.m.remove(&name);
+            } else {
+                self.m.insert(name, id);
+            }
+        }
+        self.port = other.port.or(self.port);
+        self.client = other.client.or(self.client.take());
+        self.reqq = other.reqq.or(self.reqq);
+        self.metadata_size = other.metadata_size.or(self.metadata_size);
+    }
+
+    /// Get the id the peer wants to receive an extension as, if it supports it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).cloned().and_then(|id| if id == 0 { None } else { Some(id) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use extension::*;
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let mut hs = ExtensionHandshake::local(6881, 250, Some(12345));
+        hs.m.insert("ut_example".to_owned(), 3);
+        let encoded = hs.encode();
+        assert_eq!(ExtensionHandshake::decode(&encoded).unwrap(), hs);
+    }
+
+    #[test]
+    fn test_handshake_update() {
+        let mut hs = ExtensionHandshake::default();
+        hs.m.insert("a".to_owned(), 1);
+        hs.m.insert("b".to_owned(), 2);
+        let mut later = ExtensionHandshake::default();
+        later.m.insert("a".to_owned(), 0);
+        later.m.insert("c".to_owned(), 3);
+        hs.update(later);
+        assert_eq!(hs.id("a"), None);
+        assert_eq!(hs.id("b"), Some(2));
+        assert_eq!(hs.id("c"), Some(3));
+    }
+}
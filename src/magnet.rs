@@ -0,0 +1,121 @@
+use errors::*;
+use metainfo::{INFO_HASH_SIZE, InfoHash};
+use url::Url;
+
+/// A magnet link identifying a torrent by its info hash.
+/// Only BitTorrent info hash (btih) magnet links are supported.
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// Display name (dn)
+    pub name: Option<String>,
+    /// Tracker urls (tr)
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            bail!("not a magnet link: {}", uri);
+        }
+
+        const BTIH_PREFIX: &'static str = "urn:btih:";
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "xt" if v.starts_with(BTIH_PREFIX) => {
+                    info_hash = Some(parse_btih(&v[BTIH_PREFIX.len()..])?);
+                }
+                "dn" => name = Some(v.into_owned()),
+                "tr" => trackers.push(v.into_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+               info_hash: info_hash.ok_or("magnet link missing 'xt=urn:btih:'")?,
+               name: name,
+               trackers: trackers,
+           })
+    }
+}
+
+/// Parse an info hash in either hex or base32 form.
+pub fn parse_btih(s: &str) -> Result<InfoHash> {
+    let mut hash = [0; INFO_HASH_SIZE];
+    match s.len() {
+        40 => {
+            for i in 0..INFO_HASH_SIZE {
+                hash[i] = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                    .chain_err(|| format!("invalid hex info hash: {}", s))?;
+            }
+        }
+        32 => {
+            // Each character is 5 bits, most significant first.
+            let mut acc: u64 = 0;
+            let mut nbits = 0;
+            let mut i = 0;
+            for c in s.bytes() {
+                let v = match c {
+                    b'A'..=b'Z' => c - b'A',
+                    b'a'..=b'z' => c - b'a',
+                    b'2'..=b'7' => c - b'2' + 26,
+                    _ => bail!("invalid base32 info hash: {}", s),
+                };
+                acc = (acc << 5) | v as u64;
+                nbits += 5;
+                if nbits >= 8 {
+                    nbits -= 8;
+                    hash[i] = (acc >> nbits) as u8;
+                    i += 1;
+                }
+            }
+        }
+        n => bail!("info hash has wrong length {}", n),
+    }
+    Ok(InfoHash { hash: hash })
+}
+
+#[cfg(test)]
+mod tests {
+    use magnet::*;
+
+    #[test]
+    fn test_parse_hex() {
+        let m = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some+Name&tr=http%3A%2F%2Ftracker.example%2Fannounce&tr=udp%3A%2F%2Fother%3A80")
+            .unwrap();
+        assert_eq!(m.info_hash.hash[0], 0xc1);
+        assert_eq!(m.info_hash.hash[19], 0x8a);
+        assert_eq!(m.name, Some("Some Name".to_owned()));
+        assert_eq!(m.trackers,
+                   vec!["http://tracker.example/announce".to_owned(), "udp://other:80".to_owned()]);
+    }
+
+    #[test]
+    fn test_parse_base32() {
+        let hex = MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+        let b32 = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex.info_hash, b32.info_hash);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let mut hash = [0; INFO_HASH_SIZE];
+        hash[0] = 0x01;
+        hash[1] = 0x23;
+        let info_hash = InfoHash { hash: hash };
+        let hex = format!("{}", info_hash);
+        assert_eq!(hex.len(), 40);
+        assert!(hex.starts_with("012300"));
+        assert_eq!(parse_btih(&hex).unwrap(), info_hash);
+    }
+
+    #[test]
+    fn test_parse_missing_hash() {
+        assert!(MagnetLink::parse("magnet:?dn=nothing").is_err());
+        assert!(MagnetLink::parse("http://example.com/?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a").is_err());
+    }
+}
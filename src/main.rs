@@ -26,37 +26,97 @@ extern crate tokio_core;
 extern crate tokio_io;
 extern crate bytes;
 
+mod candidates;
+mod choker;
 mod datastore;
 mod downloader;
 mod errors;
+mod extension;
 mod fillable;
 mod logging;
+mod magnet;
 mod manifest;
+mod metadata;
 mod metainfo;
+mod mse;
 mod peer_protocol;
+mod pex;
+mod picker;
+mod provenance;
+mod rate;
+mod ratelimit;
+mod session;
 mod tracker;
+mod transport;
 #[macro_use]
 mod util;
+mod utp;
 
-use bip_bencode::{BDecodeOpt, BencodeRef};
+use choker::ChokerConfig;
 use docopt::Docopt;
+use downloader::TorrentConfig;
 use errors::*;
+use magnet::MagnetLink;
 use manifest::*;
 use metainfo::*;
+use mse::EncryptionPolicy;
 use peer_protocol::PeerID;
+use picker::PickerStrategy;
+use ratelimit::{RateLimits, parse_rate};
 use ring::rand::SystemRandom;
+use session::Session;
 use slog::Logger;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+use std::time::Duration;
 
 const USAGE: &'static str = "
-Usage: bittles <torrent>
+Usage: bittles [options] [<torrent>...]
+
+Each <torrent> is the path to a .torrent file or a magnet link.
+Torrents are downloaded to tmp/<info hash>/data under the current directory.
+Once complete they are seeded until the seed ratio or seed time is reached.
+The session keeps running until `quit` is written to standard input,
+or once standard input is closed and every torrent is done seeding.
+
+Options:
+    --port <port>  Port to listen on for peer connections [default: 6881].
+    --encryption <policy>  Peer connection encryption: disabled, enabled or required [default: enabled].
+    --no-utp  Only use TCP for peer connections.
+    --picker <strategy>  Piece selection: sequential, rarest-first or random-first, unless chosen when adding [default: rarest-first].
+    --unchoke-slots <n>  Number of peers to upload to for their rate [default: 4].
+    --optimistic-slots <n>  Number of peers to upload to at random [default: 1].
+    --download-limit <rate>  Most bytes per second to download, like 500k [default: unlimited].
+    --upload-limit <rate>  Most bytes per second to upload [default: unlimited].
+    --peer-download-limit <rate>  Most bytes per second to download from each peer [default: unlimited].
+    --peer-upload-limit <rate>  Most bytes per second to upload to each peer [default: unlimited].
+    --max-bad-blocks <n>  Ban peers which send more corrupt blocks than this [default: 2].
+    --seed-ratio <ratio>  Stop seeding once this many times the torrent's size is uploaded, 0 for no limit [default: 1.0].
+    --seed-time <minutes>  Stop seeding after this many minutes, 0 for no limit [default: 0].
+    --seed  Seed data which is already complete instead of downloading it.
+
+Rate limits can be changed while running by writing lines like
+`upload 100k` or `peer-download unlimited` to standard input.
+Torrents can be added with `add <file.torrent>` or `add <magnet link>`
+and removed with `remove <info hash>` the same way.
+Each added torrent can choose its own picker like `add --picker sequential <file.torrent>`.
 ";
 
 #[derive(RustcDecodable)]
 struct Args {
-    arg_torrent: String,
+    arg_torrent: Vec<String>,
+    flag_port: u16,
+    flag_encryption: String,
+    flag_no_utp: bool,
+    flag_picker: String,
+    flag_unchoke_slots: usize,
+    flag_optimistic_slots: usize,
+    flag_download_limit: String,
+    flag_upload_limit: String,
+    flag_peer_download_limit: String,
+    flag_peer_upload_limit: String,
+    flag_max_bad_blocks: u32,
+    flag_seed_ratio: f64,
+    flag_seed_time: u64,
+    flag_seed: bool,
 }
 
 fn main() {
@@ -94,48 +154,80 @@ fn inner(log: Logger) -> Result<()> {
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
-    let cwd = std::env::current_dir().chain_err(|| "get cwd")?;
-    info!(log, "cwd: {}", cwd.display());
-    info!(log, "torrent: {}", args.arg_torrent);
-
-    let torrent_path = Path::new(&args.arg_torrent);
-    let mut buf = vec![0;0];
-    let mut f = File::open(torrent_path).chain_err(|| "open torrent file")?;
-    f.read_to_end(&mut buf).unwrap();
-
-    let res = BencodeRef::decode(buf.as_slice(), BDecodeOpt::default())
-        .chain_err(|| "decode torrent")?;
+    let encryption: EncryptionPolicy = args.flag_encryption.parse()?;
+    let picker: PickerStrategy = args.flag_picker.parse()?;
+    let choker = ChokerConfig {
+        unchoke_slots: args.flag_unchoke_slots,
+        optimistic_slots: args.flag_optimistic_slots,
+    };
+    let limits = RateLimits {
+        download: parse_rate(&args.flag_download_limit)?,
+        upload: parse_rate(&args.flag_upload_limit)?,
+        peer_download: parse_rate(&args.flag_peer_download_limit)?,
+        peer_upload: parse_rate(&args.flag_peer_upload_limit)?,
+    };
 
-    let info = match MetaInfo::new(res) {
-        Ok(x) => x,
-        Err(err) => bail!("invalid torrent file: {}", err),
+    let config = TorrentConfig {
+        picker: picker,
+        choker: choker,
+        max_bad_blocks: args.flag_max_bad_blocks,
+        seed_ratio: match args.flag_seed_ratio {
+            x if x > 0.0 => Some(x),
+            _ => None,
+        },
+        seed_time: match args.flag_seed_time {
+            0 => None,
+            x => Some(Duration::from_secs(x * 60)),
+        },
+        seed: args.flag_seed,
     };
-    info!(log, "{}", info);
 
-    let manifest = Manifest::new(info.clone());
-    info!(log, "{}", manifest);
+    let cwd = std::env::current_dir().chain_err(|| "get cwd")?;
+    info!(log, "cwd: {}", cwd.display());
 
     let rand = SystemRandom::new();
 
     let peer_id = PeerID::new(&rand)?;
     info!(log, "peer_id: {:?}", peer_id);
 
-    let datastore_path = {
-        let mut x = cwd.clone();
-        x.push("tmp");
-        x.push("data");
-        x
-    };
-    info!(log, "datastore path: {:?}", datastore_path);
+    let mut infos = Vec::new();
+    let mut magnets = Vec::new();
+    for torrent in args.arg_torrent.iter() {
+        info!(log, "torrent: {}", torrent);
+        if torrent.starts_with("magnet:") {
+            let magnet = MagnetLink::parse(torrent).chain_err(|| "parse magnet link")?;
+            info!(log, "magnet: {:?}", magnet);
+            magnets.push(magnet);
+            continue;
+        }
+        let info = MetaInfo::from_file(torrent)?;
+        info!(log, "{}", info);
 
-    let manifest_path = {
+        let manifest = Manifest::new(info.clone());
+        info!(log, "{}", manifest);
+        infos.push(info);
+    }
+
+    let dir = {
         let mut x = cwd.clone();
         x.push("tmp");
-        x.push("manifest");
         x
     };
-    info!(log, "manifest path: {:?}", manifest_path);
 
-    downloader::start(log, info, peer_id, datastore_path, manifest_path)?;
-    Ok(())
+    let session = Session::new(log,
+                               peer_id,
+                               args.flag_port,
+                               encryption,
+                               !args.flag_no_utp,
+                               limits,
+                               dir,
+                               config)?;
+    for info in infos {
+        session.handle().add(info, config)?;
+    }
+    // Metadata is fetched while the session runs.
+    for magnet in magnets {
+        session.handle().add_magnet(magnet, config)?;
+    }
+    session.run()
 }
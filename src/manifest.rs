@@ -11,6 +11,9 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use util::write_atomic;
 
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_SIZE: u64 = 1 << 14;
+
 /// Manifest describes the state of what parts of a torrent have been downloaded and verified.
 /// A manifest is associated with a single torrent.
 /// It is safe for it to be behind the state of the disk, but unsafe for it to be ahead.
@@ -119,6 +122,14 @@ impl Manifest {
         Ok(())
     }
 
+    /// Record that all data is present, as when seeding existing files.
+    /// The pieces still need to be verified.
+    pub fn fill_all(&mut self) {
+        for p in self.present.iter_mut() {
+            p.fill();
+        }
+    }
+
     /// Record that a piece was verified.
     pub fn mark_verified(&mut self, verified: Verified) -> Result<()> {
         self.size_info.check_piece(verified.piece)?;
@@ -126,11 +137,29 @@ impl Manifest {
         Ok(())
     }
 
+    /// One bool per piece. True means the piece has been verified.
+    pub fn verified_bits(&self) -> Vec<bool> {
+        self.verified.clone()
+    }
+
+    /// Whether a piece has been verified.
+    pub fn is_verified(&self, piece: u64) -> Result<bool> {
+        self.size_info.check_piece(piece)?;
+        Ok(self.verified[piece as usize])
+    }
+
     pub fn is_full(&self, piece: u64) -> Result<bool> {
         self.size_info.check_piece(piece)?;
         Ok(self.present[piece as usize].is_full())
     }
 
+    /// Whether some but not all of a piece has been added.
+    pub fn is_partial(&self, piece: u64) -> Result<bool> {
+        self.size_info.check_piece(piece)?;
+        let p = &self.present[piece as usize];
+        Ok(!p.is_empty() && !p.is_full())
+    }
+
     /// Whether all data has been added.
     /// NOT whether it's been verified.
     pub fn is_all_full(&self) -> bool {
@@ -152,71 +181,54 @@ impl Manifest {
         return self.needs_verify().len() == 0;
     }
 
-    /// Get the next desired block.
-    /// This is the first block that has not been added.
-    pub fn next_desired_block(&self, log: &Logger, after: Option<BlockRequest>) -> Option<BlockRequest> {
-        let after: BlockRequest = after.unwrap_or_else(|| {
-                                                           BlockRequest {
-                                                               piece: 0,
-                                                               offset: 0,
-                                                               length: 0,
-                                                           }
-                                                       });
-
-        // First piece that might be the one
-        let start_piece = self.size_info
-            .piece_at_point(after.piece, after.offset + after.length) as usize;
-
-        for i in start_piece..self.size_info.num_pieces() as usize {
-            let p = &self.present[i];
-            if p.is_full() {
-                continue;
-            }
-
-            // First byte in the piece to consider
-            let start_in_piece = match after.piece == i as u64 {
-                true => after.offset + after.length,
-                false => 0,
-            };
+    /// The first block of a piece at or after `offset` that has not been added.
+    /// Choosing which pieces to download is up to the piece picker.
+    pub fn missing_block(&self, piece: u64, offset: u64) -> Result<Option<BlockRequest>> {
+        self.size_info.check_piece(piece)?;
+        let p = &self.present[piece as usize];
+        if offset >= p.size() {
+            return Ok(None);
+        }
+        Ok(p.first_unfilled_starting_at(offset)?
+               .filter(|&x| x < p.size())
+               .map(|x| {
+                        BlockRequest {
+                            piece: piece,
+                            offset: x,
+                            length: cmp::min(p.size() - x, BLOCK_SIZE),
+                        }
+                    }))
+    }
 
-            if start_in_piece >= p.size() {
-                continue;
-            }
+    /// Whether all of a block has been added.
+    pub fn has_block(&self, block: BlockRequest) -> Result<bool> {
+        self.size_info
+            .check_range(block.piece, block.offset, block.length)?;
+        if block.offset + block.length > self.size_info.piece_size(block.piece) {
+            return Ok(false);
+        }
+        Ok(self.missing_block(block.piece, block.offset)?
+               .map(|missing| missing.offset >= block.offset + block.length)
+               .unwrap_or(true))
+    }
 
-            let x: Option<u64> = match self.present[i].first_unfilled_starting_at(start_in_piece) {
-                Ok(x) => x,
-                Err(err) => {
-                    warn!(log,
-                          "manifest fault after:{:?} piece:{} sip:{} err:{}",
-                          after,
-                          i,
-                          start_in_piece,
-                          err);
-                    None
-                }
-            };
+    /// Bytes in pieces which have not been verified.
+    pub fn bytes_left(&self) -> u64 {
+        self.verified
+            .iter()
+            .enumerate()
+            .filter(|&(_, v)| !v)
+            .map(|(i, _)| self.size_info.piece_size(i as u64))
+            .sum()
+    }
 
-            if let Some(x) = x {
-                if x < self.present[i].size() {
-                    let left = self.present[i].size() - x;
-                    let max_length = 1 << 14;
-                    return Some(BlockRequest {
-                                    piece: i as u64,
-                                    offset: x as u64,
-                                    length: cmp::min(left as u64, max_length),
-                                });
-                } else {
-                    warn!(log,
-                          "manifest fault after:{:?} piece:{} sip:{} x:{}",
-                          after,
-                          i,
-                          start_in_piece,
-                          x);
-                }
-            }
-        }
+    pub fn num_pieces(&self) -> u64 {
+        self.size_info.num_pieces()
+    }
 
-        None
+    /// Number of pieces verified.
+    pub fn num_verified(&self) -> u64 {
+        self.verified.iter().filter(|v| **v).count() as u64
     }
 
     // Amount of data verified in [0,1].
@@ -0,0 +1,476 @@
+use bip_bencode::{BDecodeOpt, BMutAccess, BRefAccess, BencodeMut, BencodeRef};
+use errors::*;
+use extension;
+use extension::ExtensionHandshake;
+use futures::{Sink, Stream};
+use futures::future;
+use futures::stream;
+use futures::sync::oneshot;
+use futures::future::{Future, Loop};
+use magnet::MagnetLink;
+use metainfo::{InfoHash, MetaInfo, make_info_hash};
+use peer_protocol;
+use peer_protocol::{BitTorrentPeerCodec, DEFAULT_MAX_MESSAGE_LENGTH, Message, PeerID};
+use slog::Logger;
+use std::cmp;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::str;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor;
+use tokio_io::AsyncRead;
+use tokio_io::codec::Framed;
+use tracker;
+use tracker::{TrackerClient, TrackerEvent, TransferStats};
+use util::{BxFuture, FutureEnhanced, VecDequeStream, tcp_connect2, with_timeout};
+
+/// Name of the metadata exchange extension (BEP 9).
+pub const EXTENSION_NAME: &'static str = "ut_metadata";
+
+/// Metadata is transferred in pieces of this size.
+/// Only the last piece may be smaller.
+pub const METADATA_PIECE_SIZE: u64 = 1 << 14;
+
+/// Refuse to fetch metadata larger than this.
+const MAX_METADATA_SIZE: u64 = 1 << 24;
+
+/// Maximum number of peers to try per tracker.
+const MAX_FETCH_PEERS: usize = 15;
+
+/// Number of peers to fetch the metadata from at once.
+const CONCURRENT_FETCHES: usize = 5;
+
+/// Time allowed to fetch the metadata from a single peer.
+const FETCH_TIMEOUT_SECS: u64 = 30;
+
+/// Bytes left reported to trackers before the size of the torrent is known.
+/// Reporting zero would make trackers take us for a seed and send few seeds back.
+const UNKNOWN_LEFT: u64 = 1 << 14;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request { piece: u64 },
+    Data {
+        piece: u64,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject { piece: u64 },
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        use self::MetadataMessage::*;
+        let (msg_type, piece) = match *self {
+            Request { piece } => (0, piece),
+            Data { piece, .. } => (1, piece),
+            Reject { piece } => (2, piece),
+        };
+        let mut d = BencodeMut::new_dict();
+        {
+            let dd = d.dict_mut().unwrap();
+            dd.insert(b"msg_type", BencodeMut::new_int(msg_type));
+            dd.insert(b"piece", BencodeMut::new_int(piece as i64));
+            if let Data { total_size, .. } = *self {
+                dd.insert(b"total_size", BencodeMut::new_int(total_size as i64));
+            }
+        }
+        let mut buf = d.encode();
+        // The data of a Data message follows the dictionary.
+        if let Data { ref data, .. } = *self {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        // Find where the dictionary ends and any trailing data begins.
+        let dict_len = bencode_len(payload)?;
+        let (dict_bytes, rest) = payload.split_at(dict_len);
+        let b = BencodeRef::decode(dict_bytes, BDecodeOpt::default())?;
+        let d = b.dict().ok_or("metadata message not a dict")?;
+        let msg_type = d.lookup(b"msg_type")
+            .and_then(|x| x.int())
+            .ok_or("metadata message missing 'msg_type'")?;
+        let piece = match d.lookup(b"piece").and_then(|x| x.int()) {
+            Some(x) if x >= 0 => x as u64,
+            _ => bail!("metadata message missing 'piece'"),
+        };
+        match msg_type {
+            0 => Ok(MetadataMessage::Request { piece: piece }),
+            1 => {
+                let total_size = match d.lookup(b"total_size").and_then(|x| x.int()) {
+                    Some(x) if x > 0 => x as u64,
+                    _ => bail!("metadata data message missing 'total_size'"),
+                };
+                Ok(MetadataMessage::Data {
+                       piece: piece,
+                       total_size: total_size,
+                       data: rest.to_vec(),
+                   })
+            }
+            2 => Ok(MetadataMessage::Reject { piece: piece }),
+            x => bail!("unknown metadata message type {}", x),
+        }
+    }
+}
+
+/// Length of the bencoded value at the start of `bytes`.
+fn bencode_len(bytes: &[u8]) -> Result<usize> {
+    let mut pos = 0;
+    let mut depth = 0;
+    loop {
+        match bytes.get(pos).cloned() {
+            None => bail!("truncated bencode"),
+            Some(b'i') => {
+                let end = bytes[pos..]
+                    .iter()
+                    .position(|&b| b == b'e')
+                    .ok_or("truncated bencode integer")?;
+                pos += end + 1;
+            }
+            Some(b'l') | Some(b'd') => {
+                depth += 1;
+                pos += 1;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            Some(b'0'..=b'9') => {
+                let colon = bytes[pos..]
+                    .iter()
+                    .position(|&b| b == b':')
+                    .ok_or("truncated bencode string")?;
+                let len: usize = str::from_utf8(&bytes[pos..pos + colon])
+                    .ok()
+                    .and_then(|x| x.parse().ok())
+                    .ok_or("bad bencode string length")?;
+                pos = (pos + colon + 1)
+                    .checked_add(len)
+                    .ok_or("bad bencode string length")?;
+                if pos > bytes.len() {
+                    bail!("truncated bencode string");
+                }
+            }
+            Some(b) => bail!("unexpected byte in bencode: {}", b),
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Number of metadata pieces in metadata of `total_size` bytes.
+pub fn num_pieces(total_size: u64) -> u64 {
+    (total_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE
+}
+
+/// Length of a metadata piece, or None if the piece is out of range.
+pub fn piece_length(total_size: u64, piece: u64) -> Option<u64> {
+    if piece >= num_pieces(total_size) {
+        return None;
+    }
+    let start = piece * METADATA_PIECE_SIZE;
+    Some(cmp::min(METADATA_PIECE_SIZE, total_size - start))
+}
+
+/// Get a piece of the metadata to send to a peer.
+pub fn piece_data(info_bytes: &[u8], piece: u64) -> Option<&[u8]> {
+    piece_length(info_bytes.len() as u64, piece).map(|length| {
+        let start = (piece * METADATA_PIECE_SIZE) as usize;
+        &info_bytes[start..start + length as usize]
+    })
+}
+
+/// Fetch the info dictionary for a magnet link from the swarm.
+/// Peers are found through the magnet link's trackers.
+/// The resulting MetaInfo announces to the first tracker that led to the metadata.
+/// Blocks until done, so run it on its own thread.
+/// The fetch gives up once `cancel` resolves or its sender is dropped.
+pub fn fetch_metainfo(log: &Logger, magnet: &MagnetLink, peer_id: PeerID, port: u16, cancel: oneshot::Receiver<()>) -> Result<MetaInfo> {
+    if magnet.trackers.is_empty() {
+        bail!("magnet link has no trackers");
+    }
+    let log = match magnet.name {
+        Some(ref name) => log.new(o!("name" => name.clone())),
+        None => log.clone(),
+    };
+    let trackers: Vec<&String> = magnet
+        .trackers
+        .iter()
+        .filter(|url| {
+                    let supported = tracker::is_supported(url);
+                    if !supported {
+                        warn!(log, "skipping unsupported tracker: {}", url);
+                    }
+                    supported
+                })
+        .collect();
+    if trackers.is_empty() {
+        bail!("magnet link has no supported trackers");
+    }
+    info!(log, "fetching metadata from {} trackers", trackers.len());
+
+    let mut core = reactor::Core::new()?;
+    let handle = core.handle();
+    let cancel = cancel.shared();
+
+    for tracker_url in trackers {
+        info!(log, "asking tracker for metadata peers: {}", tracker_url);
+        let mut tc = match TrackerClient::new(tracker_url, magnet.info_hash.clone(), peer_id.clone(), port) {
+            Err(err) => {
+                warn!(log, "tracker error: {}", err);
+                continue;
+            }
+            Ok(tc) => tc,
+        };
+        let stats = TransferStats {
+            left: UNKNOWN_LEFT,
+            ..TransferStats::default()
+        };
+        let peers = match tc.announce(TrackerEvent::Started, stats) {
+            Err(err) => {
+                warn!(log, "tracker error: {}", err);
+                continue;
+            }
+            Ok(res) => res.peers,
+        };
+
+        // Fetch from several peers at once and take the first to finish.
+        // Failures are logged and become None.
+        let fetches = peers.iter().take(MAX_FETCH_PEERS).map(|peer| {
+            let log = log.new(o!("addr" => format!("{}", peer.address)));
+            let f = fetch_from_peer(&log, &handle, peer.address, magnet.info_hash.clone(), peer_id.clone());
+            let f = with_timeout(f, Duration::from_secs(FETCH_TIMEOUT_SECS), &handle)
+                .then(move |res| -> Result<Option<Vec<u8>>> {
+                    match res {
+                        Ok(info_bytes) => {
+                            info!(log, "fetched metadata ({} bytes)", info_bytes.len());
+                            Ok(Some(info_bytes))
+                        }
+                        Err(err) => {
+                            warn!(log, "could not fetch metadata: {}", err);
+                            Ok(None)
+                        }
+                    }
+                });
+            Ok(f)
+        });
+        let first = stream::iter(fetches.collect::<Vec<Result<_>>>())
+            .buffer_unordered(CONCURRENT_FETCHES)
+            .filter_map(|x| x)
+            .into_future()
+            .map(|(first, _)| first)
+            .map_err(|(err, _)| err);
+        let cancelled = cancel
+            .clone()
+            .then(|_| -> Result<Option<Vec<u8>>> { Err("metadata fetch cancelled".into()) });
+        // Dropping the stream abandons the other fetches.
+        let res = core.run(first.select(cancelled).map(|(x, _)| x).map_err(|(err, _)| err));
+        // Leave the swarm until the torrent starts and announces itself.
+        if let Err(err) = tc.announce(TrackerEvent::Stopped, stats) {
+            warn!(log, "tracker error: {}", err);
+        }
+        if let Some(info_bytes) = res? {
+            return MetaInfo::from_info_bytes(&info_bytes, tracker_url.clone());
+        }
+    }
+
+    bail!("could not fetch metadata from any peer")
+}
+
+type MetadataFramed = Framed<TcpStream, BitTorrentPeerCodec>;
+
+/// Connect to a peer and download the whole info dictionary from it.
+fn fetch_from_peer(log: &Logger, handle: &reactor::Handle, addr: SocketAddr, info_hash: InfoHash, peer_id: PeerID) -> BxFuture<Vec<u8>, Error> {
+    let log = log.clone();
+    let info_hash2 = info_hash.clone();
+    let info_hash3 = info_hash.clone();
+
+    debug!(log, "connecting for metadata");
+    tcp_connect2(&addr, Duration::from_millis(3000), handle)
+        .chain_err(|| "peer connection failed")
+        .and_then(move |stream| peer_protocol::handshake_send_async(stream, info_hash, peer_id))
+        .and_then(|stream| peer_protocol::handshake_read_1_async(stream))
+        .and_then(move |(stream, remote_info_hash, reserved)| {
+            if remote_info_hash != info_hash2 {
+                bail!("info hash mismatch peer:{:?} me:{:?}", remote_info_hash, info_hash2);
+            }
+            if !reserved.extension_protocol() {
+                bail!("peer does not support the extension protocol");
+            }
+            Ok(stream)
+        })
+        .and_then(|stream| peer_protocol::handshake_read_2_async(stream))
+        .and_then(|(stream, _)| {
+            let stream: MetadataFramed = stream.framed(BitTorrentPeerCodec::new(None, DEFAULT_MAX_MESSAGE_LENGTH));
+            let mut hs = ExtensionHandshake::default();
+            if let Some(id) = extension::local_extension_id(EXTENSION_NAME) {
+                hs.m.insert(EXTENSION_NAME.to_owned(), id);
+            }
+            hs.client = Some(extension::CLIENT_NAME.to_owned());
+            stream.send(Message::Extended {
+                            id: extension::HANDSHAKE_ID,
+                            payload: hs.encode(),
+                        })
+        })
+        .and_then(move |stream| drive_fetch(log, stream, info_hash3))
+        .bxed()
+}
+
+/// Progress of fetching the metadata from one peer.
+struct FetchState {
+    info_hash: InfoHash,
+    /// Id the peer wants to receive ut_metadata messages as.
+    /// None until the peer's extension handshake arrives.
+    remote_id: Option<u8>,
+    total_size: u64,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+enum FetchStep {
+    Send(VecDeque<Message>),
+    Done(Vec<u8>),
+}
+
+impl FetchState {
+    fn handle(&mut self, log: &Logger, msg: Message) -> Result<FetchStep> {
+        let mut outs = VecDeque::new();
+        match msg {
+            Message::Extended { id: extension::HANDSHAKE_ID, payload } => {
+                if self.remote_id.is_some() {
+                    return Ok(FetchStep::Send(outs));
+                }
+                let hs = ExtensionHandshake::decode(&payload)?;
+                debug!(log, "extension handshake: {:?}", hs);
+                let remote_id = hs.id(EXTENSION_NAME)
+                    .ok_or("peer does not support ut_metadata")?;
+                let total_size = hs.metadata_size.ok_or("peer did not send metadata_size")?;
+                if total_size > MAX_METADATA_SIZE {
+                    bail!("metadata too large: {}", total_size);
+                }
+                self.remote_id = Some(remote_id);
+                self.total_size = total_size;
+                self.pieces = vec![None; num_pieces(total_size) as usize];
+                for piece in 0..num_pieces(total_size) {
+                    outs.push_back(Message::Extended {
+                                       id: remote_id,
+                                       payload: MetadataMessage::Request { piece: piece }.encode(),
+                                   });
+                }
+            }
+            Message::Extended { id, payload } => {
+                if extension::local_extension_name(id) != Some(EXTENSION_NAME) {
+                    return Ok(FetchStep::Send(outs));
+                }
+                match MetadataMessage::decode(&payload)? {
+                    MetadataMessage::Data { piece, total_size, data } => {
+                        if self.remote_id.is_none() {
+                            bail!("metadata data before extension handshake");
+                        }
+                        if total_size != self.total_size {
+                            bail!("metadata size changed from {} to {}", self.total_size, total_size);
+                        }
+                        let expected = piece_length(total_size, piece)
+                            .ok_or_else(|| format!("metadata piece out of range: {}", piece))?;
+                        if data.len() as u64 != expected {
+                            bail!("metadata piece {} has wrong length {}", piece, data.len());
+                        }
+                        debug!(log, "got metadata piece {}", piece);
+                        self.pieces[piece as usize] = Some(data);
+                    }
+                    MetadataMessage::Reject { piece } => bail!("peer rejected metadata piece {}", piece),
+                    MetadataMessage::Request { .. } => {}
+                }
+                if self.pieces.iter().all(|x| x.is_some()) {
+                    let mut info_bytes = Vec::with_capacity(self.total_size as usize);
+                    for piece in self.pieces.iter() {
+                        info_bytes.extend_from_slice(piece.as_ref().unwrap());
+                    }
+                    let info_hash = make_info_hash(&info_bytes)?;
+                    if info_hash != self.info_hash {
+                        bail!("metadata hash mismatch");
+                    }
+                    return Ok(FetchStep::Done(info_bytes));
+                }
+            }
+            _ => {}
+        }
+        Ok(FetchStep::Send(outs))
+    }
+}
+
+fn drive_fetch(log: Logger, stream: MetadataFramed, info_hash: InfoHash) -> BxFuture<Vec<u8>, Error> {
+    let fstate = FetchState {
+        info_hash: info_hash,
+        remote_id: None,
+        total_size: 0,
+        pieces: Vec::new(),
+    };
+    future::loop_fn((stream, fstate), move |(stream, mut fstate)| {
+        let log = log.clone();
+        stream
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(msg, stream)| -> BxFuture<Loop<Vec<u8>, (MetadataFramed, FetchState)>, Error> {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => return future::err("peer hung up".into()).bxed(),
+                };
+                match fstate.handle(&log, msg) {
+                    Err(err) => future::err(err).bxed(),
+                    Ok(FetchStep::Done(info_bytes)) => future::ok(Loop::Break(info_bytes)).bxed(),
+                    Ok(FetchStep::Send(outs)) => {
+                        stream
+                            .send_all(VecDequeStream::<Message, Error>::new(outs))
+                            .map(move |(stream, _)| Loop::Continue((stream, fstate)))
+                            .bxed()
+                    }
+                }
+            })
+    })
+            .bxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use metadata::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let msgs = vec![MetadataMessage::Request { piece: 0 },
+                        MetadataMessage::Reject { piece: 7 },
+                        MetadataMessage::Data {
+                            piece: 2,
+                            total_size: 40000,
+                            data: b"d3:fooe trailing bytes".to_vec(),
+                        }];
+        for msg in msgs {
+            assert_eq!(MetadataMessage::decode(&msg.encode()).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_decode_trailing_bencode() {
+        // Data which is itself valid bencode must not be taken as part of the dictionary.
+        let msg = MetadataMessage::Data {
+            piece: 0,
+            total_size: 3,
+            data: b"i5e".to_vec(),
+        };
+        assert_eq!(MetadataMessage::decode(&msg.encode()).unwrap(), msg);
+        assert!(MetadataMessage::decode(b"d8:msg_typei0e5:piecei0e").is_err());
+        assert!(MetadataMessage::decode(b"d8:msg_typei0e5:piece99:x").is_err());
+    }
+
+    #[test]
+    fn test_piece_data() {
+        let info_bytes = vec![1; (METADATA_PIECE_SIZE * 2 + 10) as usize];
+        assert_eq!(num_pieces(info_bytes.len() as u64), 3);
+        assert_eq!(piece_data(&info_bytes, 0).unwrap().len(), METADATA_PIECE_SIZE as usize);
+        assert_eq!(piece_data(&info_bytes, 2).unwrap().len(), 10);
+        assert_eq!(piece_data(&info_bytes, 3), None);
+    }
+}
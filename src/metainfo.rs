@@ -1,21 +1,34 @@
-use bip_bencode::{BDictAccess, BRefAccess, BencodeRef};
+use bip_bencode::{BDecodeOpt, BDictAccess, BRefAccess, BencodeRef};
 use errors::*;
 use itertools::Itertools;
 use ring::digest;
 use std;
 use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
 use std::str;
 use util::map_try;
 
 pub const INFO_HASH_SIZE: usize = 20;
-#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct InfoHash {
     pub hash: [u8; PIECE_HASH_SIZE],
 }
 
 impl fmt::Debug for InfoHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
-        write!(f, "{:x}", self.hash.iter().format(""))
+        write!(f, "{}", self)
+    }
+}
+
+/// Formats as 40 hex digits, the form used in magnet links.
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        for b in self.hash.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
     }
 }
 
@@ -44,6 +57,8 @@ pub fn make_info_hash(src: &[u8]) -> Result<InfoHash> {
 pub struct MetaInfo {
     pub announce: String,
     pub info_hash: InfoHash,
+    /// The bencoded info dictionary.
+    pub info_bytes: Vec<u8>,
     pub piece_hashes: Vec<PieceHash>,
     pub file_info: FileInfo,
     pub size_info: SizeInfo,
@@ -98,15 +113,45 @@ impl fmt::Display for MetaInfo {
 impl MetaInfo {
     pub fn new(src: BencodeRef) -> Result<Self> {
         let d = src.dict().ok_or_err("MetaInfo src not dict")?;
-        let info = d.lookup("info".as_bytes())
-            .ok_or_err("missing 'info'")?
-            .dict()
-            .ok_or_err("'info' not a dict")?;
 
         // for (k,_) in d.to_list() {
         //     println!("key: {}", str::from_utf8(k)?);
         // }
 
+        let announce = d.lookup("announce".as_bytes())
+            .ok_or_err("missing 'announce'")?
+            .str()
+            .ok_or_err("'announce' not a string")?
+            .to_string();
+        let info = d.lookup("info".as_bytes()).ok_or_err("missing 'info'")?;
+        Self::from_info(info, announce)
+    }
+
+    /// Load a .torrent file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut buf = Vec::new();
+        let mut f = File::open(path).chain_err(|| "open torrent file")?;
+        f.read_to_end(&mut buf).chain_err(|| "read torrent file")?;
+
+        let res = BencodeRef::decode(buf.as_slice(), BDecodeOpt::default())
+            .chain_err(|| "decode torrent")?;
+
+        match MetaInfo::new(res) {
+            Ok(x) => Ok(x),
+            Err(err) => bail!("invalid torrent file: {}", err),
+        }
+    }
+
+    /// Create from just the bencoded info dictionary.
+    /// For when the info dictionary came from somewhere other than a torrent file.
+    pub fn from_info_bytes(info_bytes: &[u8], announce: String) -> Result<Self> {
+        let info = BencodeRef::decode(info_bytes, BDecodeOpt::default())?;
+        Self::from_info(&info, announce)
+    }
+
+    fn from_info(info_ben: &BencodeRef, announce: String) -> Result<Self> {
+        let info = info_ben.dict().ok_or_err("'info' not a dict")?;
+
         // length in bytes of each piece
         let piece_length = info.lookup("piece length".as_bytes())
             .ok_or_err("missing 'piece length'")?
@@ -140,14 +185,9 @@ impl MetaInfo {
         let file_info = Self::load_file_info(info)?;
 
         let res = MetaInfo {
-            announce: d.lookup("announce".as_bytes())
-                .ok_or_err("missing 'announce'")?
-                .str()
-                .ok_or_err("'announce' not a string")?
-                .to_string(),
-            info_hash: make_info_hash(d.lookup("info".as_bytes())
-                                          .ok_or_err("missing 'info'")?
-                                          .buffer())?,
+            announce: announce,
+            info_hash: make_info_hash(info_ben.buffer())?,
+            info_bytes: info_ben.buffer().to_vec(),
             piece_hashes: piece_hashes,
             file_info: file_info.clone(),
             size_info: SizeInfo {
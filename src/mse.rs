@@ -0,0 +1,723 @@
+// Message Stream Encryption, also known as Protocol Encryption.
+// Obfuscates peer connections with a Diffie-Hellman key exchange followed by RC4.
+// http://wiki.vuze.com/w/Message_Stream_Encryption
+
+use byteorder::{BigEndian, ByteOrder};
+use errors::*;
+use futures::{Async, Poll};
+use futures::future;
+use futures::future::{Future, Loop};
+use metainfo::{INFO_HASH_SIZE, InfoHash};
+use peer_protocol::HANDSHAKE_PROTOCOL;
+use ring::digest;
+use ring::rand::SystemRandom;
+use std::cmp;
+use std::io;
+use std::str::FromStr;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, write_all};
+use util::{BxFuture, FutureEnhanced};
+
+/// Whether to encrypt peer connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Only plaintext connections.
+    Disabled,
+    /// Try encryption first and fall back to plaintext.
+    Enabled,
+    /// Only encrypted connections.
+    Required,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "required" => Ok(EncryptionPolicy::Required),
+            _ => bail!("unknown encryption policy: {}", s),
+        }
+    }
+}
+
+// Diffie-Hellman parameters. The generator is 2.
+const PRIME_HEX: &'static str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+/// Length of a public key in bytes.
+const KEY_LEN: usize = 96;
+const NUM_LIMBS: usize = KEY_LEN / 4;
+/// Length of a private key in bytes.
+const PRIVATE_KEY_LEN: usize = 20;
+
+/// Verification constant.
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+/// Most padding either side may send.
+const MAX_PAD_LEN: usize = 512;
+/// Bytes of RC4 keystream thrown away before use.
+const RC4_DISCARD: usize = 1024;
+
+/// A stream which may be encrypted.
+/// Read data is decrypted and written data is encrypted.
+pub struct EncryptedStream<S> {
+    inner: S,
+    /// None when the connection is plaintext.
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Decrypted data which was read during the handshake and not consumed yet.
+    prefix: Vec<u8>,
+    /// Encrypted data which has not been written to `inner` yet.
+    pending: Vec<u8>,
+}
+
+impl<S> EncryptedStream<S> {
+    /// Wrap a stream without encryption.
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, None, None, Vec::new())
+    }
+
+    fn new(inner: S, read_cipher: Option<Rc4>, write_cipher: Option<Rc4>, prefix: Vec<u8>) -> Self {
+        EncryptedStream {
+            inner: inner,
+            read_cipher: read_cipher,
+            write_cipher: write_cipher,
+            prefix: prefix,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: io::Write> EncryptedStream<S> {
+    /// Write out as much pending data as possible.
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let n = self.inner.write(&self.pending)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write encrypted data"));
+            }
+            self.pending.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<S: io::Read> io::Read for EncryptedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.prefix.is_empty() {
+            let n = cmp::min(buf.len(), self.prefix.len());
+            buf[..n].copy_from_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Ok(n);
+        }
+        let n = self.inner.read(buf)?;
+        if let Some(ref mut cipher) = self.read_cipher {
+            cipher.apply(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: io::Write> io::Write for EncryptedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        let data = match self.write_cipher {
+            None => return self.inner.write(buf),
+            Some(ref mut cipher) => {
+                let mut data = buf.to_vec();
+                cipher.apply(&mut data);
+                data
+            }
+        };
+        // The cipher has advanced past this data,
+        // so it must be kept until it is written.
+        self.pending = data;
+        match self.write_pending() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+            Ok(()) => {}
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for EncryptedStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for EncryptedStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.write_pending() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(err) => return Err(err),
+            Ok(()) => {}
+        }
+        self.inner.shutdown()
+    }
+}
+
+/// Start an encrypted connection as the initiating side.
+pub fn connect<S>(stream: S, info_hash: InfoHash, policy: EncryptionPolicy) -> BxFuture<EncryptedStream<S>, Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let crypto_provide = match policy {
+        EncryptionPolicy::Disabled => return future::err("encryption is disabled".into()).bxed(),
+        EncryptionPolicy::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        EncryptionPolicy::Required => CRYPTO_RC4,
+    };
+    let rand = SystemRandom::new();
+    let (private_key, public_key) = match generate_keys(&rand) {
+        Ok(x) => x,
+        Err(err) => return future::err(err).bxed(),
+    };
+    let pad = match random_pad(&rand) {
+        Ok(x) => x,
+        Err(err) => return future::err(err).bxed(),
+    };
+
+    // Send our public key and read theirs.
+    let mut out = public_key.to_vec();
+    out.extend_from_slice(&pad);
+    write_all(stream, out)
+        .and_then(|(stream, _)| read_exact(stream, [0; KEY_LEN]))
+        .map_err(|e| e.into())
+        .and_then(move |(stream, remote_key)| -> Result<_> {
+            let secret = shared_secret(&private_key, &remote_key)?;
+            let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash.hash]));
+            let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash.hash]));
+
+            let mut out = hash(&[b"req1", &secret]).to_vec();
+            out.extend(xor(&hash(&[b"req2", &info_hash.hash]), &hash(&[b"req3", &secret])));
+            let mut body = VC.to_vec();
+            body.extend_from_slice(&u32_bytes(crypto_provide));
+            // No padding and no initial payload.
+            body.extend_from_slice(&[0, 0, 0, 0]);
+            encrypt.apply(&mut body);
+            out.extend(body);
+
+            // The reply starts after the padding with the encrypted verification constant.
+            let mut vc = VC.to_vec();
+            decrypt.apply(&mut vc);
+            Ok((stream, out, encrypt, decrypt, vc))
+        })
+        .and_then(|(stream, out, encrypt, decrypt, vc)| {
+                      write_all(stream, out)
+                          .map_err(|e| e.into())
+                          .and_then(move |(stream, _)| read_until(stream, vc, MAX_PAD_LEN))
+                          .map(move |stream| (stream, encrypt, decrypt))
+                  })
+        .and_then(move |(stream, encrypt, mut decrypt)| {
+            read_exact(stream, [0; 6])
+                .map_err(|e| e.into())
+                .and_then(move |(stream, mut buf)| -> Result<_> {
+                              decrypt.apply(&mut buf);
+                              let crypto_select = BigEndian::read_u32(&buf[..4]);
+                              if crypto_select != CRYPTO_RC4 && crypto_select != CRYPTO_PLAINTEXT ||
+                                 crypto_select & crypto_provide == 0 {
+                                  bail!("peer selected unsupported encryption {}", crypto_select);
+                              }
+                              let pad_len = BigEndian::read_u16(&buf[4..]) as usize;
+                              if pad_len > MAX_PAD_LEN {
+                                  bail!("encryption padding too long: {}", pad_len);
+                              }
+                              Ok((stream, encrypt, decrypt, crypto_select, pad_len))
+                          })
+        })
+        .and_then(|(stream, encrypt, mut decrypt, crypto_select, pad_len)| {
+            read_exact(stream, vec![0; pad_len])
+                .map_err(|e| e.into())
+                .map(move |(stream, mut pad)| {
+                         decrypt.apply(&mut pad);
+                         if crypto_select == CRYPTO_RC4 {
+                             EncryptedStream::new(stream, Some(decrypt), Some(encrypt), Vec::new())
+                         } else {
+                             EncryptedStream::plaintext(stream)
+                         }
+                     })
+        })
+        .bxed()
+}
+
+/// Accept a connection which may or may not be encrypted.
+/// Plaintext connections are recognized by the start of the BitTorrent handshake.
+/// Encrypted connections must be for one of `info_hashes`.
+pub fn accept<S>(stream: S, info_hashes: Vec<InfoHash>, policy: EncryptionPolicy) -> BxFuture<EncryptedStream<S>, Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let header_len = 1 + HANDSHAKE_PROTOCOL.len();
+    read_exact(stream, vec![0; header_len])
+        .map_err(|e| e.into())
+        .and_then(move |(stream, first)| -> BxFuture<EncryptedStream<S>, Error> {
+            let plaintext = first[0] as usize == HANDSHAKE_PROTOCOL.len() && &first[1..] == HANDSHAKE_PROTOCOL.as_bytes();
+            match (plaintext, policy) {
+                (true, EncryptionPolicy::Required) => future::err("peer connected without encryption".into()).bxed(),
+                (true, _) => future::ok(EncryptedStream::new(stream, None, None, first)).bxed(),
+                (false, EncryptionPolicy::Disabled) => future::err("peer connected with encryption".into()).bxed(),
+                (false, _) => accept_encrypted(stream, first, info_hashes, policy),
+            }
+        })
+        .bxed()
+}
+
+/// Accept an encrypted connection as the receiving side.
+/// `first` is the start of the remote public key.
+fn accept_encrypted<S>(stream: S, first: Vec<u8>, info_hashes: Vec<InfoHash>, policy: EncryptionPolicy) -> BxFuture<EncryptedStream<S>, Error>
+    where S: AsyncRead + AsyncWrite + 'static
+{
+    let rand = SystemRandom::new();
+    let (private_key, public_key) = match generate_keys(&rand) {
+        Ok(x) => x,
+        Err(err) => return future::err(err).bxed(),
+    };
+    let pad = match random_pad(&rand) {
+        Ok(x) => x,
+        Err(err) => return future::err(err).bxed(),
+    };
+
+    read_exact(stream, vec![0; KEY_LEN - first.len()])
+        .map_err(|e| e.into())
+        .and_then(move |(stream, rest)| -> Result<_> {
+                      let mut remote_key = first;
+                      remote_key.extend(rest);
+                      let secret = shared_secret(&private_key, &remote_key)?;
+                      Ok((stream, secret))
+                  })
+        .and_then(move |(stream, secret)| {
+            let mut out = public_key.to_vec();
+            out.extend_from_slice(&pad);
+            write_all(stream, out)
+                .map_err(|e| e.into())
+                .and_then(move |(stream, _)| {
+                              // Their reply starts after the padding with this hash.
+                              read_until(stream, hash(&[b"req1", &secret]).to_vec(), MAX_PAD_LEN)
+                          })
+                .map(move |stream| (stream, secret))
+        })
+        .and_then(|(stream, secret)| {
+            read_exact(stream, [0; INFO_HASH_SIZE])
+                .map_err(|e| e.into())
+                .and_then(move |(stream, skey_hash)| -> Result<_> {
+                    let req3 = hash(&[b"req3", &secret]);
+                    let info_hash = match info_hashes
+                              .into_iter()
+                              .find(|x| xor(&hash(&[b"req2", &x.hash]), &req3)[..] == skey_hash[..]) {
+                        Some(x) => x,
+                        None => bail!("peer wants unknown torrent"),
+                    };
+                    let decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash.hash]));
+                    let encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash.hash]));
+                    Ok((stream, encrypt, decrypt))
+                })
+        })
+        .and_then(|(stream, encrypt, mut decrypt)| {
+            read_exact(stream, [0; 14])
+                .map_err(|e| e.into())
+                .and_then(move |(stream, mut buf)| -> Result<_> {
+                              decrypt.apply(&mut buf);
+                              if buf[..8] != VC {
+                                  bail!("bad encryption verification constant");
+                              }
+                              let crypto_provide = BigEndian::read_u32(&buf[8..12]);
+                              let pad_len = BigEndian::read_u16(&buf[12..]) as usize;
+                              if pad_len > MAX_PAD_LEN {
+                                  bail!("encryption padding too long: {}", pad_len);
+                              }
+                              Ok((stream, encrypt, decrypt, crypto_provide, pad_len))
+                          })
+        })
+        .and_then(|(stream, encrypt, mut decrypt, crypto_provide, pad_len)| {
+            // Padding followed by the length of the initial payload.
+            read_exact(stream, vec![0; pad_len + 2])
+                .map_err(|e| e.into())
+                .map(move |(stream, mut buf)| {
+                         decrypt.apply(&mut buf);
+                         let ia_len = BigEndian::read_u16(&buf[pad_len..]) as usize;
+                         (stream, encrypt, decrypt, crypto_provide, ia_len)
+                     })
+        })
+        .and_then(move |(stream, encrypt, mut decrypt, crypto_provide, ia_len)| {
+            read_exact(stream, vec![0; ia_len])
+                .map_err(|e| e.into())
+                .and_then(move |(stream, mut initial_payload)| -> Result<_> {
+                    decrypt.apply(&mut initial_payload);
+                    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+                        CRYPTO_RC4
+                    } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Required {
+                        CRYPTO_PLAINTEXT
+                    } else {
+                        bail!("peer provided no acceptable encryption: {}", crypto_provide);
+                    };
+                    Ok((stream, encrypt, decrypt, crypto_select, initial_payload))
+                })
+        })
+        .and_then(|(stream, mut encrypt, decrypt, crypto_select, initial_payload)| {
+            let mut out = VC.to_vec();
+            out.extend_from_slice(&u32_bytes(crypto_select));
+            // No padding.
+            out.extend_from_slice(&[0, 0]);
+            encrypt.apply(&mut out);
+            write_all(stream, out)
+                .map_err(|e| e.into())
+                .map(move |(stream, _)| if crypto_select == CRYPTO_RC4 {
+                         EncryptedStream::new(stream, Some(decrypt), Some(encrypt), initial_payload)
+                     } else {
+                         EncryptedStream::new(stream, None, None, initial_payload)
+                     })
+        })
+        .bxed()
+}
+
+/// Read from the stream until `pattern` has been read.
+/// Fails if more than `max_skip` bytes come before the pattern.
+fn read_until<S>(stream: S, pattern: Vec<u8>, max_skip: usize) -> BxFuture<S, Error>
+    where S: AsyncRead + 'static
+{
+    future::loop_fn((stream, Vec::new()), move |(stream, mut window)| {
+        let pattern = pattern.clone();
+        read_exact(stream, [0; 1])
+            .map_err(|e| e.into())
+            .and_then(move |(stream, byte)| {
+                          window.push(byte[0]);
+                          if window.ends_with(&pattern) {
+                              return Ok(Loop::Break(stream));
+                          }
+                          if window.len() >= pattern.len() + max_skip {
+                              bail!("encryption handshake did not synchronize");
+                          }
+                          Ok(Loop::Continue((stream, window)))
+                      })
+    })
+            .bxed()
+}
+
+/// RC4 stream cipher with the start of the keystream discarded.
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::new_no_discard(key);
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+
+    fn new_no_discard(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for i in 0..256 {
+            s[i] = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s: s, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt in place.
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for x in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *x ^= k;
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut ctx = digest::Context::new(&digest::SHA1);
+    for part in parts {
+        ctx.update(part);
+    }
+    let mut out = [0; 20];
+    out.copy_from_slice(ctx.finish().as_ref());
+    out
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+fn u32_bytes(x: u32) -> [u8; 4] {
+    let mut buf = [0; 4];
+    BigEndian::write_u32(&mut buf, x);
+    buf
+}
+
+fn random_pad(rand: &SystemRandom) -> Result<Vec<u8>> {
+    let mut len = [0; 2];
+    rand.fill(&mut len)?;
+    let mut pad = vec![0; BigEndian::read_u16(&len) as usize % (MAX_PAD_LEN + 1)];
+    rand.fill(&mut pad)?;
+    Ok(pad)
+}
+
+/// Generate a Diffie-Hellman private and public key.
+fn generate_keys(rand: &SystemRandom) -> Result<([u8; PRIVATE_KEY_LEN], [u8; KEY_LEN])> {
+    let mut private_key = [0; PRIVATE_KEY_LEN];
+    rand.fill(&mut private_key)?;
+    let public_key = bn_to_bytes(&bn_pow_mod(&bn_from_bytes(&[2]), &private_key, &prime()));
+    Ok((private_key, public_key))
+}
+
+/// Compute the shared secret from our private key and their public key.
+fn shared_secret(private_key: &[u8], remote_key: &[u8]) -> Result<[u8; KEY_LEN]> {
+    let p = prime();
+    let remote = bn_from_bytes(remote_key);
+    let one = bn_from_bytes(&[1]);
+    let mut p_minus_one = p;
+    bn_sub_assign(&mut p_minus_one, &one);
+    // These keys would make the secret easy to guess.
+    if remote == [0; NUM_LIMBS] || remote == one || bn_ge(&remote, &p_minus_one) {
+        bail!("invalid encryption public key");
+    }
+    Ok(bn_to_bytes(&bn_pow_mod(&remote, private_key, &p)))
+}
+
+// Just enough fixed-size unsigned bignum for Diffie-Hellman.
+// Numbers are little-endian arrays of 32-bit limbs.
+type Limbs = [u32; NUM_LIMBS];
+
+fn prime() -> Limbs {
+    let bytes: Vec<u8> = (0..KEY_LEN)
+        .map(|i| u8::from_str_radix(&PRIME_HEX[i * 2..i * 2 + 2], 16).unwrap())
+        .collect();
+    bn_from_bytes(&bytes)
+}
+
+/// From big-endian bytes. At most KEY_LEN bytes.
+fn bn_from_bytes(bytes: &[u8]) -> Limbs {
+    let mut x = [0; NUM_LIMBS];
+    for (i, b) in bytes.iter().rev().enumerate() {
+        x[i / 4] |= (*b as u32) << (8 * (i % 4));
+    }
+    x
+}
+
+/// To big-endian bytes.
+fn bn_to_bytes(x: &Limbs) -> [u8; KEY_LEN] {
+    let mut out = [0; KEY_LEN];
+    for i in 0..KEY_LEN {
+        out[KEY_LEN - 1 - i] = (x[i / 4] >> (8 * (i % 4))) as u8;
+    }
+    out
+}
+
+fn bn_ge(a: &Limbs, b: &Limbs) -> bool {
+    for i in (0..NUM_LIMBS).rev() {
+        if a[i] != b[i] {
+            return a[i] > b[i];
+        }
+    }
+    true
+}
+
+/// a -= b, wrapping.
+fn bn_sub_assign(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = 0;
+    for i in 0..NUM_LIMBS {
+        let x = (a[i] as u64).wrapping_sub(b[i] as u64).wrapping_sub(borrow);
+        a[i] = x as u32;
+        borrow = (x >> 63) & 1;
+    }
+}
+
+/// a = (a + b) mod p, where a and b are less than p.
+fn bn_add_mod(a: &mut Limbs, b: &Limbs, p: &Limbs) {
+    let mut carry = 0;
+    for i in 0..NUM_LIMBS {
+        let x = a[i] as u64 + b[i] as u64 + carry;
+        a[i] = x as u32;
+        carry = x >> 32;
+    }
+    if carry != 0 || bn_ge(a, p) {
+        bn_sub_assign(a, p);
+    }
+}
+
+/// -p^-1 mod 2^32, for odd p.
+fn bn_mont_inverse(p: &Limbs) -> u32 {
+    // Each Newton step doubles the number of correct low bits.
+    let mut inv: u32 = 1;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(p[0].wrapping_mul(inv)));
+    }
+    inv.wrapping_neg()
+}
+
+/// Montgomery product a * b / R mod p, where R is 2^(32 * NUM_LIMBS),
+/// p is odd, a and b are less than p and `inv` is `bn_mont_inverse(p)`.
+fn bn_mont_mul(a: &Limbs, b: &Limbs, p: &Limbs, inv: u32) -> Limbs {
+    let mut t = [0u32; NUM_LIMBS + 2];
+    for i in 0..NUM_LIMBS {
+        // t += a * b[i]
+        let mut carry = 0;
+        for j in 0..NUM_LIMBS {
+            let x = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+            t[j] = x as u32;
+            carry = x >> 32;
+        }
+        let x = t[NUM_LIMBS] as u64 + carry;
+        t[NUM_LIMBS] = x as u32;
+        t[NUM_LIMBS + 1] = (x >> 32) as u32;
+
+        // t = (t + m * p) / 2^32, choosing m so the division is exact.
+        let m = t[0].wrapping_mul(inv) as u64;
+        let mut carry = (t[0] as u64 + m * p[0] as u64) >> 32;
+        for j in 1..NUM_LIMBS {
+            let x = t[j] as u64 + m * p[j] as u64 + carry;
+            t[j - 1] = x as u32;
+            carry = x >> 32;
+        }
+        let x = t[NUM_LIMBS] as u64 + carry;
+        t[NUM_LIMBS - 1] = x as u32;
+        t[NUM_LIMBS] = t[NUM_LIMBS + 1] + (x >> 32) as u32;
+    }
+    let mut r = [0; NUM_LIMBS];
+    r.copy_from_slice(&t[..NUM_LIMBS]);
+    if t[NUM_LIMBS] != 0 || bn_ge(&r, p) {
+        bn_sub_assign(&mut r, p);
+    }
+    r
+}
+
+/// base ^ exp mod p, with exp as big-endian bytes.
+/// p must be odd and base less than p.
+fn bn_pow_mod(base: &Limbs, exp: &[u8], p: &Limbs) -> Limbs {
+    let inv = bn_mont_inverse(p);
+    let one = bn_from_bytes(&[1]);
+    // R^2 mod p converts numbers into Montgomery form.
+    let mut r2 = one;
+    for _ in 0..NUM_LIMBS * 32 * 2 {
+        let x = r2;
+        bn_add_mod(&mut r2, &x, p);
+    }
+    let base = bn_mont_mul(base, &r2, p, inv);
+    let mut r = bn_mont_mul(&one, &r2, p, inv);
+    for byte in exp {
+        for bit in (0..8).rev() {
+            r = bn_mont_mul(&r, &r, p, inv);
+            if (byte >> bit) & 1 == 1 {
+                r = bn_mont_mul(&r, &base, p, inv);
+            }
+        }
+    }
+    bn_mont_mul(&r, &one, p, inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use mse::*;
+    use std::net::SocketAddr;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use tokio_core::reactor::Core;
+    use futures::Stream;
+
+    #[test]
+    fn test_rc4() {
+        let mut buf = b"Plaintext".to_vec();
+        Rc4::new_no_discard(b"Key").apply(&mut buf);
+        assert_eq!(buf, vec![0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn test_bn_pow_mod() {
+        let p = bn_from_bytes(&[1, 0, 1]); // 65537
+        let x = bn_pow_mod(&bn_from_bytes(&[3]), &[1, 0], &p); // 3^256 mod 65537
+        assert_eq!(bn_to_bytes(&x)[KEY_LEN - 3..], [0, 0x01, 0x1a]);
+    }
+
+    #[test]
+    fn test_bn_pow_mod_prime() {
+        // Fermat: 2^(p-1) mod p is 1 for the prime p.
+        let p = prime();
+        let mut exp = p;
+        bn_sub_assign(&mut exp, &bn_from_bytes(&[1]));
+        let x = bn_pow_mod(&bn_from_bytes(&[2]), &bn_to_bytes(&exp), &p);
+        assert_eq!(x, bn_from_bytes(&[1]));
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let rand = SystemRandom::new();
+        let (a_private, a_public) = generate_keys(&rand).unwrap();
+        let (b_private, b_public) = generate_keys(&rand).unwrap();
+        assert_eq!(shared_secret(&a_private, &b_public).unwrap()[..],
+                   shared_secret(&b_private, &a_public).unwrap()[..]);
+        // Degenerate keys are refused.
+        let p = prime();
+        let mut p_minus_one = p;
+        bn_sub_assign(&mut p_minus_one, &bn_from_bytes(&[1]));
+        for key in &[[0; NUM_LIMBS], bn_from_bytes(&[1]), p_minus_one, p] {
+            assert!(shared_secret(&a_private, &bn_to_bytes(key)).is_err());
+        }
+    }
+
+    fn handshake_pair(connect_policy: EncryptionPolicy, accept_policy: EncryptionPolicy) -> Result<(bool, bool)> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr, &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = InfoHash { hash: [7; INFO_HASH_SIZE] };
+
+        // The listener also serves another torrent.
+        let other_hash = InfoHash { hash: [8; INFO_HASH_SIZE] };
+        let info_hash2 = info_hash.clone();
+        let accepted = listener
+            .incoming()
+            .into_future()
+            .map_err(|(err, _)| err.into())
+            .and_then(move |(x, _)| accept(x.unwrap().0, vec![other_hash, info_hash2], accept_policy))
+            .and_then(|stream| write_all(stream, b"hello".to_vec()).map_err(|e| e.into()))
+            .map(|(stream, _)| stream.is_encrypted());
+        let connected = TcpStream::connect(&addr, &handle)
+            .map_err(|e| e.into())
+            .and_then(move |stream| if connect_policy == EncryptionPolicy::Disabled {
+                          future::ok(EncryptedStream::plaintext(stream)).bxed()
+                      } else {
+                          connect(stream, info_hash, connect_policy)
+                      })
+            .and_then(|stream| {
+                          let encrypted = stream.is_encrypted();
+                          read_exact(stream, [0; 5])
+                              .map_err(|e| e.into())
+                              .map(move |(_, buf)| (encrypted, buf))
+                      })
+            .map(|(encrypted, buf)| {
+                     assert_eq!(&buf, b"hello");
+                     encrypted
+                 });
+        core.run(accepted.join(connected))
+    }
+
+    #[test]
+    fn test_handshake_encrypted() {
+        assert_eq!(handshake_pair(EncryptionPolicy::Enabled, EncryptionPolicy::Enabled).unwrap(),
+                   (true, true));
+        assert_eq!(handshake_pair(EncryptionPolicy::Required, EncryptionPolicy::Enabled).unwrap(),
+                   (true, true));
+    }
+
+    #[test]
+    fn test_handshake_policy() {
+        assert!(handshake_pair(EncryptionPolicy::Enabled, EncryptionPolicy::Disabled).is_err());
+    }
+}
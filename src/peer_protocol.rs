@@ -1,6 +1,6 @@
 use byteorder::{BigEndian, ByteOrder};
-use bytes::{Buf, BytesMut};
-use errors::{Error, Result};
+use bytes::{Buf, Bytes, BytesMut, IntoBuf};
+use errors::{Error, ErrorKind, Result};
 use futures::future;
 use futures::future::{BoxFuture, Future};
 use itertools::Itertools;
@@ -11,10 +11,10 @@ use std;
 use std::fmt;
 use tokio_io;
 use tokio_io::{AsyncRead, AsyncWrite};
-use util::byte_to_bits;
+use util::{bits_to_byte, byte_to_bits};
 
 pub const PEERID_SIZE: usize = 20;
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct PeerID {
     pub id: [u8; PEERID_SIZE],
 }
@@ -38,73 +38,259 @@ impl PeerID {
         id[6] = '1' as u8;
         Ok(Self { id: id })
     }
+
+    /// Decode the client software from the peer id.
+    /// Understands the Azureus style (`-XX1234-...`) and the Shadow style (`S58B-----...`).
+    pub fn client(&self) -> Option<Client> {
+        self.azureus_client().or_else(|| self.shadow_client())
+    }
+
+    fn azureus_client(&self) -> Option<Client> {
+        let id = &self.id;
+        if id[0] != b'-' || id[7] != b'-' {
+            return None;
+        }
+        if !id[1..7].iter().all(|c| (*c as char).is_ascii_alphanumeric()) {
+            return None;
+        }
+        let code = String::from_utf8_lossy(&id[1..3]).into_owned();
+        let name = match code.as_str() {
+            "AZ" => "Vuze",
+            "BC" => "BitComet",
+            "BI" => "BiglyBT",
+            "BT" => "BitTorrent",
+            "DE" => "Deluge",
+            "FD" => "Free Download Manager",
+            "KT" => "KTorrent",
+            "LT" => "libtorrent",
+            "lt" => "rTorrent",
+            "qB" => "qBittorrent",
+            "TR" => "Transmission",
+            "UM" => "µTorrent Mac",
+            "UT" => "µTorrent",
+            "UW" => "µTorrent Web",
+            "WW" => "WebTorrent",
+            _ => code.as_str(),
+        }
+                .to_owned();
+        let version = id[3..7]
+            .iter()
+            .map(|c| (*c as char).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Some(Client {
+                 name: name,
+                 version: version,
+             })
+    }
+
+    fn shadow_client(&self) -> Option<Client> {
+        let id = &self.id;
+        let name = match id[0] {
+            b'A' => "ABC",
+            b'O' => "Osprey Permaseed",
+            b'Q' => "BTQueue",
+            b'R' => "Tribler",
+            b'S' => "Shadow",
+            b'T' => "BitTornado",
+            b'U' => "UPnP NAT Bit Torrent",
+            _ => return None,
+        };
+        // Up to 5 version characters padded with '-', then "---".
+        if &id[6..9] != b"---" {
+            return None;
+        }
+        let mut parts = Vec::new();
+        for c in id[1..6].iter().take_while(|c| **c != b'-') {
+            let n = match *c {
+                b'0'..=b'9' => c - b'0',
+                b'A'..=b'Z' => c - b'A' + 10,
+                b'a'..=b'z' => c - b'a' + 36,
+                b'.' => 62,
+                _ => return None,
+            };
+            parts.push(n.to_string());
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        Some(Client {
+                 name: name.to_owned(),
+                 version: parts.join("."),
+             })
+    }
+}
+
+/// Client software identified from a peer id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub version: String,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// Shows the client software if known, otherwise the id in hex.
+impl fmt::Display for PeerID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        match self.client() {
+            Some(client) => write!(f, "{}", client),
+            None => write!(f, "{:?}", self),
+        }
+    }
 }
 
 // In version 1.0 of the BitTorrent protocol, pstrlen = 19, and pstr = "BitTorrent protocol".
-const HANDSHAKE_PROTOCOL: &'static str = "BitTorrent protocol";
+pub const HANDSHAKE_PROTOCOL: &'static str = "BitTorrent protocol";
 
-pub struct BitTorrentPeerCodec;
+/// The 8 reserved bytes of the handshake.
+/// Each set bit advertises support for a protocol extension.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Reserved {
+    pub bytes: [u8; 8],
+}
+
+impl fmt::Debug for Reserved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        write!(f, "{:02x}", self.bytes.iter().format(""))
+    }
+}
+
+impl Reserved {
+    /// The reserved bytes advertising what this client supports.
+    pub fn local() -> Self {
+        let mut r = Reserved::default();
+        r.bytes[5] |= 0x10;
+        r.bytes[7] |= 0x04;
+        r
+    }
+
+    /// Extension protocol (BEP 10).
+    /// Bit 20 counting from the right.
+    pub fn extension_protocol(&self) -> bool {
+        self.bytes[5] & 0x10 != 0
+    }
+
+    /// Fast extension (BEP 6).
+    /// Bit 62 counting from the right.
+    pub fn fast_extension(&self) -> bool {
+        self.bytes[7] & 0x04 != 0
+    }
+}
+
+/// Largest message accepted by default, not counting the length prefix.
+/// Fits a 128KiB block in a piece message.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: u32 = (1 << 17) + 9;
+
+/// Fail with a protocol violation.
+macro_rules! bail_protocol {
+    ($($arg:tt)*) => {
+        bail!(ErrorKind::Protocol(format!($($arg)*)))
+    };
+}
+
+pub struct BitTorrentPeerCodec {
+    /// Longest message to accept, not counting the length prefix.
+    max_message_length: u32,
+    /// Number of pieces in the torrent if known.
+    /// Used to validate bitfields.
+    num_pieces: Option<u64>,
+}
 
 impl BitTorrentPeerCodec {
+    pub fn new(num_pieces: Option<u64>, max_message_length: u32) -> Self {
+        BitTorrentPeerCodec {
+            max_message_length: max_message_length,
+            num_pieces: num_pieces,
+        }
+    }
+
     // Sense whether there's a complete frame.
     // Does not take any bytes from src.
     // Returns Some(message_length) is there is a complete frame.
     // message_length does not include its own 4 bytes.
     // For example, for an `Interested`, message_length would be 1.
     // Returns None if not enough data to read a frame.
-    fn sense_frame(src: &BytesMut) -> Option<u32> {
+    // Fails if the frame is longer than allowed.
+    fn sense_frame(&self, src: &BytesMut) -> Result<Option<u32>> {
         const NUM_LEN: usize = 4;
         if src.len() < NUM_LEN {
             // Wait for the frame size
-            return None;
+            return Ok(None);
         }
         let message_length = BigEndian::read_u32(src.as_ref());
-        if src.len() + NUM_LEN < message_length as usize {
+        if message_length > self.max_message_length {
+            bail_protocol!("message length {} exceeds limit {}",
+                           message_length,
+                           self.max_message_length);
+        }
+        if src.len() < NUM_LEN + message_length as usize {
             // Wait for complete frame
-            return None;
+            return Ok(None);
         }
-        Some(message_length)
+        Ok(Some(message_length))
     }
 
     // Decode a single message.
     // Called with the exact frame (excluding message length tag).
-    fn decode_message<B: Buf>(mut src: B) -> std::result::Result<Message, Error> {
+    // Piece blocks share memory with the frame rather than being copied.
+    // Returns None for messages with unknown ids, which are to be ignored.
+    fn decode_message(&self, frame: Bytes) -> std::result::Result<Option<Message>, Error> {
         type BE = BigEndian;
         const NUM_LEN: usize = 4;
         use peer_protocol::Message::*;
 
+        let mut src = frame.clone().into_buf();
+
         let message_length = src.remaining();
 
         if message_length == 0 {
-            return Ok(KeepAlive);
+            return Ok(Some(KeepAlive));
         }
         let message_id = src.get_u8();
 
         if message_length == 1 {
             return match message_id {
-                       0 => Ok(Choke),
-                       1 => Ok(Unchoke),
-                       2 => Ok(Interested),
-                       3 => Ok(NotInterested),
-                       4 | 5 | 6 | 7 | 8 | 9 => bail!("message id {} specified no body", message_id),
-                       _ => bail!("unknown message id {} (no-body)", message_id),
+                       0 => Ok(Some(Choke)),
+                       1 => Ok(Some(Unchoke)),
+                       2 => Ok(Some(Interested)),
+                       3 => Ok(Some(NotInterested)),
+                       14 => Ok(Some(HaveAll)),
+                       15 => Ok(Some(HaveNone)),
+                       4 | 5 | 6 | 7 | 8 | 9 | 13 | 16 | 17 | 20 => bail_protocol!("message id {} specified no body", message_id),
+                       _ => Ok(None),
                    };
         }
 
         let body_length: usize = message_length - 1;
 
-        match message_id {
-            0 | 1 | 2 | 3 => // Choke; Unchoke; Interested; NotInterested
-                bail!("message id {} specified non-zero body {}", message_id, body_length),
+        let message = match message_id {
+            0 | 1 | 2 | 3 | 14 | 15 => // Choke; Unchoke; Interested; NotInterested; HaveAll; HaveNone
+                bail_protocol!("message id {} specified non-zero body {}", message_id, body_length),
             4 => { // Message::Have
                 if body_length != NUM_LEN {
-                    bail!("message wrong size 'Have' {} != 4", body_length);
+                    bail_protocol!("message wrong size 'Have' {} != 4", body_length);
                 }
                 Ok(Have {
                     piece: src.get_u32::<BE>()
                 })
             },
             5 => { // Message::Bitfield
+                if let Some(num_pieces) = self.num_pieces {
+                    let expected = (num_pieces + 7) / 8;
+                    if body_length as u64 != expected {
+                        bail_protocol!("message wrong size 'Bitfield' {} != {}", body_length, expected);
+                    }
+                    // Bits past the last piece must be zero.
+                    let spare = (expected * 8 - num_pieces) as u32;
+                    if spare > 0 && src.bytes()[body_length - 1] & ((1u8 << spare) - 1) != 0 {
+                        bail_protocol!("bitfield has spare bits set");
+                    }
+                }
                 let mut bits: Vec<bool> = Vec::new();
                 for byte in src.iter() {
                     bits.extend_from_slice(&byte_to_bits(byte));
@@ -115,7 +301,7 @@ impl BitTorrentPeerCodec {
             },
             6 => { // Message::Request
                 if body_length != NUM_LEN * 3 {
-                    bail!("message wrong size 'Request' {} != 12", body_length);
+                    bail_protocol!("message wrong size 'Request' {} != 12", body_length);
                 }
                 Ok(Request {
                     piece: src.get_u32::<BE>(),
@@ -125,11 +311,11 @@ impl BitTorrentPeerCodec {
             },
             7 => { // Message::Piece
                 if body_length < NUM_LEN * 2 {
-                    bail!("message wrong size 'Piece' {} < 8", body_length);
+                    bail_protocol!("message wrong size 'Piece' {} < 8", body_length);
                 }
                 let piece = src.get_u32::<BE>();
                 let offset = src.get_u32::<BE>();
-                let block = src.bytes().to_vec();
+                let block = frame.slice_from(1 + NUM_LEN * 2);
                 Ok(Piece {
                     piece: piece,
                     offset: offset,
@@ -138,7 +324,7 @@ impl BitTorrentPeerCodec {
             },
             8 => { // Message::Cancel
                 if body_length != NUM_LEN * 3 {
-                    bail!("message wrong size 'Cancel' {} != 12", body_length);
+                    bail_protocol!("message wrong size 'Cancel' {} != 12", body_length);
                 }
                 Ok(Cancel {
                     piece: src.get_u32::<BE>(),
@@ -147,15 +333,50 @@ impl BitTorrentPeerCodec {
                 })
             },
             9 => { // Message::Port
-                if body_length != NUM_LEN {
-                    bail!("message wrong size 'Port' {} != 4", body_length);
+                if body_length != 2 {
+                    bail_protocol!("message wrong size 'Port' {} != 2", body_length);
                 }
                 Ok(Port {
-                    port: src.get_u32::<BE>(),
+                    port: src.get_u16::<BE>(),
                 })
             },
-            _ => bail!("unknown message id {} (+body)", message_id),
-        }
+            13 => { // Message::Suggest
+                if body_length != NUM_LEN {
+                    bail_protocol!("message wrong size 'Suggest' {} != 4", body_length);
+                }
+                Ok(Suggest {
+                    piece: src.get_u32::<BE>()
+                })
+            },
+            16 => { // Message::Reject
+                if body_length != NUM_LEN * 3 {
+                    bail_protocol!("message wrong size 'Reject' {} != 12", body_length);
+                }
+                Ok(Reject {
+                    piece: src.get_u32::<BE>(),
+                    offset: src.get_u32::<BE>(),
+                    length: src.get_u32::<BE>(),
+                })
+            },
+            17 => { // Message::AllowedFast
+                if body_length != NUM_LEN {
+                    bail_protocol!("message wrong size 'AllowedFast' {} != 4", body_length);
+                }
+                Ok(AllowedFast {
+                    piece: src.get_u32::<BE>()
+                })
+            },
+            20 => { // Message::Extended
+                let id = src.get_u8();
+                let payload = src.bytes().to_vec();
+                Ok(Extended {
+                    id: id,
+                    payload: payload,
+                })
+            },
+            _ => return Ok(None),
+        };
+        message.map(Some)
     }
 }
 
@@ -165,27 +386,29 @@ impl tokio_io::codec::Decoder for BitTorrentPeerCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> std::result::Result<Option<Self::Item>, Self::Error> {
         const NUM_LEN: usize = 4;
-        use bytes::IntoBuf;
 
-        let message_length = match Self::sense_frame(&src) {
-            Some(x) => x,
-            None => return Ok(None),
-        };
+        // Messages we don't know are skipped.
+        loop {
+            let message_length = match self.sense_frame(&src)? {
+                Some(x) => x,
+                None => return Ok(None),
+            };
 
-        // Drop the bytes representing message_length
-        let _ = src.split_to(NUM_LEN);
+            // Drop the bytes representing message_length
+            let _ = src.split_to(NUM_LEN);
 
-        assert!(src.len() >= message_length as usize,
-                "decoder bug: buf_len:{} < msg_len:{}",
-                src.len(),
-                message_length);
+            assert!(src.len() >= message_length as usize,
+                    "decoder bug: buf_len:{} < msg_len:{}",
+                    src.len(),
+                    message_length);
 
-        // Take the message out of the source.
-        // Also convert it to a Buf.
-        // And replace the `src` name to confuse myself.
-        let src = src.split_to(message_length as usize).freeze().into_buf();
+            // Take the message out of the source without copying.
+            let frame = src.split_to(message_length as usize).freeze();
 
-        Self::decode_message(src).map(Some)
+            if let Some(msg) = self.decode_message(frame)? {
+                return Ok(Some(msg));
+            }
+        }
     }
 }
 
@@ -202,15 +425,45 @@ impl tokio_io::codec::Encoder for BitTorrentPeerCodec {
                 dst.reserve(4);
                 dst.put_u32::<BE>(0); // message length
             }
-            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => {
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested | Message::HaveAll | Message::HaveNone => {
                 dst.reserve(4 + 1);
                 dst.put_u32::<BE>(1); // message length
                 dst.put_u8(message_id);
             }
+            Message::Have { piece } |
+            Message::Suggest { piece } |
+            Message::AllowedFast { piece } => {
+                dst.reserve(4 + 1 + 4);
+                dst.put_u32::<BE>(1 + 4); // message length
+                dst.put_u8(message_id);
+                dst.put_u32::<BE>(piece);
+            }
+            Message::Bitfield { ref bits } => {
+                // Pack 8 bits per byte, padding the last byte with zeros.
+                let n_bytes = (bits.len() + 7) / 8;
+                dst.reserve(4 + 1 + n_bytes);
+                dst.put_u32::<BE>((1 + n_bytes) as u32); // message length
+                dst.put_u8(message_id);
+                for chunk in bits.chunks(8) {
+                    let mut byte = [false; 8];
+                    byte[..chunk.len()].copy_from_slice(chunk);
+                    dst.put_u8(bits_to_byte(byte));
+                }
+            }
             Message::Request {
                 piece,
                 offset,
                 length,
+            } |
+            Message::Cancel {
+                piece,
+                offset,
+                length,
+            } |
+            Message::Reject {
+                piece,
+                offset,
+                length,
             } => {
                 dst.reserve(4 + 1 + 4 * 3);
                 dst.put_u32::<BE>(1 + 4 * 3); // message length
@@ -219,16 +472,30 @@ impl tokio_io::codec::Encoder for BitTorrentPeerCodec {
                 dst.put_u32::<BE>(offset);
                 dst.put_u32::<BE>(length);
             }
-            Message::Bitfield { ref bits } => {
-                // TODO(jessk) why is `ref` required here?
-                // TODO(jessk) make this work
-                // let msg: [u8] = bits.chunks(8).map(|bb| bits_to_byte(bb)).collect();
-                // send_message_frame(stream, message_id, &msg)?;
-                let _ = bits;
-                bail!("send_message not implemented for id {}", message_id);
+            Message::Piece {
+                piece,
+                offset,
+                ref block,
+            } => {
+                dst.reserve(4 + 1 + 4 * 2 + block.len());
+                dst.put_u32::<BE>((1 + 4 * 2 + block.len()) as u32); // message length
+                dst.put_u8(message_id);
+                dst.put_u32::<BE>(piece);
+                dst.put_u32::<BE>(offset);
+                dst.put_slice(block);
             }
-            _ => {
-                bail!("send_message not implemented for id {}", message_id);
+            Message::Port { port } => {
+                dst.reserve(4 + 1 + 2);
+                dst.put_u32::<BE>(1 + 2); // message length
+                dst.put_u8(message_id);
+                dst.put_u16::<BE>(port);
+            }
+            Message::Extended { id, ref payload } => {
+                dst.reserve(4 + 1 + 1 + payload.len());
+                dst.put_u32::<BE>((1 + 1 + payload.len()) as u32); // message length
+                dst.put_u8(message_id);
+                dst.put_u8(id);
+                dst.put_slice(payload);
             }
         }
         Ok(())
@@ -236,7 +503,7 @@ impl tokio_io::codec::Encoder for BitTorrentPeerCodec {
 }
 
 /// Read the first half of the peer handshake.
-pub fn handshake_read_1_async<R>(stream: R) -> BoxFuture<(R, InfoHash), Error>
+pub fn handshake_read_1_async<R>(stream: R) -> BoxFuture<(R, InfoHash, Reserved), Error>
     where R: AsyncRead + Send + 'static
 {
     use tokio_io::io::read_exact;
@@ -265,13 +532,13 @@ pub fn handshake_read_1_async<R>(stream: R) -> BoxFuture<(R, InfoHash), Error>
             // 8 reserved bytes.
             read_exact(stream, [0; 8])
         })
-        .and_then(|(stream, _)| {
+        .and_then(|(stream, reserved)| {
             // Info hash
             let info_hash = [0; INFO_HASH_SIZE];
-            read_exact(stream, info_hash)
+            read_exact(stream, info_hash).map(move |(stream, info_hash)| (stream, info_hash, reserved))
         })
-        .and_then(|(stream, info_hash)| {
-            Ok((stream, InfoHash { hash: info_hash }))
+        .and_then(|(stream, info_hash, reserved)| {
+            Ok((stream, InfoHash { hash: info_hash }, Reserved { bytes: reserved }))
         })
         .map_err(|e| e.into())
         .boxed()
@@ -306,7 +573,7 @@ pub fn handshake_send_async<W>(stream: W, info_hash: InfoHash, peer_id: PeerID)
         .and_then(move |(stream, _)| write_all(stream, pstr))
         .and_then(|(stream, _)| {
                       // 8 reserved bytes
-                      write_all(stream, [0; 8])
+                      write_all(stream, Reserved::local().bytes)
                   })
         .and_then(move |(stream, _)| {
                       // Info hash
@@ -334,9 +601,191 @@ mod tests {
         let mut reader2 = std::io::Read::take(reader, 1);
         assert_eq!(reader2.read_n(3).unwrap(), vec![0, 1, 2]);
     }
+
+    /// Encode a message and decode it again.
+    fn round_trip(msg: Message) -> Message {
+        use tokio_io::codec::{Decoder, Encoder};
+        let mut buf = BytesMut::with_capacity(64);
+        let mut codec = BitTorrentPeerCodec::new(None, DEFAULT_MAX_MESSAGE_LENGTH);
+        codec.encode(msg, &mut buf).unwrap();
+        let out = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty(), "decode left {} bytes", buf.len());
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let msgs = vec![Message::KeepAlive,
+                        Message::Choke,
+                        Message::Unchoke,
+                        Message::Interested,
+                        Message::NotInterested,
+                        Message::Have { piece: 70000 },
+                        Message::Bitfield { bits: vec![true, false, true, true, false, false, false, true] },
+                        Message::Request {
+                            piece: 1,
+                            offset: 1 << 14,
+                            length: 1 << 14,
+                        },
+                        Message::Piece {
+                            piece: 2,
+                            offset: 3,
+                            block: Bytes::from(&[1, 2, 3, 4, 5][..]),
+                        },
+                        Message::Cancel {
+                            piece: 4,
+                            offset: 5,
+                            length: 6,
+                        },
+                        Message::Port { port: 6881 },
+                        Message::Suggest { piece: 7 },
+                        Message::HaveAll,
+                        Message::HaveNone,
+                        Message::Reject {
+                            piece: 8,
+                            offset: 9,
+                            length: 10,
+                        },
+                        Message::AllowedFast { piece: 11 },
+                        Message::Extended {
+                            id: 0,
+                            payload: b"d1:md6:ut_pexi1eee".to_vec(),
+                        }];
+        for msg in msgs {
+            assert_eq!(round_trip(msg.clone()), msg);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_bitfield_padding() {
+        let bits = vec![true, true, false, false, true, false, false, true, true, false, true];
+        let mut expected = bits.clone();
+        expected.extend_from_slice(&[false; 5]);
+        assert_eq!(round_trip(Message::Bitfield { bits: bits }),
+                   Message::Bitfield { bits: expected });
+    }
+
+    #[test]
+    fn test_encode_bitfield_bytes() {
+        use tokio_io::codec::Encoder;
+        let mut buf = BytesMut::with_capacity(64);
+        let msg = Message::Bitfield { bits: vec![true, false, false, false, false, false, false, true, true] };
+        BitTorrentPeerCodec::new(None, DEFAULT_MAX_MESSAGE_LENGTH).encode(msg, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 3, 5, 0b10000001, 0b10000000]);
+    }
+
+    #[test]
+    fn test_decode_piece_without_copy() {
+        use tokio_io::codec::{Decoder, Encoder};
+        let mut codec = BitTorrentPeerCodec::new(None, DEFAULT_MAX_MESSAGE_LENGTH);
+        let mut buf = BytesMut::with_capacity(1024);
+        let msg = Message::Piece {
+            piece: 1,
+            offset: 0,
+            block: Bytes::from(vec![7; 500]),
+        };
+        codec.encode(msg.clone(), &mut buf).unwrap();
+        let base = buf.as_ptr() as usize;
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message::Piece { ref block, .. }) => {
+                // The block points into the read buffer.
+                assert_eq!(block.as_ptr() as usize, base + 4 + 1 + 8);
+                assert_eq!(block.len(), 500);
+            }
+            x => panic!("unexpected decode result: {:?}", x),
+        }
+    }
+
+    fn peer_id(prefix: &[u8]) -> PeerID {
+        let mut id = [b'x'; PEERID_SIZE];
+        id[..prefix.len()].copy_from_slice(prefix);
+        PeerID { id: id }
+    }
+
+    #[test]
+    fn test_client_azureus() {
+        assert_eq!(peer_id(b"-TR2940-").client(),
+                   Some(Client {
+                            name: "Transmission".to_owned(),
+                            version: "2.9.4.0".to_owned(),
+                        }));
+        assert_eq!(peer_id(b"-ZZ1000-").client().unwrap().name, "ZZ");
+    }
+
+    #[test]
+    fn test_client_shadow() {
+        assert_eq!(peer_id(b"S58B-----").client(),
+                   Some(Client {
+                            name: "Shadow".to_owned(),
+                            version: "5.8.11".to_owned(),
+                        }));
+        assert_eq!(peer_id(b"T03I--00").client(), None);
+        assert_eq!(peer_id(b"M4-3-6--").client(), None);
+    }
+
+    fn is_protocol_error<T>(res: Result<T>) -> bool {
+        match res {
+            Err(Error(ErrorKind::Protocol(_), _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_decode_partial_frame() {
+        use tokio_io::codec::Decoder;
+        let mut codec = BitTorrentPeerCodec::new(None, DEFAULT_MAX_MESSAGE_LENGTH);
+        // A Request frame missing its last byte.
+        let frame = [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        let mut buf = BytesMut::from(&frame[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), frame.len());
+        buf.extend(&[3]);
+        assert_eq!(codec.decode(&mut buf).unwrap(),
+                   Some(Message::Request {
+                            piece: 1,
+                            offset: 2,
+                            length: 3,
+                        }));
+    }
+
+    #[test]
+    fn test_decode_unknown_id() {
+        use tokio_io::codec::Decoder;
+        let mut codec = BitTorrentPeerCodec::new(None, DEFAULT_MAX_MESSAGE_LENGTH);
+        // Unknown ids with and without a body, then an Interested.
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 99, 0, 0, 0, 3, 42, 1, 2, 0, 0, 0, 1, 2][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Interested));
+        assert!(buf.is_empty());
+        // An unknown message alone yields nothing yet.
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 99][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_too_long() {
+        use tokio_io::codec::Decoder;
+        let mut codec = BitTorrentPeerCodec::new(None, 100);
+        let mut buf = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 7][..]);
+        assert!(is_protocol_error(codec.decode(&mut buf)));
+    }
+
+    #[test]
+    fn test_decode_bitfield_validation() {
+        use tokio_io::codec::Decoder;
+        let mut codec = BitTorrentPeerCodec::new(Some(11), DEFAULT_MAX_MESSAGE_LENGTH);
+        let mut buf = BytesMut::from(&[0, 0, 0, 3, 5, 0xff, 0xe0][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        // Too short
+        let mut buf = BytesMut::from(&[0, 0, 0, 2, 5, 0xff][..]);
+        assert!(is_protocol_error(codec.decode(&mut buf)));
+        // Spare bit set
+        let mut buf = BytesMut::from(&[0, 0, 0, 3, 5, 0xff, 0xf0][..]);
+        assert!(is_protocol_error(codec.decode(&mut buf)));
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     KeepAlive,
     Choke,
@@ -366,7 +815,7 @@ pub enum Message {
         /// Offset within the piece
         offset: u32, // (begin)
         /// Block data
-        block: Vec<u8>,
+        block: Bytes,
     },
     Cancel {
         /// Piece index
@@ -378,7 +827,37 @@ pub enum Message {
     },
     Port {
         /// The listen port is the port this peer's DHT node is listening on.
-        port: u32,
+        port: u16,
+    },
+    /// Fast extension (BEP 6): Suggest that the peer download a piece.
+    Suggest {
+        /// Piece index
+        piece: u32,
+    },
+    /// Fast extension (BEP 6): The peer has every piece.
+    HaveAll,
+    /// Fast extension (BEP 6): The peer has no pieces.
+    HaveNone,
+    /// Fast extension (BEP 6): A request will not be served.
+    Reject {
+        /// Piece index
+        piece: u32, // (index)
+        /// Offset within the piece
+        offset: u32, // (begin)
+        /// Length in bytes
+        length: u32,
+    },
+    /// Fast extension (BEP 6): The piece may be requested even while choked.
+    AllowedFast {
+        /// Piece index
+        piece: u32,
+    },
+    /// Extension protocol message (BEP 10).
+    Extended {
+        /// Extended message id. 0 is the extension handshake.
+        id: u8,
+        /// Extension specific payload
+        payload: Vec<u8>,
     },
 }
 
@@ -400,6 +879,7 @@ impl Message {
                         offset,
                         block.len())
             }
+            &Message::Extended { ref id, ref payload } => format!("Extended {{ id:{} payload:[len {}] }}", id, payload.len()),
             _ => format!("{:?}", self),
         }
     }
@@ -429,6 +909,16 @@ impl Message {
                 length: _,
             } => 8,
             Message::Port { port: _ } => 9,
+            Message::Suggest { piece: _ } => 13,
+            Message::HaveAll => 14,
+            Message::HaveNone => 15,
+            Message::Reject {
+                piece: _,
+                offset: _,
+                length: _,
+            } => 16,
+            Message::AllowedFast { piece: _ } => 17,
+            Message::Extended { id: _, payload: _ } => 20,
         }
     }
 }
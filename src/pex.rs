@@ -0,0 +1,81 @@
+use bip_bencode::{BDecodeOpt, BMutAccess, BRefAccess, BencodeMut, BencodeRef};
+use errors::*;
+use std::net::SocketAddr;
+use util::{decode_compact_addrs, encode_compact_addrs};
+
+/// Name of the peer exchange extension (BEP 11).
+pub const EXTENSION_NAME: &'static str = "ut_pex";
+
+/// Send peer exchange messages this often.
+pub const PEX_INTERVAL_SECS: u64 = 60;
+
+/// Most peers to list as added or as dropped in one message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Changes to the set of peers the sender is connected to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let added = encode_compact_addrs(&self.added, false);
+        let added6 = encode_compact_addrs(&self.added, true);
+        // One flags byte per added peer. We don't know anything about them.
+        let added_f = vec![0; added.len() / 6];
+        let added6_f = vec![0; added6.len() / 18];
+        let dropped = encode_compact_addrs(&self.dropped, false);
+        let dropped6 = encode_compact_addrs(&self.dropped, true);
+
+        let mut d = BencodeMut::new_dict();
+        {
+            let dd = d.dict_mut().unwrap();
+            dd.insert(b"added", BencodeMut::new_bytes(&added));
+            dd.insert(b"added.f", BencodeMut::new_bytes(&added_f));
+            dd.insert(b"added6", BencodeMut::new_bytes(&added6));
+            dd.insert(b"added6.f", BencodeMut::new_bytes(&added6_f));
+            dd.insert(b"dropped", BencodeMut::new_bytes(&dropped));
+            dd.insert(b"dropped6", BencodeMut::new_bytes(&dropped6));
+        }
+        d.encode()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let b = BencodeRef::decode(payload, BDecodeOpt::default())?;
+        let d = b.dict().ok_or("pex message not a dict")?;
+        let lookup = |key: &[u8], ipv6: bool| -> Result<Vec<SocketAddr>> {
+            match d.lookup(key).and_then(|x| x.bytes()) {
+                Some(x) => decode_compact_addrs(x, ipv6),
+                None => Ok(Vec::new()),
+            }
+        };
+        let mut added = lookup(b"added", false)?;
+        added.extend(lookup(b"added6", true)?);
+        let mut dropped = lookup(b"dropped", false)?;
+        dropped.extend(lookup(b"dropped6", true)?);
+        Ok(PexMessage {
+               added: added,
+               dropped: dropped,
+           })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pex::*;
+
+    #[test]
+    fn test_round_trip() {
+        let msg = PexMessage {
+            added: vec!["10.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:51413".parse().unwrap()],
+            dropped: vec!["192.168.1.2:80".parse().unwrap()],
+        };
+        assert_eq!(PexMessage::decode(&msg.encode()).unwrap(), msg);
+    }
+}
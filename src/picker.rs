@@ -0,0 +1,327 @@
+use byteorder::{BigEndian, ByteOrder};
+use errors::*;
+use fillable::Fillable;
+use manifest::Manifest;
+use ring::rand::SystemRandom;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Random-first picks random pieces until this many are verified.
+const RANDOM_FIRST_PIECES: u64 = 4;
+
+/// How fast a peer delivers compared to the other peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PeerSpeed {
+    Slow,
+    Fast,
+}
+
+/// Chooses the order in which to download pieces.
+pub trait PiecePicker {
+    /// Pieces we still need, best first.
+    /// Only availability and the manifest may matter,
+    /// so that the order can be kept until one of them changes.
+    fn rank(&self, availability: &Availability, manifest: &Manifest) -> Vec<u64>;
+}
+
+/// Which piece picker to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickerStrategy {
+    /// Pieces in order. Useful for streaming.
+    Sequential,
+    /// The pieces fewest peers have.
+    RarestFirst,
+    /// Random pieces until a few are complete, then rarest first.
+    /// Gets something to share quickly.
+    RandomFirst,
+}
+
+impl FromStr for PickerStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sequential" => Ok(PickerStrategy::Sequential),
+            "rarest-first" => Ok(PickerStrategy::RarestFirst),
+            "random-first" => Ok(PickerStrategy::RandomFirst),
+            _ => bail!("unknown piece picker: {}", s),
+        }
+    }
+}
+
+impl PickerStrategy {
+    pub fn new_picker(self, num_pieces: u64) -> Result<Box<PiecePicker>> {
+        Ok(match self {
+               PickerStrategy::Sequential => Box::new(SequentialPicker),
+               PickerStrategy::RarestFirst => Box::new(RarestFirstPicker { tiebreak: random_keys(num_pieces)? }),
+               PickerStrategy::RandomFirst => Box::new(RandomFirstPicker { rarest: RarestFirstPicker { tiebreak: random_keys(num_pieces)? } }),
+           })
+    }
+}
+
+/// Number of connected peers which have each piece.
+pub struct Availability {
+    counts: Vec<u32>,
+    /// Incremented on every change.
+    generation: u64,
+}
+
+impl Availability {
+    pub fn new(num_pieces: u64) -> Self {
+        Availability {
+            counts: vec![0; num_pieces as usize],
+            generation: 0,
+        }
+    }
+
+    pub fn get(&self, piece: u64) -> u32 {
+        self.counts[piece as usize]
+    }
+
+    /// Count the pieces of a peer.
+    /// Call when a peer connects or its pieces are replaced.
+    pub fn add_peer(&mut self, has: &Fillable) {
+        self.generation += 1;
+        for (piece, n) in self.counts.iter_mut().enumerate() {
+            if has.has(piece as u64) {
+                *n += 1;
+            }
+        }
+    }
+
+    /// Stop counting the pieces of a peer.
+    /// Call when a peer disconnects or before its pieces are replaced.
+    pub fn remove_peer(&mut self, has: &Fillable) {
+        self.generation += 1;
+        for (piece, n) in self.counts.iter_mut().enumerate() {
+            if has.has(piece as u64) {
+                *n = n.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Count a piece a peer just got.
+    pub fn add_piece(&mut self, piece: u64) {
+        self.generation += 1;
+        if let Some(n) = self.counts.get_mut(piece as usize) {
+            *n += 1;
+        }
+    }
+}
+
+/// Random value for each piece.
+fn random_keys(num_pieces: u64) -> Result<Vec<u32>> {
+    let mut buf = vec![0; num_pieces as usize * 4];
+    SystemRandom::new().fill(&mut buf)?;
+    Ok(buf.chunks(4).map(BigEndian::read_u32).collect())
+}
+
+/// Pieces we still need.
+fn wanted(manifest: &Manifest) -> Vec<u64> {
+    (0..manifest.num_pieces())
+        .filter(|&piece| !manifest.is_full(piece).unwrap_or(true))
+        .collect()
+}
+
+/// Orders pieces for each peer.
+/// The picker's order is kept until availability or the manifest changes.
+/// Started pieces are put first so that they can be finished, verified and shared.
+pub struct PieceOrder {
+    picker: Box<PiecePicker>,
+    /// The picker's order.
+    order: Vec<u64>,
+    /// Position of each piece in `order`.
+    position: Vec<usize>,
+    /// Availability generation the order was made for.
+    generation: u64,
+    stale: bool,
+}
+
+impl PieceOrder {
+    pub fn new(picker: Box<PiecePicker>, num_pieces: u64) -> Self {
+        PieceOrder {
+            picker: picker,
+            order: Vec::new(),
+            position: vec![0; num_pieces as usize],
+            generation: 0,
+            stale: true,
+        }
+    }
+
+    /// Make a new order next time. Call when pieces are verified or fail verification.
+    pub fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /// Pieces worth requesting from a peer, best first.
+    /// `has` holds the pieces the peer has and `speed` is how fast it is.
+    /// `started` maps the pieces being downloaded to the fastest peer they are requested from.
+    /// A slow peer leaves pieces that fast peers are finishing for last,
+    /// since its blocks would hold those pieces up.
+    pub fn rank<'a>(&'a mut self, has: &'a Fillable, availability: &Availability, manifest: &Manifest, started: &'a HashMap<u64, PeerSpeed>, speed: PeerSpeed) -> Box<Iterator<Item = u64> + 'a> {
+        if self.stale || self.generation != availability.generation {
+            self.order = self.picker.rank(availability, manifest);
+            for (i, &piece) in self.order.iter().enumerate() {
+                self.position[piece as usize] = i;
+            }
+            self.generation = availability.generation;
+            self.stale = false;
+        }
+        let mut first = Vec::new();
+        let mut last = Vec::new();
+        for (&piece, &other) in started.iter().filter(|&(&piece, _)| has.has(piece)) {
+            match other > speed {
+                true => last.push(piece),
+                false => first.push(piece),
+            }
+        }
+        let position = &self.position;
+        first.sort_by_key(|&piece| position[piece as usize]);
+        last.sort_by_key(|&piece| position[piece as usize]);
+        let fresh = self.order
+            .iter()
+            .cloned()
+            .filter(move |&piece| has.has(piece) && !started.contains_key(&piece));
+        Box::new(first.into_iter().chain(fresh).chain(last.into_iter()))
+    }
+}
+
+pub struct SequentialPicker;
+
+impl PiecePicker for SequentialPicker {
+    fn rank(&self, _availability: &Availability, manifest: &Manifest) -> Vec<u64> {
+        wanted(manifest)
+    }
+}
+
+pub struct RarestFirstPicker {
+    /// Random order used to break ties between equally rare pieces.
+    tiebreak: Vec<u32>,
+}
+
+impl PiecePicker for RarestFirstPicker {
+    fn rank(&self, availability: &Availability, manifest: &Manifest) -> Vec<u64> {
+        let mut pieces = wanted(manifest);
+        pieces.sort_by_key(|&piece| (availability.get(piece), self.tiebreak[piece as usize]));
+        pieces
+    }
+}
+
+pub struct RandomFirstPicker {
+    rarest: RarestFirstPicker,
+}
+
+impl PiecePicker for RandomFirstPicker {
+    fn rank(&self, availability: &Availability, manifest: &Manifest) -> Vec<u64> {
+        if manifest.num_verified() >= RANDOM_FIRST_PIECES {
+            return self.rarest.rank(availability, manifest);
+        }
+        let mut pieces = wanted(manifest);
+        pieces.sort_by_key(|&piece| self.rarest.tiebreak[piece as usize]);
+        pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use picker::*;
+
+    #[test]
+    fn test_availability() {
+        let mut availability = Availability::new(4);
+        let mut a = Fillable::new(4);
+        a.add(0, 2).unwrap();
+        let mut b = Fillable::new(4);
+        b.add(1, 4).unwrap();
+        availability.add_peer(&a);
+        availability.add_peer(&b);
+        availability.add_piece(3);
+        assert_eq!((0..4).map(|p| availability.get(p)).collect::<Vec<_>>(),
+                   vec![1, 2, 1, 2]);
+        availability.remove_peer(&a);
+        assert_eq!((0..4).map(|p| availability.get(p)).collect::<Vec<_>>(),
+                   vec![0, 1, 1, 2]);
+    }
+
+    /// Manifest for a torrent of one byte pieces.
+    fn test_manifest(num_pieces: usize) -> Manifest {
+        use metainfo::MetaInfo;
+        let mut info = format!("d6:lengthi{}e4:name1:a12:piece lengthi1e6:pieces{}:", num_pieces, num_pieces * 20).into_bytes();
+        info.extend(vec![0; num_pieces * 20]);
+        info.push(b'e');
+        Manifest::new(MetaInfo::from_info_bytes(&info, String::new()).unwrap())
+    }
+
+    fn fillable(num_pieces: u64, pieces: &[u64]) -> Fillable {
+        let mut x = Fillable::new(num_pieces);
+        for piece in pieces {
+            x.add(*piece, *piece + 1).unwrap();
+        }
+        x
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut manifest = test_manifest(5);
+        manifest.add_block(4, 0, 1).unwrap();
+        let picker = RarestFirstPicker { tiebreak: vec![0; 5] };
+        let mut availability = Availability::new(5);
+        let all = fillable(5, &[0, 1, 2, 3, 4]);
+        availability.add_peer(&all);
+        availability.add_peer(&all);
+        availability.add_peer(&fillable(5, &[1, 2]));
+        availability.add_piece(2);
+        let mut order = PieceOrder::new(Box::new(picker), 5);
+        assert_eq!(order.rank(&all, &availability, &manifest, &HashMap::new(), PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![0, 3, 1, 2]);
+        // Started pieces come first.
+        let started = [(2, PeerSpeed::Slow)].iter().cloned().collect();
+        assert_eq!(order.rank(&all, &availability, &manifest, &started, PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![2, 0, 3, 1]);
+        // Only pieces the peer has.
+        assert_eq!(order.rank(&fillable(5, &[1, 3]), &availability, &manifest, &HashMap::new(), PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![3, 1]);
+    }
+
+    #[test]
+    fn test_slow_peer() {
+        let manifest = test_manifest(3);
+        let picker = RarestFirstPicker { tiebreak: vec![0; 3] };
+        let all = fillable(3, &[0, 1, 2]);
+        let mut availability = Availability::new(3);
+        availability.add_peer(&all);
+        let mut order = PieceOrder::new(Box::new(picker), 3);
+        let started = [(0, PeerSpeed::Fast), (1, PeerSpeed::Slow)].iter().cloned().collect();
+        // A slow peer leaves the piece a fast peer is finishing for last.
+        assert_eq!(order.rank(&all, &availability, &manifest, &started, PeerSpeed::Slow).collect::<Vec<_>>(),
+                   vec![1, 2, 0]);
+        assert_eq!(order.rank(&all, &availability, &manifest, &started, PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_order_kept() {
+        let manifest = test_manifest(3);
+        let picker = RarestFirstPicker { tiebreak: vec![0; 3] };
+        let all = fillable(3, &[0, 1, 2]);
+        let mut availability = Availability::new(3);
+        availability.add_peer(&fillable(3, &[0]));
+        let mut order = PieceOrder::new(Box::new(picker), 3);
+        assert_eq!(order.rank(&all, &availability, &manifest, &HashMap::new(), PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![1, 2, 0]);
+        // Availability changes make a new order.
+        availability.add_peer(&fillable(3, &[1, 2]));
+        availability.add_piece(2);
+        assert_eq!(order.rank(&all, &availability, &manifest, &HashMap::new(), PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_sequential() {
+        let manifest = test_manifest(3);
+        let has = fillable(3, &[0, 2]);
+        let mut order = PieceOrder::new(Box::new(SequentialPicker), 3);
+        assert_eq!(order.rank(&has, &Availability::new(3), &manifest, &HashMap::new(), PeerSpeed::Fast).collect::<Vec<_>>(),
+                   vec![0, 2]);
+    }
+}
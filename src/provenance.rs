@@ -0,0 +1,138 @@
+use ring::digest;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+
+/// Who sent a block, and what they sent.
+#[derive(Debug, Clone)]
+struct Origin<K> {
+    peer: K,
+    ip: IpAddr,
+    /// SHA-1 of the block data.
+    hash: Vec<u8>,
+}
+
+/// Result of a piece passing verification.
+pub struct Verified<K> {
+    /// Peers which sent blocks of the piece.
+    pub contributors: HashSet<K>,
+    /// Addresses which sent bad blocks of the piece earlier.
+    pub culprits: Vec<IpAddr>,
+}
+
+/// Tracks which peers sent the blocks of unverified pieces,
+/// so that the senders of corrupt data can be found.
+///
+/// When a piece fails verification and a single address sent all of it,
+/// that address is to blame. Otherwise the blocks are kept as suspects
+/// until the piece verifies, and each suspect block which differs from
+/// the verified data is blamed on its sender.
+pub struct Provenance<K> {
+    /// Origin of each block of each unverified piece, by offset.
+    blocks: HashMap<u64, HashMap<u64, Origin<K>>>,
+    /// Origin of each block of pieces which failed verification, by offset.
+    suspects: HashMap<u64, HashMap<u64, Origin<K>>>,
+}
+
+impl<K: Copy + Eq + Hash> Provenance<K> {
+    pub fn new() -> Self {
+        Provenance {
+            blocks: HashMap::new(),
+            suspects: HashMap::new(),
+        }
+    }
+
+    /// Record a block received from a peer.
+    pub fn add_block(&mut self, piece: u64, offset: u64, data: &[u8], peer: K, ip: IpAddr) {
+        let origin = Origin {
+            peer: peer,
+            ip: ip,
+            hash: digest::digest(&digest::SHA1, data).as_ref().to_vec(),
+        };
+        self.blocks
+            .entry(piece)
+            .or_insert_with(HashMap::new)
+            .insert(offset, origin);
+    }
+
+    /// Record that a piece failed verification.
+    /// Returns the addresses to blame right away.
+    pub fn flunked(&mut self, piece: u64) -> Vec<IpAddr> {
+        let blocks = match self.blocks.remove(&piece) {
+            Some(blocks) => blocks,
+            None => return Vec::new(),
+        };
+        let ips: HashSet<IpAddr> = blocks.values().map(|origin| origin.ip).collect();
+        if ips.len() == 1 {
+            return ips.into_iter().collect();
+        }
+        // Blocks from an earlier failure stay unless sent again.
+        let suspects = self.suspects.entry(piece).or_insert_with(HashMap::new);
+        for (offset, origin) in blocks {
+            suspects.insert(offset, origin);
+        }
+        Vec::new()
+    }
+
+    /// Record that a piece passed verification.
+    pub fn verified(&mut self, piece: u64) -> Verified<K> {
+        let blocks = self.blocks.remove(&piece).unwrap_or_default();
+        let suspects = self.suspects.remove(&piece).unwrap_or_default();
+        let mut culprits = Vec::new();
+        for (offset, suspect) in suspects {
+            if let Some(good) = blocks.get(&offset) {
+                if good.hash != suspect.hash {
+                    culprits.push(suspect.ip);
+                }
+            }
+        }
+        Verified {
+            contributors: blocks.values().map(|origin| origin.peer).collect(),
+            culprits: culprits,
+        }
+    }
+
+    /// Pieces an address is suspected of sending bad blocks of.
+    pub fn suspected_pieces(&self, ip: IpAddr) -> HashSet<u64> {
+        self.suspects
+            .iter()
+            .filter(|&(_, blocks)| blocks.values().any(|origin| origin.ip == ip))
+            .map(|(piece, _)| *piece)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use provenance::*;
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+
+    #[test]
+    fn test_single_sender_blamed() {
+        let mut p = Provenance::new();
+        p.add_block(0, 0, b"aaaa", 1, ip(1));
+        p.add_block(0, 4, b"bbbb", 1, ip(1));
+        assert_eq!(p.flunked(0), vec![ip(1)]);
+        assert!(p.suspected_pieces(ip(1)).is_empty());
+    }
+
+    #[test]
+    fn test_compare_after_redownload() {
+        let mut p = Provenance::new();
+        p.add_block(0, 0, b"aaaa", 1, ip(1));
+        p.add_block(0, 4, b"xxxx", 2, ip(2));
+        assert!(p.flunked(0).is_empty());
+        assert_eq!(p.suspected_pieces(ip(1)), [0].iter().cloned().collect());
+        assert_eq!(p.suspected_pieces(ip(2)), [0].iter().cloned().collect());
+
+        p.add_block(0, 0, b"aaaa", 3, ip(3));
+        p.add_block(0, 4, b"bbbb", 3, ip(3));
+        let v = p.verified(0);
+        assert_eq!(v.culprits, vec![ip(2)]);
+        assert_eq!(v.contributors, [3].iter().cloned().collect());
+        assert!(p.suspected_pieces(ip(2)).is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+/// Length of the window each rate sample covers.
+const SAMPLE_MILLIS: u64 = 1000;
+/// Weight of the newest sample in the smoothed rate.
+const SMOOTHING: f64 = 0.3;
+
+/// Measures a transfer rate in bytes per second.
+/// Bytes are counted over fixed windows and the window rates are smoothed.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    /// Smoothed rate. None until the first window completes.
+    rate: Option<f64>,
+    /// Start of the current window.
+    window_start: Instant,
+    /// Bytes counted in the current window.
+    window_bytes: u64,
+    /// Bytes counted ever.
+    total: u64,
+}
+
+impl RateMeter {
+    pub fn new(now: Instant) -> Self {
+        RateMeter {
+            rate: None,
+            window_start: now,
+            window_bytes: 0,
+            total: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64, now: Instant) {
+        self.roll(now);
+        self.window_bytes += bytes;
+        self.total += bytes;
+    }
+
+    /// Bytes per second.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.roll(now);
+        self.rate.unwrap_or(0.0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Close any windows which have ended by `now`.
+    fn roll(&mut self, now: Instant) {
+        let windows = duration_millis(now.duration_since(self.window_start)) / SAMPLE_MILLIS;
+        if windows == 0 {
+            return;
+        }
+        let sample = self.window_bytes as f64 * 1000.0 / SAMPLE_MILLIS as f64;
+        let rate = match self.rate {
+            Some(rate) => SMOOTHING * sample + (1.0 - SMOOTHING) * rate,
+            None => sample,
+        };
+        // The windows after the first were empty.
+        self.rate = Some(rate * (1.0 - SMOOTHING).powi((windows - 1) as i32));
+        self.window_bytes = 0;
+        self.window_start += Duration::from_millis(windows * SAMPLE_MILLIS);
+    }
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use rate::*;
+
+    #[test]
+    fn test_rate_meter() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+        meter.add(5000, start);
+        assert_eq!(meter.rate(start), 0.0);
+        assert_eq!(meter.rate(start + Duration::from_millis(1000)), 5000.0);
+        meter.add(1000, start + Duration::from_millis(1500));
+        let rate = meter.rate(start + Duration::from_millis(2000));
+        assert!(rate > 1000.0 && rate < 5000.0);
+        // Goes to zero after a long idle time.
+        assert!(meter.rate(start + Duration::from_secs(1000)) < 1.0);
+        assert_eq!(meter.total(), 6000);
+    }
+}
@@ -0,0 +1,204 @@
+use errors::*;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Seconds of traffic a bucket may save up for a burst.
+const BURST_SECS: f64 = 1.0;
+
+/// Token bucket limiting a transfer rate.
+/// Transfers are let through in whole messages and may overdraw the bucket.
+/// The debt is paid off by waiting before the next transfer.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Bytes per second. None means unlimited.
+    rate: Option<u64>,
+    /// Bytes which may be transferred now. Negative when overdrawn.
+    tokens: f64,
+    /// When tokens were last added.
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>, now: Instant) -> Self {
+        TokenBucket {
+            rate: rate,
+            tokens: 0.0,
+            last: now,
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        if rate.is_none() {
+            self.tokens = 0.0;
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last);
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            self.tokens = (self.tokens + secs * rate as f64).min(rate as f64 * BURST_SECS);
+        }
+        self.last = now;
+    }
+
+    /// Whether a transfer may start now, which is when the bucket is not overdrawn.
+    pub fn ready(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        match self.rate {
+            Some(rate) if rate > 0 => self.tokens >= 0.0,
+            _ => true,
+        }
+    }
+
+    /// Take tokens for a transfer.
+    /// Returns how long to wait before the next transfer.
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return Duration::from_secs(0),
+        };
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let secs = -self.tokens / rate as f64;
+        Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+    }
+}
+
+/// Rate limits, in bytes per second. None means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+    pub peer_download: Option<u64>,
+    pub peer_upload: Option<u64>,
+}
+
+impl RateLimits {
+    /// Apply a command changing one limit.
+    /// Commands are `download`, `upload`, `peer-download` or `peer-upload` followed by a rate.
+    pub fn apply_command(self, line: &str) -> Result<RateLimits> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 2 {
+            bail!("expected a limit and a rate: {}", line);
+        }
+        let rate = parse_rate(words[1])?;
+        let mut limits = self;
+        match words[0] {
+            "download" => limits.download = rate,
+            "upload" => limits.upload = rate,
+            "peer-download" => limits.peer_download = rate,
+            "peer-upload" => limits.peer_upload = rate,
+            x => bail!("unknown limit: {}", x),
+        }
+        Ok(limits)
+    }
+}
+
+/// Parse a rate like "500k" or "2m" in bytes per second.
+/// "0" and "unlimited" mean no limit.
+pub fn parse_rate(s: &str) -> Result<Option<u64>> {
+    let s = s.trim().to_lowercase();
+    if s == "unlimited" {
+        return Ok(None);
+    }
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        _ => (&s[..], 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .chain_err(|| format!("bad rate: {}", s))?;
+    let rate = match n.checked_mul(multiplier) {
+        Some(rate) => rate,
+        None => bail!("bad rate: {}", s),
+    };
+    Ok(match rate {
+           0 => None,
+           rate => Some(rate),
+       })
+}
+
+/// Displays a rate in bytes per second. None shows as unlimited.
+pub struct DisplayRate(pub Option<u64>);
+
+impl fmt::Display for DisplayRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            None => write!(f, "unlimited"),
+            Some(rate) => write!(f, "{:.1}kB/s", rate as f64 / 1024.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratelimit::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0").unwrap(), None);
+        assert_eq!(parse_rate("unlimited").unwrap(), None);
+        assert_eq!(parse_rate("100").unwrap(), Some(100));
+        assert_eq!(parse_rate("500k").unwrap(), Some(500 * 1024));
+        assert_eq!(parse_rate("2M").unwrap(), Some(2 * 1024 * 1024));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("99999999999999m").is_err());
+    }
+
+    #[test]
+    fn test_apply_command() {
+        let limits = RateLimits::default()
+            .apply_command("upload 100k")
+            .unwrap()
+            .apply_command("peer-download 10")
+            .unwrap();
+        assert_eq!(limits.upload, Some(100 * 1024));
+        assert_eq!(limits.peer_download, Some(10));
+        assert_eq!(limits.download, None);
+        assert_eq!(limits.apply_command("upload 0").unwrap().upload, None);
+        assert!(limits.apply_command("sideways 10").is_err());
+        assert!(limits.apply_command("upload").is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000), now);
+        assert_eq!(bucket.take(500, now), Duration::from_millis(500));
+        // The debt is paid off over time.
+        let later = now + Duration::from_millis(500);
+        assert_eq!(bucket.take(0, later), Duration::from_secs(0));
+        // Savings are capped.
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(1000, much_later), Duration::from_secs(0));
+        assert_eq!(bucket.take(1000, much_later), Duration::from_secs(1));
+
+        bucket.set_rate(None, much_later);
+        assert_eq!(bucket.take(1 << 20, much_later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_token_bucket_ready() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000), now);
+        assert!(bucket.ready(now));
+        bucket.take(1500, now);
+        assert!(!bucket.ready(now + Duration::from_millis(1000)));
+        assert!(bucket.ready(now + Duration::from_millis(1500)));
+
+        let mut unlimited = TokenBucket::new(None, now);
+        unlimited.take(1 << 20, now);
+        assert!(unlimited.ready(now));
+    }
+}
@@ -0,0 +1,557 @@
+use downloader;
+use downloader::{DownloaderState, TorrentConfig};
+use errors::*;
+use futures;
+use futures::Stream;
+use futures::future;
+use futures::future::Future;
+use futures::sync::mpsc::UnboundedSender;
+use futures::sync::oneshot;
+use magnet;
+use magnet::MagnetLink;
+use metadata;
+use metainfo::{InfoHash, MetaInfo};
+use mse;
+use mse::EncryptionPolicy;
+use peer_protocol;
+use peer_protocol::PeerID;
+use ratelimit::{RateLimits, TokenBucket};
+use slog::Logger;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor;
+use transport::Transport;
+use util::{BxFuture, FutureEnhanced, with_timeout};
+use utp::UtpSocket;
+
+/// How often to look for torrents which are done seeding.
+const MONITOR_INTERVAL_MILLIS: u64 = 500;
+
+type AM<T> = Arc<Mutex<T>>;
+
+/// Settings and limiters shared by every torrent in a session.
+pub struct Shared {
+    pub peer_id: PeerID,
+    /// Port we are listening on for peer connections.
+    pub port: u16,
+    pub encryption: EncryptionPolicy,
+    /// Socket for uTP connections, unless uTP is disabled.
+    pub utp: Option<UtpSocket>,
+    next_peer_num: AtomicUsize,
+    pub limits: Mutex<RateLimits>,
+    /// Limits blocks received by all torrents together.
+    pub download_limiter: Mutex<TokenBucket>,
+    /// Limits blocks sent by all torrents together.
+    pub upload_limiter: Mutex<TokenBucket>,
+}
+
+impl Shared {
+    pub fn new(peer_id: PeerID, port: u16, encryption: EncryptionPolicy, utp: Option<UtpSocket>, limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Shared {
+            peer_id: peer_id,
+            port: port,
+            encryption: encryption,
+            utp: utp,
+            next_peer_num: AtomicUsize::new(0),
+            limits: Mutex::new(limits),
+            download_limiter: Mutex::new(TokenBucket::new(limits.download, now)),
+            upload_limiter: Mutex::new(TokenBucket::new(limits.upload, now)),
+        }
+    }
+
+    /// Allocate a number for a new peer connection.
+    pub fn next_peer_num(&self) -> usize {
+        self.next_peer_num.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Runs any number of torrents on one event loop,
+/// with one listening socket and rate limits shared between them.
+pub struct Session {
+    core: reactor::Core,
+    handle: SessionHandle,
+}
+
+/// Adds and removes the torrents of a running session.
+#[derive(Clone)]
+pub struct SessionHandle {
+    log: Logger,
+    handle: reactor::Handle,
+    shared: Arc<Shared>,
+    /// Each torrent is stored in a subdirectory named by its info hash.
+    dir: PathBuf,
+    /// Settings for torrents added by command without their own.
+    config: TorrentConfig,
+    state: AM<SessionState>,
+}
+
+struct SessionState {
+    torrents: HashMap<InfoHash, AM<DownloaderState>>,
+    /// Torrents whose metadata is being fetched or whose files are being opened.
+    adding: HashSet<InfoHash>,
+    /// Dropping a sender cancels the metadata fetch of a magnet link.
+    fetching: HashMap<InfoHash, oneshot::Sender<()>>,
+    /// Number of removed torrents still announcing their stop.
+    stopping: usize,
+    /// Set by the `quit` command.
+    quitting: bool,
+    /// Set once standard input closes and no more commands can arrive.
+    commands_closed: bool,
+}
+
+impl Session {
+    pub fn new(log: Logger, peer_id: PeerID, port: u16, encryption: EncryptionPolicy, utp: bool, limits: RateLimits, dir: PathBuf, config: TorrentConfig) -> Result<Session> {
+        let core = reactor::Core::new()?;
+        let handle = core.handle();
+        let session = SessionHandle::new(log.clone(), &handle, peer_id, port, encryption, utp, limits, dir, config)?;
+
+        let log2 = log.clone();
+        handle.spawn(run_commands(session.clone()).map_err(move |err| error!(log2, "commands failed: {}", err)));
+
+        Ok(Session {
+               core: core,
+               handle: session,
+           })
+    }
+
+    pub fn handle(&self) -> SessionHandle {
+        self.handle.clone()
+    }
+
+    /// Run until the `quit` command,
+    /// or once standard input is closed and no torrents are left.
+    pub fn run(mut self) -> Result<()> {
+        let monitor = run_monitor(self.handle.clone());
+        self.core.run(monitor)
+    }
+}
+
+impl SessionHandle {
+    /// Listen for peers on `port` and run torrents on the event loop of `handle`.
+    /// Commands are not read, that is up to `Session`.
+    pub fn new(log: Logger, handle: &reactor::Handle, peer_id: PeerID, port: u16, encryption: EncryptionPolicy, utp: bool, limits: RateLimits, dir: PathBuf, config: TorrentConfig) -> Result<SessionHandle> {
+        let listen_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        let listener = TcpListener::bind(&listen_addr, handle)
+            .chain_err(|| format!("could not listen on port {}", port))?;
+        let port = listener.local_addr()?.port();
+        info!(log, "listening on port {}", port);
+
+        // uTP shares the port number with TCP.
+        let utp = if utp {
+            let utp_addr = SocketAddr::new(listen_addr.ip(), port);
+            let socket = UtpSocket::bind(&utp_addr, handle)
+                .chain_err(|| format!("could not listen for utp on port {}", port))?;
+            info!(log, "listening for utp on port {}", port);
+            Some(socket)
+        } else {
+            None
+        };
+
+        let shared = Shared::new(peer_id, port, encryption, utp, limits);
+
+        let session = SessionHandle {
+            log: log.clone(),
+            handle: handle.clone(),
+            shared: Arc::new(shared),
+            dir: dir,
+            config: config,
+            state: Arc::new(Mutex::new(SessionState {
+                                          torrents: HashMap::new(),
+                                          adding: HashSet::new(),
+                                          fetching: HashMap::new(),
+                                          stopping: 0,
+                                          quitting: false,
+                                          commands_closed: false,
+                                      })),
+        };
+
+        handle.spawn(run_listener(session.clone(), listener));
+        Ok(session)
+    }
+
+    /// Port the session listens on for peer connections.
+    pub fn port(&self) -> u16 {
+        self.shared.port
+    }
+
+    /// Start running a torrent.
+    /// Its files are opened and checked on another thread,
+    /// and failures from then on are logged.
+    pub fn add(&self, info: MetaInfo, config: TorrentConfig) -> Result<()> {
+        self.reserve(&info.info_hash)?;
+        self.open(info, config);
+        Ok(())
+    }
+
+    /// Start running a torrent from a magnet link.
+    /// Its metadata is fetched on another thread, then it is added like any other.
+    pub fn add_magnet(&self, magnet: MagnetLink, config: TorrentConfig) -> Result<()> {
+        let info_hash = magnet.info_hash.clone();
+        if self.state.lock().unwrap().quitting {
+            bail!("session is quitting");
+        }
+        self.reserve(&info_hash)?;
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.state.lock().unwrap().fetching.insert(info_hash.clone(), cancel_tx);
+        info!(self.log, "fetching metadata for {}", info_hash);
+
+        let log = self.log.new(o!("torrent" => format!("{}", info_hash)));
+        let (tx, rx) = oneshot::channel();
+        let log2 = log.clone();
+        let peer_id = self.shared.peer_id.clone();
+        let port = self.shared.port;
+        thread::spawn(move || {
+                          let _ = tx.send(metadata::fetch_metainfo(&log2, &magnet, peer_id, port, cancel_rx));
+                      });
+
+        let session = self.clone();
+        let f = rx.then(move |res| {
+            let res = res.map_err(|_| Into::<Error>::into("fetching metadata failed"))
+                .and_then(|x| x);
+            let mut state = session.state.lock().unwrap();
+            state.fetching.remove(&info_hash);
+            match res {
+                Ok(info) => {
+                    drop(state);
+                    info!(log, "{}", info);
+                    session.open(info, config);
+                    Ok(())
+                }
+                Err(_) if state.quitting => {
+                    state.adding.remove(&info_hash);
+                    info!(log, "metadata fetch cancelled");
+                    Ok(())
+                }
+                Err(err) => {
+                    state.adding.remove(&info_hash);
+                    error!(log, "could not fetch metadata: {}", err);
+                    Err(())
+                }
+            }
+        });
+        self.handle.spawn(f);
+        Ok(())
+    }
+
+    /// Claim an info hash for a torrent being added.
+    fn reserve(&self, info_hash: &InfoHash) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.torrents.contains_key(info_hash) || state.adding.contains(info_hash) {
+            bail!("torrent already added: {}", info_hash);
+        }
+        state.adding.insert(info_hash.clone());
+        Ok(())
+    }
+
+    /// Open and start a torrent whose info hash is reserved.
+    fn open(&self, info: MetaInfo, config: TorrentConfig) {
+        let info_hash = info.info_hash.clone();
+        let dir = self.dir.join(format!("{}", info_hash));
+        let datastore_path = dir.join("data");
+        let manifest_path = dir.join("manifest");
+        info!(self.log, "adding torrent {}", info_hash);
+        info!(self.log, "datastore path: {:?}", datastore_path);
+        info!(self.log, "manifest path: {:?}", manifest_path);
+
+        let log = self.log.new(o!("torrent" => format!("{}", info_hash)));
+        let (tx, rx) = oneshot::channel();
+        let log2 = log.clone();
+        thread::spawn(move || {
+                          let _ = tx.send(downloader::open(&log2, info, datastore_path, manifest_path, &config));
+                      });
+
+        let session = self.clone();
+        let log2 = log.clone();
+        let f = rx.map_err(|_| Into::<Error>::into("opening torrent failed"))
+            .and_then(move |res| {
+                session.state.lock().unwrap().adding.remove(&info_hash);
+                let dstate_c = downloader::start(log, &session.handle, session.shared.clone(), res?, config)?;
+                let quitting = {
+                    let mut state = session.state.lock().unwrap();
+                    state.torrents.insert(info_hash.clone(), dstate_c);
+                    state.quitting
+                };
+                // Torrents opened after a quit are stopped right away.
+                if quitting {
+                    session.remove(&info_hash)?;
+                }
+                Ok(())
+            });
+        self.handle.spawn(f.map_err(move |err| error!(log2, "could not add torrent: {}", err)));
+    }
+
+    /// Stop running a torrent.
+    pub fn remove(&self, info_hash: &InfoHash) -> Result<()> {
+        let dstate_c = {
+            let mut state = self.state.lock().unwrap();
+            match state.torrents.remove(info_hash) {
+                Some(dstate_c) => {
+                    state.stopping += 1;
+                    dstate_c
+                }
+                None => bail!("unknown torrent: {}", info_hash),
+            }
+        };
+        info!(self.log, "removing torrent {}", info_hash);
+
+        let log = self.log.new(o!("torrent" => format!("{}", info_hash)));
+        let state_c = self.state.clone();
+        self.handle
+            .spawn(downloader::stop(log, &dstate_c).then(move |_| {
+                                                               state_c.lock().unwrap().stopping -= 1;
+                                                               Ok(())
+                                                           }));
+        Ok(())
+    }
+
+    /// Remove every torrent and end the session once their stops are announced.
+    pub fn quit(&self) {
+        info!(self.log, "quitting");
+        let info_hashes: Vec<InfoHash> = {
+            let mut state = self.state.lock().unwrap();
+            state.quitting = true;
+            state.fetching.clear();
+            state.torrents.keys().cloned().collect()
+        };
+        for info_hash in info_hashes {
+            if let Err(err) = self.remove(&info_hash) {
+                warn!(self.log, "could not remove torrent: {}", err);
+            }
+        }
+    }
+
+    /// Change the rate limits of the session and of every peer.
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        let now = Instant::now();
+        *self.shared.limits.lock().unwrap() = limits;
+        self.shared
+            .download_limiter
+            .lock()
+            .unwrap()
+            .set_rate(limits.download, now);
+        self.shared
+            .upload_limiter
+            .lock()
+            .unwrap()
+            .set_rate(limits.upload, now);
+        let torrents: Vec<AM<DownloaderState>> = self.state.lock().unwrap().torrents.values().cloned().collect();
+        for dstate_c in torrents {
+            dstate_c.lock().unwrap().set_rate_limits(limits);
+        }
+    }
+
+    /// Carry out a command read from standard input.
+    /// `add [--picker <strategy>] <torrent file or magnet link>` and `remove <info hash>` manage torrents
+    /// and `quit` ends the session.
+    /// Anything else changes a rate limit, see `RateLimits::apply_command`.
+    fn command(&self, line: &str) -> Result<()> {
+        let mut parts = line.trim().splitn(2, ' ');
+        match (parts.next(), parts.next().map(str::trim)) {
+            (Some("add"), Some(args)) => {
+                let (config, torrent) = parse_add(args, self.config)?;
+                if torrent.starts_with("magnet:") {
+                    let magnet = MagnetLink::parse(torrent)?;
+                    info!(self.log, "magnet: {:?}", magnet);
+                    self.add_magnet(magnet, config)
+                } else {
+                    let info = MetaInfo::from_file(torrent)?;
+                    info!(self.log, "{}", info);
+                    self.add(info, config)
+                }
+            }
+            (Some("remove"), Some(hash)) => {
+                let info_hash = magnet::parse_btih(hash)?;
+                self.remove(&info_hash)
+            }
+            (Some("quit"), None) => {
+                self.quit();
+                Ok(())
+            }
+            _ => {
+                let limits = self.shared.limits.lock().unwrap().apply_command(line)?;
+                info!(self.log, "rate limits: {:?}", limits);
+                self.set_rate_limits(limits);
+                Ok(())
+            }
+        }
+    }
+
+    /// Read the start of an incoming peer's handshake
+    /// and hand the peer to the torrent it asks for.
+    fn accept(&self, stream: Transport, addr: SocketAddr) {
+        let peer_num = self.shared.next_peer_num();
+        let log = self.log.new(o!("peer_num" => peer_num));
+        info!(log, "accepted connection from {}", addr; "utp" => stream.is_utp());
+
+        let info_hashes: Vec<InfoHash> = self.state.lock().unwrap().torrents.keys().cloned().collect();
+        let handshake = mse::accept(stream, info_hashes, self.shared.encryption)
+            .and_then(|stream| peer_protocol::handshake_read_1_async(stream));
+        let session = self.clone();
+        let log2 = log.clone();
+        let f = with_timeout(handshake,
+                             Duration::from_millis(downloader::HANDSHAKE_TIMEOUT_MILLIS),
+                             &self.handle)
+                .and_then(move |(stream, info_hash, reserved)| {
+                    debug!(log, "remote info hash: {}", info_hash);
+                    debug!(log, "remote reserved: {:?}", reserved);
+                    let dstate_c = session.state.lock().unwrap().torrents.get(&info_hash).cloned();
+                    match dstate_c {
+                        Some(dstate_c) => downloader::run_inbound_peer(log, session.handle.clone(), dstate_c, stream, addr, reserved, peer_num),
+                        None => future::err(format!("peer wants unknown torrent {}", info_hash).into()).bxed(),
+                    }
+                });
+        self.handle.spawn(f.map_err(move |err| error!(log2, "inbound peer failed: {}", err)));
+    }
+}
+
+/// Split the arguments of an `add` command into the torrent's settings and the torrent.
+/// `--picker <strategy>` overrides the default piece picker.
+fn parse_add(args: &str, default: TorrentConfig) -> Result<(TorrentConfig, &str)> {
+    let mut config = default;
+    let mut torrent = args.trim();
+    if torrent.starts_with("--picker ") {
+        let mut parts = torrent["--picker ".len()..].trim().splitn(2, ' ');
+        config.picker = parts.next().unwrap_or("").parse()?;
+        torrent = parts.next().map(str::trim).unwrap_or("");
+    }
+    if torrent.is_empty() {
+        bail!("add needs a torrent file or magnet link");
+    }
+    Ok((config, torrent))
+}
+
+/// Accept incoming peer connections for every torrent.
+fn run_listener(session: SessionHandle, listener: TcpListener) -> BxFuture<(), ()> {
+    let log = session.log.clone();
+    let tcp_incoming = listener
+        .incoming()
+        .map(|(stream, addr)| (Transport::Tcp(stream), addr));
+    let incoming: Box<Stream<Item = (Transport, SocketAddr), Error = ::std::io::Error>> = match session.shared.utp.clone() {
+        Some(utp) => {
+            let utp_incoming = utp.incoming()
+                .map(|(stream, addr)| (Transport::Utp(stream), addr));
+            Box::new(tcp_incoming.select(utp_incoming))
+        }
+        None => Box::new(tcp_incoming),
+    };
+    incoming
+        .for_each(move |(stream, addr)| {
+                      session.accept(stream, addr);
+                      Ok(())
+                  })
+        .map_err(move |err| error!(log, "listener failed: {}", err))
+        .bxed()
+}
+
+/// Read commands from standard input, one per line.
+/// See `SessionHandle::command`.
+fn run_commands(session: SessionHandle) -> BxFuture<(), Error> {
+    let (tx, rx) = futures::sync::mpsc::unbounded();
+    thread::spawn(move || {
+                      let stdin = io::stdin();
+                      for line in stdin.lock().lines() {
+                          match line {
+                              Ok(line) => {
+                                  if UnboundedSender::send(&tx, line).is_err() {
+                                      break;
+                                  }
+                              }
+                              Err(_) => break,
+                          }
+                      }
+                  });
+    let session2 = session.clone();
+    rx.map_err(|()| Into::<Error>::into("commands failed"))
+        .for_each(move |line| {
+                      if let Err(err) = session.command(&line) {
+                          warn!(session.log, "bad command: {}", err);
+                      }
+                      Ok(())
+                  })
+        .map(move |()| {
+                 info!(session2.log, "standard input closed, ending once no torrents are left");
+                 session2.state.lock().unwrap().commands_closed = true;
+             })
+        .bxed()
+}
+
+/// Run a loop that removes torrents once they are done seeding.
+/// Ends once every torrent is removed and its stop announced,
+/// after a quit or when no more commands can arrive.
+fn run_monitor(session: SessionHandle) -> BxFuture<(), Error> {
+    use futures::future::Loop::{Break, Continue};
+
+    future::loop_fn(session, |session| {
+        let duration = Duration::from_millis(MONITOR_INTERVAL_MILLIS);
+        match reactor::Timeout::new(duration, &session.handle) {
+            Err(err) => future::err(Into::<Error>::into(err)).bxed(),
+            Ok(timeout) => timeout
+                .map_err(|e| e.into())
+                .and_then(move |()| {
+                    let torrents: Vec<(InfoHash, AM<DownloaderState>)> = session
+                        .state
+                        .lock()
+                        .unwrap()
+                        .torrents
+                        .iter()
+                        .map(|(info_hash, dstate_c)| (info_hash.clone(), dstate_c.clone()))
+                        .collect();
+                    let now = Instant::now();
+                    for (info_hash, dstate_c) in torrents {
+                        if dstate_c.lock().unwrap().is_done_seeding(now) {
+                            info!(session.log, "torrent done seeding: {}", info_hash);
+                            session.remove(&info_hash)?;
+                        }
+                    }
+                    let done = {
+                        let state = session.state.lock().unwrap();
+                        (state.quitting || state.commands_closed) && state.torrents.is_empty() && state.adding.is_empty() && state.stopping == 0
+                    };
+                    match done {
+                        true => Ok(Break(())),
+                        false => Ok(Continue(session)),
+                    }
+                }).bxed(),
+        }
+    })
+            .bxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use session::*;
+    use choker::ChokerConfig;
+    use picker::PickerStrategy;
+
+    #[test]
+    fn test_parse_add() {
+        let default = TorrentConfig {
+            picker: PickerStrategy::RarestFirst,
+            choker: ChokerConfig {
+                unchoke_slots: 4,
+                optimistic_slots: 1,
+            },
+            max_bad_blocks: 2,
+            seed_ratio: None,
+            seed_time: None,
+            seed: false,
+        };
+        let (config, torrent) = parse_add("a b.torrent", default).unwrap();
+        assert_eq!(config.picker, PickerStrategy::RarestFirst);
+        assert_eq!(torrent, "a b.torrent");
+        let (config, torrent) = parse_add("--picker sequential  magnet:?xt", default).unwrap();
+        assert_eq!(config.picker, PickerStrategy::Sequential);
+        assert_eq!(torrent, "magnet:?xt");
+        assert!(parse_add("--picker fastest a.torrent", default).is_err());
+        assert!(parse_add("--picker sequential", default).is_err());
+    }
+}
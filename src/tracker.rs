@@ -1,20 +1,50 @@
 use bip_bencode::{BDecodeOpt, BDictAccess, BRefAccess, BencodeRef};
-use byteorder::{BigEndian, ByteOrder};
 use errors::*;
 use hyper;
 use hyper::Url;
 use metainfo::*;
-use peer_protocol::PeerID;
+use peer_protocol::{PEERID_SIZE, PeerID};
+use std::cmp;
 use std::io::Read;
 use std::net;
-use std::time::Duration;
-use util::QueryParameters;
+use std::time::{Duration, Instant};
+use util::{QueryParameters, decode_compact_addrs};
+
+/// Number of peers to ask the tracker for.
+const NUMWANT: i64 = 50;
+/// Announce interval to use until the tracker gives one.
+const DEFAULT_INTERVAL_SECS: u64 = 30 * 60;
+/// Minimum announce interval to use if the tracker gives none.
+const DEFAULT_MIN_INTERVAL_SECS: u64 = 5 * 60;
+/// Wait before retrying a failed announce.
+const RETRY_SECS: u64 = 60;
+
+/// Transfer totals reported to the tracker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransferStats {
+    /// Bytes uploaded since the started event.
+    pub uploaded: u64,
+    /// Bytes downloaded since the started event.
+    pub downloaded: u64,
+    /// Bytes still needed to have the whole torrent verified.
+    pub left: u64,
+}
+
+/// Whether the client can announce to a url.
+/// Only HTTP trackers are supported.
+pub fn is_supported(announce: &str) -> bool {
+    Url::parse(announce)
+        .map(|url| url.scheme() == "http")
+        .unwrap_or(false)
+}
 
 // Client to talk to a tracker
 #[derive(Debug)]
 pub struct TrackerClient {
-    metainfo: MetaInfo,
+    info_hash: InfoHash,
     peer_id: PeerID,
+    /// Port we are listening on for peer connections.
+    port: u16,
     url: Url,
     client: hyper::client::Client,
     tracker_id: Option<String>,
@@ -22,14 +52,15 @@ pub struct TrackerClient {
 }
 
 impl TrackerClient {
-    pub fn new(metainfo: MetaInfo, peer_id: PeerID) -> Result<TrackerClient> {
-        let url = Url::parse(&metainfo.announce)?;
+    pub fn new(announce: &str, info_hash: InfoHash, peer_id: PeerID, port: u16) -> Result<TrackerClient> {
+        let url = Url::parse(announce)?;
         let mut client = hyper::client::Client::new();
         client.set_read_timeout(Some(Duration::from_secs(10)));
         client.set_write_timeout(Some(Duration::from_secs(10)));
         Ok(TrackerClient {
-               metainfo: metainfo,
+               info_hash: info_hash,
                peer_id: peer_id,
+               port: port,
                url: url,
                client: client,
                tracker_id: None,
@@ -37,23 +68,32 @@ impl TrackerClient {
            })
     }
 
-    pub fn easy_start(&mut self) -> Result<TrackerResponse> {
+    /// Announce to the tracker.
+    /// A response with a failure reason is returned as an error.
+    pub fn announce(&mut self, event: TrackerEvent, stats: TransferStats) -> Result<TrackerResponse> {
         let req = TrackerRequest {
-            info_hash: self.metainfo.info_hash.clone(),
+            info_hash: self.info_hash.clone(),
             peer_id: self.peer_id.clone(),
-            port: 6881, // TODO this is not true
-            uploaded: 0,
-            downloaded: 0,
-            left: 0, // TODO
+            port: self.port as i64,
+            uploaded: stats.uploaded as i64,
+            downloaded: stats.downloaded as i64,
+            left: stats.left as i64,
             compact: true,
             no_peer_id: false,
-            event: TrackerEvent::Started,
+            event: event,
             ip: None,
-            numwant: Some(10),
+            numwant: match event {
+                TrackerEvent::Stopped => Some(0),
+                _ => Some(NUMWANT),
+            },
             key: None,
-            tracker_id: None,
+            tracker_id: self.tracker_id.clone(),
         };
-        self.request(&req).chain_err(|| "tracker request error")
+        let res = self.request(&req).chain_err(|| "tracker request error")?;
+        if let Some(ref reason) = res.failure_reason {
+            bail!("tracker failed: {}", reason);
+        }
+        Ok(res)
     }
 
     fn request(&mut self, req: &TrackerRequest) -> Result<TrackerResponse> {
@@ -116,8 +156,22 @@ impl TrackerClient {
         http_res.read_to_end(&mut buf)?;
         let b = BencodeRef::decode(buf.as_slice(), BDecodeOpt::default())?;
         let bd = b.dict().ok_or("response bencoding not a dict")?;
+        let failure_reason = lookup_str(bd, "failure reason".as_bytes())?;
+        if failure_reason.is_some() {
+            // A failure may be the only key.
+            return Ok(TrackerResponse {
+                          failure_reason: failure_reason,
+                          warning_message: None,
+                          interval: 0,
+                          min_interval: None,
+                          tracker_id: None,
+                          complete: None,
+                          incomplete: None,
+                          peers: Vec::new(),
+                      });
+        }
         Ok(TrackerResponse {
-               failure_reason: lookup_str(bd, "failure reason".as_bytes())?,
+               failure_reason: None,
                warning_message: lookup_str(bd, "warning message".as_bytes())?,
                interval: lookup_i64(bd, "interval".as_bytes())?
                    .ok_or("missing 'interval'")?,
@@ -138,34 +192,57 @@ impl TrackerClient {
         if let Some(_) = peers.str() {
             bail!("Reading peers as 'str' not implemented");
         }
-        if let Some(_) = peers.list() {
-            bail!("Reading peers as 'list' not implemented");
+        if let Some(peers) = peers.list() {
+            // peers: (dictionary model)
+            // A list of dictionaries with the keys 'peer id', 'ip' and 'port'.
+            return peers.into_iter().map(parse_peer_dict).collect();
         }
         if let Some(peers) = peers.bytes() {
             // peers: (binary model)
             // multiples of 6 bytes.
             // First 4 bytes are the IP address and last 2 bytes are the port number.
             // All in network (big endian) notation.
-            if peers.len() % 6 != 0 {
-                bail!("Peers 'byte' representation not 6*n bytes");
-            }
-            return Ok(peers
-                          .chunks(6)
-                          .map(|chunk| {
-                let (bytes_ip, bytes_port) = chunk.split_at(4);
-                let port = BigEndian::read_u16(bytes_port);
-                let ip = net::Ipv4Addr::new(bytes_ip[0], bytes_ip[1], bytes_ip[2], bytes_ip[3]);
-                Peer {
-                    peer_id: None,
-                    address: net::SocketAddr::V4(net::SocketAddrV4::new(ip, port)),
-                }
-            })
+            return Ok(decode_compact_addrs(peers, false)
+                          .chain_err(|| "Peers 'byte' representation not 6*n bytes")?
+                          .into_iter()
+                          .map(|addr| {
+                                   Peer {
+                                       peer_id: None,
+                                       address: addr,
+                                   }
+                               })
                           .collect());
         }
         bail!("wrong type for 'peers': {:?}", peers);
     }
 }
 
+/// Parse one peer from the dictionary model of the peers list.
+fn parse_peer_dict(peer: &BencodeRef) -> Result<Peer> {
+    let d = peer.dict().ok_or("peer not a dict")?;
+    let ip: net::IpAddr = lookup_str(d, b"ip")?
+        .ok_or("peer missing 'ip'")?
+        .parse()
+        .chain_err(|| "peer 'ip' not an ip address")?;
+    let port = lookup_i64(d, b"port")?.ok_or("peer missing 'port'")?;
+    if port <= 0 || port > u16::max_value() as i64 {
+        bail!("peer 'port' out of range: {}", port);
+    }
+    let peer_id = match d.lookup(b"peer id").and_then(|x| x.bytes()) {
+        Some(x) if x.len() == PEERID_SIZE => {
+            let mut id = [0; PEERID_SIZE];
+            id.copy_from_slice(x);
+            Some(PeerID { id: id })
+        }
+        Some(_) => bail!("peer 'peer id' wrong length"),
+        None => None,
+    };
+    Ok(Peer {
+           peer_id: peer_id,
+           address: net::SocketAddr::new(ip, port as u16),
+       })
+}
+
 fn lookup_str<'a>(dict: &'a BDictAccess<BencodeRef>, key: &'a [u8]) -> Result<Option<String>> {
     match dict.lookup(key) {
         Some(x) => {
@@ -190,8 +267,7 @@ fn lookup_i64<'a>(dict: &'a BDictAccess<BencodeRef>, key: &'a [u8]) -> Result<Op
     }
 }
 
-#[derive(Clone, Copy)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrackerEvent {
     Started,
     Stopped,
@@ -249,3 +325,116 @@ pub struct Peer {
     pub peer_id: Option<PeerID>, // peer's self-selected ID, as described above for the tracker request
     pub address: net::SocketAddr,
 }
+
+/// Decides when to announce again.
+pub struct AnnounceSchedule {
+    /// When the last announce was made.
+    last: Instant,
+    /// Wait between regular announces.
+    interval: Duration,
+    /// Shortest wait between any announces.
+    min_interval: Duration,
+    /// Whether the last announce failed.
+    failed: bool,
+}
+
+impl AnnounceSchedule {
+    /// Schedule following an announce at `now`.
+    pub fn new(now: Instant) -> Self {
+        AnnounceSchedule {
+            last: now,
+            interval: Duration::from_secs(DEFAULT_INTERVAL_SECS),
+            min_interval: Duration::from_secs(DEFAULT_MIN_INTERVAL_SECS),
+            failed: false,
+        }
+    }
+
+    pub fn succeeded(&mut self, res: &TrackerResponse, now: Instant) {
+        let interval = cmp::max(res.interval, 0) as u64;
+        let min_interval = res.min_interval
+            .map(|x| cmp::max(x, 0) as u64)
+            .unwrap_or(cmp::min(interval, DEFAULT_MIN_INTERVAL_SECS));
+        self.last = now;
+        self.min_interval = Duration::from_secs(min_interval);
+        self.interval = Duration::from_secs(cmp::max(interval, min_interval));
+        self.failed = false;
+    }
+
+    pub fn failed(&mut self, now: Instant) {
+        self.last = now;
+        self.failed = true;
+    }
+
+    /// Whether any announce may be made now.
+    pub fn allowed(&self, now: Instant) -> bool {
+        let wait = match self.failed {
+            true => Duration::from_secs(RETRY_SECS),
+            false => self.min_interval,
+        };
+        now.duration_since(self.last) >= wait
+    }
+
+    /// Whether a completed announce may be made now.
+    /// Completion is reported without waiting out the min interval,
+    /// but still waits to retry after a failure.
+    pub fn may_complete(&self, now: Instant) -> bool {
+        !self.failed || now.duration_since(self.last) >= Duration::from_secs(RETRY_SECS)
+    }
+
+    /// Whether a regular announce is due.
+    /// With `want_peers` announces are made as often as the tracker allows.
+    pub fn due(&self, now: Instant, want_peers: bool) -> bool {
+        if !self.allowed(now) {
+            return false;
+        }
+        self.failed || want_peers || now.duration_since(self.last) >= self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracker::*;
+
+    fn response(interval: i64, min_interval: Option<i64>) -> TrackerResponse {
+        TrackerResponse {
+            failure_reason: None,
+            warning_message: None,
+            interval: interval,
+            min_interval: min_interval,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            peers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_is_supported() {
+        assert!(is_supported("http://tracker.example.com:6969/announce"));
+        assert!(!is_supported("udp://tracker.example.com:6969/announce"));
+        assert!(!is_supported("not a url"));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+        let mut schedule = AnnounceSchedule::new(now);
+        schedule.succeeded(&response(1800, Some(600)), now);
+        assert!(!schedule.due(secs(599), true));
+        assert!(schedule.due(secs(600), true));
+        assert!(!schedule.due(secs(1799), false));
+        assert!(schedule.due(secs(1800), false));
+        assert!(schedule.may_complete(secs(1)));
+
+        schedule.failed(now);
+        assert!(!schedule.may_complete(secs(RETRY_SECS - 1)));
+        assert!(!schedule.allowed(secs(RETRY_SECS - 1)));
+        assert!(schedule.due(secs(RETRY_SECS), false));
+
+        // The interval is never shorter than the minimum interval.
+        schedule.succeeded(&response(10, Some(100)), now);
+        assert!(!schedule.due(secs(99), false));
+        assert!(schedule.due(secs(100), false));
+    }
+}
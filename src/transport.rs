@@ -0,0 +1,94 @@
+use errors::*;
+use futures::{Future, Poll};
+use slog::Logger;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor;
+use tokio_io::{AsyncRead, AsyncWrite};
+use util::{BxFuture, FutureEnhanced, tcp_connect2, with_timeout};
+use utp::{UtpSocket, UtpStream};
+
+/// Time allowed for a uTP connection before falling back to TCP.
+const UTP_CONNECT_TIMEOUT_MILLIS: u64 = 2000;
+const TCP_CONNECT_TIMEOUT_MILLIS: u64 = 3000;
+
+/// A connection to a peer over either TCP or uTP.
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    pub fn is_utp(&self) -> bool {
+        match *self {
+            Transport::Tcp(_) => false,
+            Transport::Utp(_) => true,
+        }
+    }
+}
+
+impl io::Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.read(buf),
+            Transport::Utp(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.write(buf),
+            Transport::Utp(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.flush(),
+            Transport::Utp(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {}
+
+impl AsyncWrite for Transport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Transport::Tcp(ref mut s) => AsyncWrite::shutdown(s),
+            Transport::Utp(ref mut s) => s.shutdown(),
+        }
+    }
+}
+
+/// Connect to a peer.
+/// Tries uTP first if a socket is given and falls back to TCP.
+pub fn connect(log: &Logger, addr: SocketAddr, utp: Option<&UtpSocket>, handle: &reactor::Handle) -> BxFuture<Transport, Error> {
+    let tcp = {
+        let handle = handle.clone();
+        move || {
+            tcp_connect2(&addr, Duration::from_millis(TCP_CONNECT_TIMEOUT_MILLIS), &handle)
+                .chain_err(|| "peer connection failed")
+                .map(Transport::Tcp)
+        }
+    };
+    match utp {
+        None => tcp().bxed(),
+        Some(utp) => {
+            let log = log.clone();
+            with_timeout(utp.connect(&addr),
+                         Duration::from_millis(UTP_CONNECT_TIMEOUT_MILLIS),
+                         handle)
+                    .map(Transport::Utp)
+                    .or_else(move |err| {
+                                 debug!(log, "utp connection failed, trying tcp: {}", err);
+                                 tcp()
+                             })
+                    .bxed()
+        }
+    }
+}
@@ -10,7 +10,7 @@ use std::fs;
 use std::fs::File;
 use std::io;
 use std::marker::Send;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::path::Path;
 use std::sync::mpsc;
 use std::thread;
@@ -142,6 +142,19 @@ mod tests {
         assert_eq!(reader.read_n(2).unwrap(), vec![9, 9]);
         assert_eq!(reader.read_u8().unwrap(), 12);
     }
+
+    #[test]
+    fn test_compact_addrs() {
+        let addrs: Vec<SocketAddr> = vec!["10.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:51413".parse().unwrap(), "192.168.1.2:80".parse().unwrap()];
+        let v4 = encode_compact_addrs(&addrs, false);
+        assert_eq!(v4.len(), 12);
+        assert_eq!(decode_compact_addrs(&v4, false).unwrap(),
+                   vec![addrs[0], addrs[2]]);
+        let v6 = encode_compact_addrs(&addrs, true);
+        assert_eq!(v6.len(), 18);
+        assert_eq!(decode_compact_addrs(&v6, true).unwrap(), vec![addrs[1]]);
+        assert!(decode_compact_addrs(&v4[..5], false).is_err());
+    }
 }
 
 pub fn byte_to_bits(b: u8) -> [bool; 8] {
@@ -165,6 +178,57 @@ pub fn byte_to_bits(b: u8) -> [bool; 8] {
     z
 }
 
+/// Parse addresses in compact form.
+/// Each address is 4 (or 16 for ipv6) bytes of IP followed by 2 bytes of port.
+/// All in network (big endian) notation.
+pub fn decode_compact_addrs(src: &[u8], ipv6: bool) -> Result<Vec<SocketAddr>> {
+    let ip_len = if ipv6 { 16 } else { 4 };
+    if src.len() % (ip_len + 2) != 0 {
+        bail!("compact addresses not {}*n bytes", ip_len + 2);
+    }
+    Ok(src.chunks(ip_len + 2)
+           .map(|chunk| {
+        let (bytes_ip, bytes_port) = chunk.split_at(ip_len);
+        let port = BigEndian::read_u16(bytes_port);
+        if ipv6 {
+            let mut segments = [0; 8];
+            for i in 0..8 {
+                segments[i] = BigEndian::read_u16(&bytes_ip[i * 2..]);
+            }
+            let ip = Ipv6Addr::new(segments[0],
+                                   segments[1],
+                                   segments[2],
+                                   segments[3],
+                                   segments[4],
+                                   segments[5],
+                                   segments[6],
+                                   segments[7]);
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))
+        } else {
+            let ip = Ipv4Addr::new(bytes_ip[0], bytes_ip[1], bytes_ip[2], bytes_ip[3]);
+            SocketAddr::V4(SocketAddrV4::new(ip, port))
+        }
+    })
+           .collect())
+}
+
+/// Encode addresses in compact form.
+/// Addresses of the other IP version are skipped.
+pub fn encode_compact_addrs(addrs: &[SocketAddr], ipv6: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut port_buf = [0; 2];
+    for addr in addrs {
+        match (*addr, ipv6) {
+            (SocketAddr::V4(a), false) => buf.extend_from_slice(&a.ip().octets()),
+            (SocketAddr::V6(a), true) => buf.extend_from_slice(&a.ip().octets()),
+            _ => continue,
+        }
+        BigEndian::write_u16(&mut port_buf, addr.port());
+        buf.extend_from_slice(&port_buf);
+    }
+    buf
+}
+
 pub fn bits_to_byte(b: [bool; 8]) -> u8 {
     let mut z = 0;
     for i in 0..8 {
@@ -317,3 +381,30 @@ pub fn map_try<A, B, I, E, F>(it: I, mut f: F) -> std::result::Result<Vec<B>, E>
                      })
     })
 }
+
+/// Resolves after a delay.
+pub fn sleep<E>(duration: Duration, handle: &reactor::Handle) -> BxFuture<(), E>
+    where E: From<io::Error> + 'static
+{
+    match reactor::Timeout::new(duration, handle) {
+        Err(e) => future::err(e.into()).bxed(),
+        Ok(timeout) => timeout.map_err(|e| e.into()).bxed(),
+    }
+}
+
+/// Fail a future with a timeout error if it does not finish within `timeout`.
+pub fn with_timeout<F>(f: F, timeout: Duration, handle: &reactor::Handle) -> BxFuture<F::Item, F::Error>
+    where F: Future + 'static,
+          F::Error: From<io::Error> + 'static
+{
+    match reactor::Timeout::new(timeout, handle) {
+        Err(e) => future::err(e.into()).bxed(),
+        Ok(timeout) => {
+            let timeout = timeout.then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out").into()));
+            f.select(timeout)
+                .map(|(x, _)| x)
+                .map_err(|(err, _)| err)
+                .bxed()
+        }
+    }
+}
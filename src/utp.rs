@@ -0,0 +1,1070 @@
+// uTP, the micro transport protocol (BEP 29).
+// Reliable ordered streams over UDP.
+// LEDBAT congestion control backs off when it sees queuing delay,
+// so that uTP traffic gives way to other traffic on the link.
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::{Async, Future, Poll, Stream};
+use futures::task;
+use futures::task::Task;
+use ring::rand::SystemRandom;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+/// Largest payload in one packet. Keeps datagrams under common path MTUs.
+const MAX_PAYLOAD: usize = 1400;
+/// Receive window we advertise.
+const RECV_WINDOW: usize = 1 << 20;
+/// Most written data to buffer before writes block.
+const SEND_BUFFER: usize = 1 << 18;
+/// Furthest ahead an out of order packet may be and still be kept.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+/// Most connections waiting to be accepted.
+const MAX_PENDING_ACCEPT: usize = 32;
+/// Forget a connection nobody accepted after hearing nothing for this many RTOs.
+const ACCEPT_TIMEOUT_RTOS: u32 = 4;
+/// Fail a connection after hearing nothing for this long.
+/// Peers send keep-alives more often than this.
+const IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// Queuing delay LEDBAT aims for.
+const TARGET_DELAY_MICROS: i64 = 100_000;
+/// Most the congestion window grows in one round trip.
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = RECV_WINDOW as f64;
+/// Base delay is the minimum over this many buckets.
+const BASE_DELAY_BUCKETS: usize = 2;
+const BASE_DELAY_BUCKET_SECS: u64 = 60;
+
+const INITIAL_RTO_MILLIS: u64 = 1000;
+const MIN_RTO_MILLIS: u64 = 500;
+const MAX_RTO_MILLIS: u64 = 60_000;
+/// Give up on a connection after sending a packet this many times.
+const MAX_TRANSMISSIONS: u32 = 8;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+/// How often to check for timeouts while there are connections.
+const TICK_MILLIS: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+    ty: PacketType,
+    conn_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_LEN];
+        buf[0] = (self.ty as u8) << 4 | VERSION;
+        // No extensions.
+        buf[1] = 0;
+        BigEndian::write_u16(&mut buf[2..4], self.conn_id);
+        BigEndian::write_u32(&mut buf[4..8], self.timestamp);
+        BigEndian::write_u32(&mut buf[8..12], self.timestamp_diff);
+        BigEndian::write_u32(&mut buf[12..16], self.wnd_size);
+        BigEndian::write_u16(&mut buf[16..18], self.seq_nr);
+        BigEndian::write_u16(&mut buf[18..20], self.ack_nr);
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION {
+            return None;
+        }
+        let ty = match buf[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        };
+        // Skip over extensions.
+        let mut extension = buf[1];
+        let mut pos = HEADER_LEN;
+        while extension != 0 {
+            if pos + 2 > buf.len() {
+                return None;
+            }
+            extension = buf[pos];
+            pos += 2 + buf[pos + 1] as usize;
+            if pos > buf.len() {
+                return None;
+            }
+        }
+        Some(Packet {
+                 ty: ty,
+                 conn_id: BigEndian::read_u16(&buf[2..4]),
+                 timestamp: BigEndian::read_u32(&buf[4..8]),
+                 timestamp_diff: BigEndian::read_u32(&buf[8..12]),
+                 wnd_size: BigEndian::read_u32(&buf[12..16]),
+                 seq_nr: BigEndian::read_u16(&buf[16..18]),
+                 ack_nr: BigEndian::read_u16(&buf[18..20]),
+                 payload: buf[pos..].to_vec(),
+             })
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrapping.
+fn seq_lt(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+fn seq_le(a: u16, b: u16) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+fn duration_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+/// Connections are identified by the remote address and the id we receive on.
+type ConnKey = (SocketAddr, u16);
+
+struct Connection {
+    addr: SocketAddr,
+    state: ConnState,
+    recv_id: u16,
+    send_id: u16,
+    /// Next sequence number to send.
+    seq_nr: u16,
+    /// Last sequence number received in order.
+    ack_nr: u16,
+
+    /// Data written but not yet sent.
+    send_buf: VecDeque<u8>,
+    /// Packets sent and not yet acknowledged, in order.
+    unacked: VecDeque<SentPacket>,
+    fin_queued: bool,
+    fin_sent: bool,
+
+    /// Data received in order and not yet read.
+    recv_buf: VecDeque<u8>,
+    /// Packets received ahead of a gap.
+    out_of_order: HashMap<u16, Packet>,
+    /// Whether the remote has finished sending.
+    eof: bool,
+    error: Option<io::ErrorKind>,
+
+    /// Congestion window in bytes.
+    max_window: f64,
+    /// Receive window advertised by the remote.
+    peer_window: u32,
+    /// Minimum delay samples per time bucket.
+    base_delays: VecDeque<(Instant, u32)>,
+    /// Delay of the last packet received, echoed back to the remote.
+    reply_micros: u32,
+    rtt_micros: u64,
+    rtt_var_micros: u64,
+    rto: Duration,
+    last_ack_nr: u16,
+    dup_acks: u32,
+    /// When the last packet arrived from the remote.
+    last_received: Instant,
+
+    /// No UtpStream refers to this connection any more.
+    handle_dropped: bool,
+    reader: Option<Task>,
+    writer: Option<Task>,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr, state: ConnState, recv_id: u16, send_id: u16, seq_nr: u16, now: Instant) -> Self {
+        Connection {
+            addr: addr,
+            state: state,
+            recv_id: recv_id,
+            send_id: send_id,
+            seq_nr: seq_nr,
+            ack_nr: 0,
+            send_buf: VecDeque::new(),
+            unacked: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            eof: false,
+            error: None,
+            max_window: 2.0 * MIN_WINDOW,
+            peer_window: RECV_WINDOW as u32,
+            base_delays: VecDeque::new(),
+            reply_micros: 0,
+            rtt_micros: 0,
+            rtt_var_micros: 0,
+            rto: Duration::from_millis(INITIAL_RTO_MILLIS),
+            last_ack_nr: 0,
+            dup_acks: 0,
+            last_received: now,
+            handle_dropped: false,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    fn packet(&self, ty: PacketType, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            ty: ty,
+            conn_id: self.send_id,
+            timestamp: 0,
+            timestamp_diff: self.reply_micros,
+            wnd_size: RECV_WINDOW.saturating_sub(self.recv_buf.len()) as u32,
+            seq_nr: seq_nr,
+            ack_nr: self.ack_nr,
+            payload: payload,
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.unpark();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(task) = self.writer.take() {
+            task.unpark();
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.error = Some(kind);
+        self.state = ConnState::Closed;
+        self.send_buf.clear();
+        self.unacked.clear();
+        self.wake_reader();
+        self.wake_writer();
+    }
+
+    fn queue_ack(&self, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        out.push_back((self.addr, self.packet(PacketType::State, self.seq_nr, Vec::new())));
+    }
+
+    fn handle_packet(&mut self, pkt: Packet, now: Instant, now_micros: u32, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        if self.state == ConnState::Closed {
+            return;
+        }
+        self.last_received = now;
+        if pkt.ty == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+        self.reply_micros = now_micros.wrapping_sub(pkt.timestamp);
+        self.peer_window = pkt.wnd_size;
+
+        if self.state == ConnState::SynSent {
+            // The reply to our SYN carries the first sequence number the remote will use.
+            if pkt.ty != PacketType::State || pkt.ack_nr != self.seq_nr.wrapping_sub(1) {
+                return;
+            }
+            self.state = ConnState::Connected;
+            self.ack_nr = pkt.seq_nr.wrapping_sub(1);
+            self.last_ack_nr = pkt.ack_nr;
+            self.wake_writer();
+        }
+
+        self.process_ack(&pkt, now, out);
+
+        match pkt.ty {
+            PacketType::Data | PacketType::Fin => {
+                self.receive(pkt);
+                self.queue_ack(out);
+            }
+            _ => {}
+        }
+    }
+
+    fn process_ack(&mut self, pkt: &Packet, now: Instant, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        let mut acked_bytes = 0;
+        let mut progressed = false;
+        while self.unacked.front().map(|x| seq_le(x.packet.seq_nr, pkt.ack_nr)).unwrap_or(false) {
+            let sent = self.unacked.pop_front().unwrap();
+            progressed = true;
+            acked_bytes += sent.packet.payload.len();
+            // Round trips of retransmitted packets are ambiguous.
+            if sent.transmissions == 1 {
+                self.update_rtt(now.duration_since(sent.sent_at));
+            }
+        }
+
+        if progressed {
+            self.dup_acks = 0;
+            if acked_bytes > 0 {
+                self.update_window(acked_bytes, pkt.timestamp_diff, now);
+            }
+            self.wake_writer();
+        } else if pkt.ty == PacketType::State && !self.unacked.is_empty() && pkt.ack_nr == self.last_ack_nr {
+            self.dup_acks += 1;
+            if self.dup_acks == 3 {
+                // The packet after the acked one was probably lost.
+                self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+                self.retransmit_first(now, out);
+            }
+        }
+        self.last_ack_nr = pkt.ack_nr;
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = duration_micros(sample);
+        if self.rtt_micros == 0 {
+            self.rtt_micros = sample;
+            self.rtt_var_micros = sample / 2;
+        } else {
+            let delta = if self.rtt_micros > sample {
+                self.rtt_micros - sample
+            } else {
+                sample - self.rtt_micros
+            };
+            self.rtt_var_micros = (3 * self.rtt_var_micros + delta) / 4;
+            self.rtt_micros = (7 * self.rtt_micros + sample) / 8;
+        }
+        let rto = cmp::max(self.rtt_micros + 4 * self.rtt_var_micros, MIN_RTO_MILLIS * 1000);
+        self.rto = Duration::from_millis(cmp::min(rto / 1000, MAX_RTO_MILLIS));
+    }
+
+    /// LEDBAT: grow the window while queuing delay is under target and shrink it when over.
+    fn update_window(&mut self, acked_bytes: usize, delay_sample: u32, now: Instant) {
+        if delay_sample == 0 {
+            // The remote hasn't seen a packet from us yet.
+            return;
+        }
+        let new_bucket = match self.base_delays.back() {
+            Some(&(start, _)) => now.duration_since(start) >= Duration::from_secs(BASE_DELAY_BUCKET_SECS),
+            None => true,
+        };
+        if new_bucket {
+            self.base_delays.push_back((now, delay_sample));
+            if self.base_delays.len() > BASE_DELAY_BUCKETS {
+                self.base_delays.pop_front();
+            }
+        } else if let Some(&mut (_, ref mut min)) = self.base_delays.back_mut() {
+            *min = cmp::min(*min, delay_sample);
+        }
+        let base_delay = self.base_delays.iter().map(|&(_, x)| x).min().unwrap_or(delay_sample);
+
+        let our_delay = delay_sample.wrapping_sub(base_delay) as i64;
+        let off_target = (TARGET_DELAY_MICROS - our_delay) as f64 / TARGET_DELAY_MICROS as f64;
+        let window_factor = acked_bytes as f64 / self.max_window;
+        let gain = MAX_WINDOW_INCREASE_PER_RTT * off_target * window_factor;
+        self.max_window = (self.max_window + gain).max(MIN_WINDOW).min(MAX_WINDOW);
+    }
+
+    fn retransmit_first(&mut self, now: Instant, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        let ack_nr = self.ack_nr;
+        let wnd_size = RECV_WINDOW.saturating_sub(self.recv_buf.len()) as u32;
+        let reply_micros = self.reply_micros;
+        if let Some(sent) = self.unacked.front_mut() {
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            if sent.packet.ty != PacketType::Syn {
+                sent.packet.ack_nr = ack_nr;
+            }
+            sent.packet.wnd_size = wnd_size;
+            sent.packet.timestamp_diff = reply_micros;
+            out.push_back((self.addr, sent.packet.clone()));
+        }
+    }
+
+    fn check_timeout(&mut self, now: Instant, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        let (sent_at, transmissions) = match self.unacked.front() {
+            Some(x) => (x.sent_at, x.transmissions),
+            None => return,
+        };
+        if now.duration_since(sent_at) < self.rto {
+            return;
+        }
+        let max_transmissions = if self.state == ConnState::SynSent {
+            MAX_SYN_TRANSMISSIONS
+        } else {
+            MAX_TRANSMISSIONS
+        };
+        if transmissions >= max_transmissions {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+        self.max_window = MIN_WINDOW;
+        self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MILLIS));
+        self.retransmit_first(now, out);
+    }
+
+    fn receive(&mut self, pkt: Packet) {
+        if self.eof {
+            return;
+        }
+        let expected = self.ack_nr.wrapping_add(1);
+        if pkt.seq_nr == expected {
+            self.deliver(pkt);
+            while !self.eof {
+                let next = self.ack_nr.wrapping_add(1);
+                match self.out_of_order.remove(&next) {
+                    Some(pkt) => self.deliver(pkt),
+                    None => break,
+                }
+            }
+        } else if seq_lt(expected, pkt.seq_nr) && pkt.seq_nr.wrapping_sub(expected) < MAX_OUT_OF_ORDER {
+            self.out_of_order.insert(pkt.seq_nr, pkt);
+        }
+    }
+
+    fn deliver(&mut self, pkt: Packet) {
+        self.ack_nr = pkt.seq_nr;
+        match pkt.ty {
+            PacketType::Fin => {
+                self.eof = true;
+                self.out_of_order.clear();
+            }
+            _ => self.recv_buf.extend(pkt.payload),
+        }
+        self.wake_reader();
+    }
+
+    /// Send buffered data as the windows allow.
+    fn send_data(&mut self, now: Instant, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        if self.state != ConnState::Connected {
+            return;
+        }
+        let window = self.max_window.min(self.peer_window as f64) as usize;
+        let mut in_flight: usize = self.unacked.iter().map(|x| x.packet.payload.len()).sum();
+        while !self.send_buf.is_empty() {
+            let size = cmp::min(MAX_PAYLOAD, self.send_buf.len());
+            // Always allow one packet in flight so a tiny window can't stall the connection.
+            if in_flight > 0 && in_flight + size > window {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buf.drain(..size).collect();
+            let seq_nr = self.seq_nr;
+            self.send(PacketType::Data, seq_nr, payload, now, out);
+            in_flight += size;
+        }
+        if self.send_buf.len() < SEND_BUFFER {
+            self.wake_writer();
+        }
+        if self.send_buf.is_empty() && self.fin_queued && !self.fin_sent {
+            let seq_nr = self.seq_nr;
+            self.send(PacketType::Fin, seq_nr, Vec::new(), now, out);
+            self.fin_sent = true;
+        }
+    }
+
+    fn send(&mut self, ty: PacketType, seq_nr: u16, payload: Vec<u8>, now: Instant, out: &mut VecDeque<(SocketAddr, Packet)>) {
+        let packet = self.packet(ty, seq_nr, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        out.push_back((self.addr, packet.clone()));
+        self.unacked.push_back(SentPacket {
+                                   packet: packet,
+                                   sent_at: now,
+                                   transmissions: 1,
+                               });
+    }
+
+    /// Whether the connection can be forgotten.
+    fn finished(&self) -> bool {
+        self.handle_dropped && (self.error.is_some() || (self.fin_sent && self.unacked.is_empty()))
+    }
+}
+
+struct Inner {
+    /// Timestamps are microseconds since this instant.
+    epoch: Instant,
+    rand: SystemRandom,
+    connections: HashMap<ConnKey, Connection>,
+    /// Packets waiting to be sent.
+    outgoing: VecDeque<(SocketAddr, Packet)>,
+    /// Connections waiting to be accepted.
+    incoming: VecDeque<ConnKey>,
+    acceptor: Option<Task>,
+    driver: Option<Task>,
+}
+
+impl Inner {
+    fn micros(&self, now: Instant) -> u32 {
+        duration_micros(now.duration_since(self.epoch)) as u32
+    }
+
+    fn random_u16(&self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.rand
+            .fill(&mut buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "random number generator failed"))?;
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn wake_driver(&mut self) {
+        if let Some(task) = self.driver.take() {
+            task.unpark();
+        }
+    }
+
+    fn handle_datagram(&mut self, addr: SocketAddr, buf: &[u8]) {
+        let pkt = match Packet::decode(buf) {
+            Some(x) => x,
+            None => return,
+        };
+        let now = Instant::now();
+        let now_micros = self.micros(now);
+
+        if pkt.ty == PacketType::Syn {
+            self.handle_syn(addr, pkt, now, now_micros);
+            return;
+        }
+
+        let mut key = (addr, pkt.conn_id);
+        if pkt.ty == PacketType::Reset && !self.connections.contains_key(&key) {
+            // Resets may be sent with either id.
+            if let Some(k) = self.connections
+                   .iter()
+                   .find(|&(_, c)| c.addr == addr && c.send_id == pkt.conn_id)
+                   .map(|(k, _)| *k) {
+                key = k;
+            }
+        }
+        match self.connections.get_mut(&key) {
+            Some(conn) => conn.handle_packet(pkt, now, now_micros, &mut self.outgoing),
+            None => {
+                if pkt.ty != PacketType::Reset {
+                    self.outgoing.push_back((addr, reset_packet(&pkt)));
+                }
+            }
+        }
+    }
+
+    fn handle_syn(&mut self, addr: SocketAddr, pkt: Packet, now: Instant, now_micros: u32) {
+        let key = (addr, pkt.conn_id.wrapping_add(1));
+        if let Some(conn) = self.connections.get_mut(&key) {
+            // Our reply was lost.
+            conn.last_received = now;
+            conn.queue_ack(&mut self.outgoing);
+            return;
+        }
+        if self.incoming.len() >= MAX_PENDING_ACCEPT {
+            self.outgoing.push_back((addr, reset_packet(&pkt)));
+            return;
+        }
+        let seq_nr = match self.random_u16() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let mut conn = Connection::new(addr, ConnState::Connected, key.1, pkt.conn_id, seq_nr, now);
+        conn.ack_nr = pkt.seq_nr;
+        conn.reply_micros = now_micros.wrapping_sub(pkt.timestamp);
+        conn.peer_window = pkt.wnd_size;
+        conn.queue_ack(&mut self.outgoing);
+        self.connections.insert(key, conn);
+        self.incoming.push_back(key);
+        if let Some(task) = self.acceptor.take() {
+            task.unpark();
+        }
+    }
+
+    /// Forget connections waiting to be accepted whose remote has gone quiet,
+    /// and fail other connections which have been idle too long.
+    fn expire(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+        for (key, conn) in self.connections.iter_mut() {
+            let idle = now.duration_since(conn.last_received);
+            if self.incoming.contains(key) {
+                if idle >= conn.rto * ACCEPT_TIMEOUT_RTOS {
+                    expired.push(*key);
+                }
+            } else if conn.state != ConnState::Closed && idle >= Duration::from_secs(IDLE_TIMEOUT_SECS) {
+                conn.fail(io::ErrorKind::TimedOut);
+            }
+        }
+        for key in expired {
+            self.connections.remove(&key);
+            self.incoming.retain(|k| *k != key);
+        }
+    }
+}
+
+fn reset_packet(pkt: &Packet) -> Packet {
+    Packet {
+        ty: PacketType::Reset,
+        conn_id: pkt.conn_id,
+        timestamp: 0,
+        timestamp_diff: 0,
+        wnd_size: 0,
+        seq_nr: 0,
+        ack_nr: pkt.seq_nr,
+        payload: Vec::new(),
+    }
+}
+
+/// A UDP socket carrying uTP connections.
+/// Cloning gives another handle to the same socket.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Mutex<Inner>>,
+    local_addr: SocketAddr,
+}
+
+impl UtpSocket {
+    /// Bind a socket and start running it on the reactor.
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<UtpSocket> {
+        let socket = UdpSocket::bind(addr, handle)?;
+        let local_addr = socket.local_addr()?;
+        let inner = Arc::new(Mutex::new(Inner {
+                                            epoch: Instant::now(),
+                                            rand: SystemRandom::new(),
+                                            connections: HashMap::new(),
+                                            outgoing: VecDeque::new(),
+                                            incoming: VecDeque::new(),
+                                            acceptor: None,
+                                            driver: None,
+                                        }));
+        let driver = Driver {
+            socket: socket,
+            handle: handle.clone(),
+            tick: None,
+            inner: inner.clone(),
+            buf: vec![0; 1 << 16],
+        };
+        handle.spawn(driver.map_err(|_| ()));
+        Ok(UtpSocket {
+               inner: inner,
+               local_addr: local_addr,
+           })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Open a connection.
+    pub fn connect(&self, addr: &SocketAddr) -> UtpConnect {
+        let mut inner = self.inner.lock().unwrap();
+        let recv_id = loop {
+            match inner.random_u16() {
+                Ok(id) => {
+                    if !inner.connections.contains_key(&(*addr, id)) {
+                        break id;
+                    }
+                }
+                Err(err) => {
+                    return UtpConnect {
+                               inner: self.inner.clone(),
+                               key: None,
+                               error: Some(err),
+                           }
+                }
+            }
+        };
+        let now = Instant::now();
+        let mut conn = Connection::new(*addr, ConnState::SynSent, recv_id, recv_id.wrapping_add(1), 1, now);
+        let mut syn = conn.packet(PacketType::Syn, 1, Vec::new());
+        // The SYN is the only packet sent with our receive id.
+        syn.conn_id = recv_id;
+        syn.ack_nr = 0;
+        conn.seq_nr = 2;
+        conn.unacked.push_back(SentPacket {
+                                   packet: syn.clone(),
+                                   sent_at: now,
+                                   transmissions: 1,
+                               });
+        inner.outgoing.push_back((*addr, syn));
+        inner.connections.insert((*addr, recv_id), conn);
+        inner.wake_driver();
+        UtpConnect {
+            inner: self.inner.clone(),
+            key: Some((*addr, recv_id)),
+            error: None,
+        }
+    }
+
+    /// Stream of connections from remote peers.
+    pub fn incoming(&self) -> Incoming {
+        Incoming { inner: self.inner.clone() }
+    }
+}
+
+/// Future of an outgoing connection.
+pub struct UtpConnect {
+    inner: Arc<Mutex<Inner>>,
+    key: Option<ConnKey>,
+    error: Option<io::Error>,
+}
+
+impl Future for UtpConnect {
+    type Item = UtpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<UtpStream, io::Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        let key = match self.key {
+            Some(key) => key,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "polled after completion")),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let conn = match inner.connections.get_mut(&key) {
+            Some(x) => x,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "utp connection vanished")),
+        };
+        if let Some(kind) = conn.error {
+            return Err(io::Error::new(kind, "utp connection failed"));
+        }
+        if conn.state == ConnState::Connected {
+            self.key = None;
+            return Ok(Async::Ready(UtpStream {
+                                       inner: self.inner.clone(),
+                                       key: key,
+                                   }));
+        }
+        conn.writer = Some(task::park());
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for UtpConnect {
+    fn drop(&mut self) {
+        // Abandon a connection attempt that never completed.
+        if let Some(key) = self.key {
+            if let Ok(mut inner) = self.inner.lock() {
+                inner.connections.remove(&key);
+            }
+        }
+    }
+}
+
+/// Stream of incoming connections and their remote addresses.
+pub struct Incoming {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Stream for Incoming {
+    type Item = (UtpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.incoming.pop_front() {
+            Some(key) => {
+                let stream = UtpStream {
+                    inner: self.inner.clone(),
+                    key: key,
+                };
+                Ok(Async::Ready(Some((stream, key.0))))
+            }
+            None => {
+                inner.acceptor = Some(task::park());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// A uTP connection.
+pub struct UtpStream {
+    inner: Arc<Mutex<Inner>>,
+    key: ConnKey,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "utp connection closed")
+}
+
+impl io::Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let conn = inner.connections.get_mut(&self.key).ok_or_else(not_connected)?;
+        if !conn.recv_buf.is_empty() {
+            let n = cmp::min(buf.len(), conn.recv_buf.len());
+            for (dst, src) in buf.iter_mut().zip(conn.recv_buf.drain(..n)) {
+                *dst = src;
+            }
+            return Ok(n);
+        }
+        if let Some(kind) = conn.error {
+            return Err(io::Error::new(kind, "utp connection failed"));
+        }
+        if conn.eof {
+            return Ok(0);
+        }
+        conn.reader = Some(task::park());
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl io::Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let n = {
+            let conn = inner.connections.get_mut(&self.key).ok_or_else(not_connected)?;
+            if let Some(kind) = conn.error {
+                return Err(io::Error::new(kind, "utp connection failed"));
+            }
+            if conn.fin_queued {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "utp connection shut down"));
+            }
+            let space = SEND_BUFFER.saturating_sub(conn.send_buf.len());
+            if space == 0 {
+                conn.writer = Some(task::park());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = cmp::min(space, buf.len());
+            conn.send_buf.extend(&buf[..n]);
+            n
+        };
+        inner.wake_driver();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Buffered data is sent as soon as the windows allow.
+        Ok(())
+    }
+}
+
+impl AsyncRead for UtpStream {}
+
+impl AsyncWrite for UtpStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(conn) = inner.connections.get_mut(&self.key) {
+            conn.fin_queued = true;
+        }
+        inner.wake_driver();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(conn) = inner.connections.get_mut(&self.key) {
+                conn.handle_dropped = true;
+                conn.fin_queued = true;
+            }
+            inner.wake_driver();
+        }
+    }
+}
+
+/// Runs the socket: receives and sends datagrams and handles timeouts.
+struct Driver {
+    socket: UdpSocket,
+    handle: Handle,
+    /// Next check for timeouts. Only armed while there are connections.
+    tick: Option<Timeout>,
+    inner: Arc<Mutex<Inner>>,
+    buf: Vec<u8>,
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let tick = match self.tick {
+            Some(ref mut timeout) => timeout.poll()?.is_ready(),
+            None => false,
+        };
+        if tick {
+            self.tick = None;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.driver = Some(task::park());
+
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((n, addr)) => inner.handle_datagram(addr, &self.buf[..n]),
+                // Other errors are about single datagrams.
+                Err(ref err) if err.kind() != io::ErrorKind::WouldBlock => continue,
+                Err(_) => break,
+            }
+        }
+
+        let now = Instant::now();
+        {
+            let inner = &mut *inner;
+            for conn in inner.connections.values_mut() {
+                if tick {
+                    conn.check_timeout(now, &mut inner.outgoing);
+                }
+                conn.send_data(now, &mut inner.outgoing);
+            }
+        }
+        if tick {
+            inner.expire(now);
+            inner.connections.retain(|_, conn| !conn.finished());
+        }
+
+        let now_micros = inner.micros(now);
+        while let Some((addr, mut packet)) = inner.outgoing.pop_front() {
+            packet.timestamp = now_micros;
+            match self.socket.send_to(&packet.encode(), &addr) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    inner.outgoing.push_front((addr, packet));
+                    break;
+                }
+                // Lost datagrams are recovered by retransmission.
+                _ => {}
+            }
+        }
+
+        if inner.connections.is_empty() {
+            self.tick = None;
+        } else if self.tick.is_none() {
+            let mut timeout = Timeout::new(Duration::from_millis(TICK_MILLIS), &self.handle)?;
+            // Polling registers for the wakeup.
+            if timeout.poll()?.is_ready() {
+                task::park().unpark();
+            }
+            self.tick = Some(timeout);
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utp::*;
+    use tokio_core::reactor::Core;
+    use tokio_io::io::{read_to_end, shutdown, write_all};
+
+    #[test]
+    fn test_packet_round_trip() {
+        let pkt = Packet {
+            ty: PacketType::Data,
+            conn_id: 513,
+            timestamp: 12345678,
+            timestamp_diff: 4321,
+            wnd_size: 1 << 20,
+            seq_nr: 65535,
+            ack_nr: 7,
+            payload: b"hello".to_vec(),
+        };
+        assert_eq!(Packet::decode(&pkt.encode()), Some(pkt));
+        assert_eq!(Packet::decode(&[0x41; 10]), None);
+    }
+
+    #[test]
+    fn test_seq_wrapping() {
+        assert!(seq_lt(1, 2));
+        assert!(seq_lt(65535, 0));
+        assert!(!seq_lt(0, 65535));
+        assert!(seq_le(3, 3));
+    }
+
+    #[test]
+    fn test_expire() {
+        let now = Instant::now();
+        let mut inner = Inner {
+            epoch: now,
+            rand: SystemRandom::new(),
+            connections: HashMap::new(),
+            outgoing: VecDeque::new(),
+            incoming: VecDeque::new(),
+            acceptor: None,
+            driver: None,
+        };
+        let syn = |port: u16| {
+            let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let pkt = Packet {
+                ty: PacketType::Syn,
+                conn_id: 100,
+                timestamp: 0,
+                timestamp_diff: 0,
+                wnd_size: RECV_WINDOW as u32,
+                seq_nr: 1,
+                ack_nr: 0,
+                payload: Vec::new(),
+            };
+            (addr, pkt)
+        };
+
+        // SYNs which are never followed up fill the accept queue.
+        for port in 0..MAX_PENDING_ACCEPT as u16 {
+            let (addr, pkt) = syn(1000 + port);
+            inner.handle_syn(addr, pkt, now, 0);
+        }
+        let (addr, pkt) = syn(2000);
+        inner.handle_syn(addr, pkt, now, 0);
+        assert_eq!(inner.incoming.len(), MAX_PENDING_ACCEPT);
+        assert!(!inner.connections.contains_key(&(addr, 101)));
+
+        // An accepted connection is only failed once idle for much longer.
+        let accepted = inner.incoming.pop_front().unwrap();
+        inner.expire(now + Duration::from_secs(10));
+        assert!(inner.incoming.is_empty());
+        assert_eq!(inner.connections.len(), 1);
+        assert_eq!(inner.connections[&accepted].error, None);
+        inner.expire(now + Duration::from_secs(IDLE_TIMEOUT_SECS));
+        assert_eq!(inner.connections[&accepted].error, Some(io::ErrorKind::TimedOut));
+
+        let (addr, pkt) = syn(2000);
+        inner.handle_syn(addr, pkt, now, 0);
+        assert_eq!(inner.incoming.len(), 1);
+    }
+
+    #[test]
+    fn test_loopback_transfer() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let a = UtpSocket::bind(&addr, &handle).unwrap();
+        let b = UtpSocket::bind(&addr, &handle).unwrap();
+
+        let data: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+        let data2 = data.clone();
+
+        let send = a.connect(&b.local_addr())
+            .and_then(move |stream| write_all(stream, data2))
+            .and_then(|(stream, _)| shutdown(stream));
+        let recv = b.incoming()
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(x, _)| read_to_end(x.unwrap().0, Vec::new()))
+            .map(|(_, buf)| buf);
+
+        let (_, received) = core.run(send.join(recv)).unwrap();
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+    }
+}